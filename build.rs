@@ -0,0 +1,47 @@
+// MIT License
+//
+// Copyright (c) 2016 Rafael Medina García <rafamedgar@gmail.com>
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+extern crate pkg_config;
+
+use std::env;
+
+/// Link SQLCipher when built with the `sqlcipher` feature
+///
+/// SQLCipher exports the same functions as SQLite. Libraries of the program
+/// come before those of its dependencies when linking, so it takes the place
+/// of the SQLite library linked by libsqlite3-sys.
+fn main() {
+    if env::var("CARGO_FEATURE_SQLCIPHER").is_err() {
+        return;
+    }
+
+    // Allow users to specify where to find SQLCipher
+    if let Ok(dir) = env::var("SQLCIPHER_LIB_DIR") {
+        println!("cargo:rustc-link-search={}", dir);
+        println!("cargo:rustc-link-lib=sqlcipher");
+        return;
+    }
+
+    if let Err(err) = pkg_config::find_library("sqlcipher") {
+        panic!("Could not find SQLCipher ({}). Try setting SQLCIPHER_LIB_DIR.", err);
+    }
+}
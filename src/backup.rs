@@ -0,0 +1,231 @@
+// MIT License
+//
+// Copyright (c) 2016 Rafael Medina García <rafamedgar@gmail.com>
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+/// Database backups
+///
+/// Backups are made with the SQLite online backup API, so they can be taken
+/// while the database is in use. They are named after the database file and
+/// the time they were made, e.g. `receipts-20170102-153000.db`, and only
+/// files following that pattern are considered when rotating.
+///
+/// Backups of encrypted databases are encrypted with the same passphrase.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time;
+
+use chrono::{Datelike, Duration, Local, NaiveDate, NaiveDateTime};
+use gettextrs::gettext;
+use rusqlite;
+use rusqlite::Connection;
+use rusqlite::backup::Backup;
+
+use common::BACKUP_INTERVAL_HOURS;
+use config::BackupSettings;
+use db;
+use db::Database;
+use encryption;
+use i18n::tr_format;
+use migrations;
+
+
+/// Time in backup file names
+const TIME_FORMAT: &'static str = "%Y%m%d-%H%M%S";
+
+/// Pages copied at a time
+const PAGES_PER_STEP: i32 = 100;
+
+/// Backup of a database
+pub struct BackupFile {
+    pub path: PathBuf,
+    pub created: NaiveDateTime
+}
+
+/// Back up a database into `dir`
+///
+/// `db` is the open database at `db_path`. Returns the path of the backup.
+pub fn create(db: &Database, db_path: &str, dir: &str) -> Result<PathBuf, String> {
+    try!(fs::create_dir_all(dir).map_err(|e| e.to_string()));
+
+    let now = Local::now().naive_local();
+    let path = Path::new(dir).join(format!("{}-{}.db", stem(db_path), now.format(TIME_FORMAT)));
+
+    // Unfinished backups are not listed
+    let partial = path.with_extension("db.part");
+
+    let result = copy_to_file(db, &partial)
+        .and_then(|_| fs::rename(&partial, &path).map_err(|e| e.to_string()));
+
+    if result.is_err() {
+        let _ = fs::remove_file(&partial);
+    }
+
+    result.map(|_| path)
+}
+
+/// Backups of a database in `dir`, newest first
+pub fn list(dir: &str, db_path: &str) -> Vec<BackupFile> {
+    let prefix = format!("{}-", stem(db_path));
+
+    let entries = match fs::read_dir(dir) {
+        Ok(e) => e,
+        Err(_) => return Vec::new()
+    };
+
+    let mut backups: Vec<BackupFile> = entries
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| {
+            let name = entry.file_name().to_string_lossy().into_owned();
+
+            if !name.starts_with(prefix.as_str()) || !name.ends_with(".db") {
+                return None;
+            }
+
+            NaiveDateTime::parse_from_str(&name[prefix.len()..name.len() - 3], TIME_FORMAT)
+                .ok()
+                .map(|created| BackupFile { path: entry.path(), created: created })
+        })
+        .collect();
+
+    backups.sort_by(|a, b| b.created.cmp(&a.created));
+
+    backups
+}
+
+/// Whether the last backup of a database is older than the backup interval
+pub fn is_due(dir: &str, db_path: &str) -> bool {
+    let now = Local::now().naive_local();
+
+    list(dir, db_path).first()
+        .map_or(true, |b| now - b.created >= Duration::hours(BACKUP_INTERVAL_HOURS))
+}
+
+/// Delete old backups of a database
+///
+/// The newest backup of each of the last `keep_daily` days, `keep_weekly`
+/// weeks and `keep_monthly` months that have backups is kept, as well as
+/// the newest backup overall. Returns the number of backups deleted.
+pub fn rotate(dir: &str, db_path: &str, settings: &BackupSettings) -> Result<usize, String> {
+    let mut days: Vec<NaiveDate> = Vec::new();
+    let mut weeks: Vec<(i32, u32)> = Vec::new();
+    let mut months: Vec<(i32, u32)> = Vec::new();
+    let mut deleted = 0;
+
+    for (index, backup) in list(dir, db_path).iter().enumerate() {
+        let date = backup.created.date();
+        let (week_year, week, _) = date.isoweekdate();
+        let mut keep = index == 0;
+
+        if !days.contains(&date) && days.len() < settings.keep_daily as usize {
+            days.push(date);
+            keep = true;
+        }
+
+        if !weeks.contains(&(week_year, week)) && weeks.len() < settings.keep_weekly as usize {
+            weeks.push((week_year, week));
+            keep = true;
+        }
+
+        let month = (date.year(), date.month());
+
+        if !months.contains(&month) && months.len() < settings.keep_monthly as usize {
+            months.push(month);
+            keep = true;
+        }
+
+        if !keep {
+            try!(fs::remove_file(&backup.path).map_err(|e| e.to_string()));
+            deleted += 1;
+        }
+    }
+
+    Ok(deleted)
+}
+
+/// Replace the contents of a database with a backup
+///
+/// `db` is the open database at `db_path`. The backup is copied through a
+/// second connection, so `db` sees the restored receipts right away, and
+/// then migrated to the current version.
+pub fn restore(db: &Database, db_path: &str, backup: &Path) -> Result<(), String> {
+    let source = try!(Connection::open_with_flags(backup, rusqlite::SQLITE_OPEN_READ_ONLY)
+        .map_err(|e| e.to_string()));
+
+    try!(use_key(db, &source));
+
+    if migrations::get_version(&source).unwrap_or(-1) < 1 {
+        let name = backup.to_string_lossy();
+
+        return Err(if encryption::is_encrypted(name.as_ref()) && !encryption::is_available() {
+            encryption::unavailable_message(name.as_ref())
+        } else if encryption::is_encrypted(name.as_ref()) {
+            tr_format("{} is encrypted with another passphrase", &[name.as_ref()])
+        } else {
+            tr_format("{} is not a Receipt Keeper database", &[name.as_ref()])
+        });
+    }
+
+    let mut dest = try!(Connection::open(db_path).map_err(|e| e.to_string()));
+    try!(use_key(db, &dest));
+    try!(copy(&source, &mut dest));
+
+    if migrations::migrate(db) < 0 {
+        return Err(gettext("The restored database could not be updated"));
+    }
+
+    Ok(())
+}
+
+/// Copy a database into a new file
+///
+/// Copies of encrypted databases use the same passphrase.
+fn copy_to_file(db: &Database, path: &Path) -> Result<(), String> {
+    let mut dest = try!(Connection::open(path).map_err(|e| e.to_string()));
+    try!(use_key(db, &dest));
+    try!(copy(db.connection(), &mut dest));
+
+    // The copy keeps the WAL mode of the database; a single file is easier
+    // to move around
+    dest.execute_batch("PRAGMA journal_mode = DELETE;").map_err(|e| e.to_string())
+}
+
+/// Copy the contents of a database into another
+fn copy(source: &Connection, dest: &mut Connection) -> Result<(), String> {
+    let backup = try!(Backup::new(source, dest).map_err(|e| e.to_string()));
+
+    backup.run_to_completion(PAGES_PER_STEP, time::Duration::from_millis(0), None)
+        .map_err(|e| e.to_string())
+}
+
+/// Use the passphrase of an encrypted database in another connection
+fn use_key(db: &Database, conn: &Connection) -> Result<(), String> {
+    match db.key() {
+        Some(key) => db::apply_key(conn, key.as_str()).map_err(|e| e.to_string()),
+        None => Ok(())
+    }
+}
+
+/// File name of a database without its extension
+fn stem(db_path: &str) -> String {
+    Path::new(db_path).file_stem()
+        .map_or("receipts".to_string(), |s| s.to_string_lossy().into_owned())
+}
@@ -25,21 +25,195 @@
 use std::collections::HashMap;
 use std::cell::RefCell;
 use std::rc::Rc;
+use std::time::Instant;
 
 use chrono::offset::local::Local;
 
+use config::Config;
+use worker::Worker;
+
 
 /// Application state
 ///
 /// The window map is a HashMap that stores window IDs using
 /// Receipt ID as key
+///
+/// The worker is started once the database path is known and shared by
+/// all windows.
+///
+/// `db_path` is the database currently open, which belongs to the current
+/// profile in the configuration. `key` is its passphrase if it is encrypted.
+/// `session` is incremented every time a database is opened, so that timers
+/// started for the previous one can tell they are no longer needed.
+///
+/// `last_activity` is the last time the user interacted with a window, used
+/// to lock encrypted databases.
 pub struct State {
+    pub config: Config,
     pub db_path: String,
+    pub worker: Option<Worker>,
+    pub key: Option<String>,
+    pub session: u32,
+    pub last_activity: Instant,
+    pub events: EventBus,
     pub window_map: HashMap<i32, u32>,
 }
 
-/// Date regular expression
-pub const RE_DATE: &'static str = r"^(\d{2})/(\d{2})/(\d{4})$";
+impl State {
+    /// Database worker
+    ///
+    /// Panics if the database has not been opened yet.
+    pub fn worker(&self) -> &Worker {
+        self.worker.as_ref().expect("database is not open")
+    }
+}
+
+/// Named database, e.g. for personal or company receipts
+#[derive(Clone, PartialEq)]
+pub struct Profile {
+    pub name: String,
+    pub db_path: String
+}
+
+/// Change made to a receipt, identified by its ID
+///
+/// `External` is emitted when another process changed the database, in which
+/// case any receipt may have changed.
+#[derive(Clone, Copy, PartialEq)]
+pub enum ReceiptEvent {
+    Created(i32),
+    Updated(i32),
+    Deleted(i32),
+    External
+}
+
+impl ReceiptEvent {
+    /// ID of the receipt that changed, if known
+    pub fn id(&self) -> Option<i32> {
+        match *self {
+            ReceiptEvent::Created(id) => Some(id),
+            ReceiptEvent::Updated(id) => Some(id),
+            ReceiptEvent::Deleted(id) => Some(id),
+            ReceiptEvent::External => None
+        }
+    }
+}
+
+/// Receipt change notifications
+///
+/// Windows subscribe to learn about changes made elsewhere in the
+/// application. Subscribers are called in the main thread.
+pub struct EventBus {
+    next_id: u32,
+    subscribers: Vec<(u32, Rc<Fn(ReceiptEvent)>)>
+}
+
+impl EventBus {
+    /// Create a bus without subscribers
+    pub fn new() -> EventBus {
+        EventBus {
+            next_id: 0,
+            subscribers: Vec::new()
+        }
+    }
+
+    /// Add a subscriber
+    ///
+    /// Returns the ID used to unsubscribe.
+    pub fn subscribe<F: Fn(ReceiptEvent) + 'static>(&mut self, subscriber: F) -> u32 {
+        let id = self.next_id;
+        self.next_id += 1;
+
+        self.subscribers.push((id, Rc::new(subscriber)));
+
+        id
+    }
+
+    /// Remove a subscriber
+    pub fn unsubscribe(&mut self, id: u32) {
+        self.subscribers.retain(|&(s, _)| s != id);
+    }
+}
+
+/// Notify all subscribers of a change to a receipt
+///
+/// The state is not borrowed while subscribers are called, so they are free
+/// to use it.
+pub fn emit(state: &Rc<RefCell<State>>, event: ReceiptEvent) {
+    let subscribers: Vec<Rc<Fn(ReceiptEvent)>> = state.borrow().events.subscribers.iter()
+        .map(|&(_, ref subscriber)| subscriber.clone())
+        .collect();
+
+    for subscriber in subscribers.iter() {
+        subscriber(event);
+    }
+}
+
+/// Layout of the receipt table
+///
+/// Columns are identified by the names in `COLUMNS`.
+#[derive(Clone)]
+pub struct ColumnLayout {
+    pub order: Vec<String>,
+    pub hidden: Vec<String>,
+}
+
+impl ColumnLayout {
+    /// Create the default layout
+    pub fn new() -> ColumnLayout {
+        ColumnLayout {
+            order: COLUMNS.iter().map(|c| c.to_string()).collect(),
+            hidden: vec!["description".to_string(), "entered".to_string()],
+        }
+    }
+}
+
+/// Configurable columns of the receipt table, in default order
+///
+/// Each name matches a `column_<name>` column in the main window. Receipts
+/// have no category or tags, so there are no columns for them.
+pub const COLUMNS: [&'static str; 10] = [
+    "id",
+    "shop",
+    "cost",
+    "currency",
+    "type",
+    "date",
+    "return_by",
+    "warranty",
+    "description",
+    "entered"
+];
+
+/// Days before the end of a return or warranty period in which
+/// a receipt is considered to be expiring
+pub const EXPIRY_DAYS: i64 = 14;
+
+/// Days in which a receipt is considered to have been entered recently
+pub const RECENT_DAYS: i64 = 7;
+
+/// Name of the profile created when there is none
+pub const DEFAULT_PROFILE: &'static str = "Default";
+
+/// Default number of days receipts are kept in the trash
+pub const TRASH_RETENTION_DAYS: i64 = 30;
+
+/// Hours between automatic backups
+pub const BACKUP_INTERVAL_HOURS: i64 = 24;
+
+/// Default minutes without activity after which encrypted databases are
+/// locked
+pub const LOCK_MINUTES: u32 = 5;
+
+/// Days between the payment dates of receipts considered duplicates
+pub const DUPLICATE_DAYS: i64 = 3;
+
+/// Days between the payment date of a receipt and the posting date of the
+/// bank transaction it is matched with
+pub const MATCH_DAYS: i64 = 5;
+
+/// Number of receipts loaded at a time in the receipt table
+pub const PAGE_SIZE: i32 = 200;
 
 /// Currencies
 pub const CURRENCIES: [&'static str; 3] = [
@@ -49,8 +223,9 @@ pub const CURRENCIES: [&'static str; 3] = [
 ];
 
 /// Payment types
-pub const PAYMENTS: [&'static str; 3] = [
+pub const PAYMENTS: [&'static str; 4] = [
     "card",
     "cash",
-    "points"
+    "points",
+    "transfer"
 ];
@@ -24,51 +24,391 @@
 
 use ini::Ini;
 use std::env;
-use std::path::{Path, PathBuf};
+use std::fs;
+use std::path::PathBuf;
+use std::str::FromStr;
 
-use common::State;
+use common::{ColumnLayout, Profile, COLUMNS, CURRENCIES, DEFAULT_PROFILE, LOCK_MINUTES,
+             PAYMENTS, TRASH_RETENTION_DAYS};
+use dates::DateFormat;
+use i18n::tr_format;
 
-/// Attempt to read the config file
+/// Application settings
 ///
-/// If it does not exist, an empty file will be created
-pub fn read_config_file() -> String {
-    let mut conf_path = env::home_dir().unwrap();
-    conf_path.push(".receipt-keeper");
+/// Every setting has a default, used when it is missing or invalid in the
+/// config file. Keys unknown to this version are kept when saving.
+#[derive(Clone)]
+pub struct Config {
+    pub profiles: Vec<Profile>,
+    // Name of the current profile
+    pub profile: String,
+    pub base_currency: String,
+    pub date_format: DateFormat,
+    pub default_payment: String,
+    pub window: WindowGeometry,
+    pub columns: ColumnLayout,
+    pub trash_retention_days: i64,
+    pub backup: BackupSettings,
+    // Minutes without activity before an encrypted database is locked, 0
+    // to never lock it
+    pub lock_minutes: u32,
+    // Contents of the file as last read or written
+    file: Ini
+}
+
+/// Size of the main window
+#[derive(Clone, Copy, PartialEq)]
+pub struct WindowGeometry {
+    pub width: i32,
+    pub height: i32,
+    pub maximized: bool
+}
+
+/// Automatic backups of the database
+#[derive(Clone, PartialEq)]
+pub struct BackupSettings {
+    pub enabled: bool,
+    pub directory: String,
+    // Number of daily, weekly and monthly backups kept
+    pub keep_daily: u32,
+    pub keep_weekly: u32,
+    pub keep_monthly: u32
+}
+
+impl Config {
+    /// Create the default configuration
+    pub fn new() -> Config {
+        Config {
+            profiles: vec![Profile {
+                name: DEFAULT_PROFILE.to_string(),
+                db_path: String::new()
+            }],
+            profile: DEFAULT_PROFILE.to_string(),
+            base_currency: CURRENCIES[0].to_string(),
+            date_format: DateFormat::DayMonthYear,
+            default_payment: PAYMENTS[0].to_string(),
+            window: WindowGeometry {
+                width: 800,
+                height: 600,
+                maximized: false
+            },
+            columns: ColumnLayout::new(),
+            trash_retention_days: TRASH_RETENTION_DAYS,
+            backup: BackupSettings {
+                enabled: true,
+                directory: default_backup_dir(),
+                keep_daily: 7,
+                keep_weekly: 4,
+                keep_monthly: 12
+            },
+            lock_minutes: LOCK_MINUTES,
+            file: Ini::new()
+        }
+    }
+
+    /// Database path of a profile
+    pub fn profile_path(&self, name: &str) -> Option<&str> {
+        self.profiles.iter()
+            .find(|p| p.name == name)
+            .map(|p| p.db_path.as_str())
+    }
+}
+
+/// Read the config file
+///
+/// The file is created if it does not exist, migrating the settings in
+/// `~/.receipt-keeper` used by older versions when available. A file that
+/// cannot be read is copied next to it before the defaults are used, as the
+/// next save replaces it.
+pub fn load() -> Config {
+    let conf_path = config_path();
+
+    if conf_path.exists() {
+        return match Ini::load_from_file(conf_path.to_str().unwrap()) {
+            Ok(file) => parse(file),
+            Err(err) => {
+                let mut copy_path = conf_path.clone().into_os_string();
+                copy_path.push(".broken");
+                let copy_path = PathBuf::from(copy_path);
+
+                let path = conf_path.to_string_lossy();
+                let copy = copy_path.to_string_lossy();
+
+                eprintln!("{}", tr_format("Could not read the config file {}, using the default settings: {}",
+                                          &[path.as_ref(), err.to_string().as_str()]));
+
+                if fs::copy(&conf_path, &copy_path).is_ok() {
+                    eprintln!("{}", tr_format("A copy of it was kept in {}", &[copy.as_ref()]));
+                }
+
+                Config::new()
+            }
+        };
+    }
+
+    let config = legacy_path()
+        .and_then(|path| Ini::load_from_file(path.to_str().unwrap()).ok())
+        .map_or(Config::new(), parse);
+
+    save(&config);
+
+    config
+}
+
+/// Write the configuration to the config file
+pub fn save(config: &Config) {
+    let conf_path = config_path();
 
-    if !conf_path.exists() {
-        // Create empty file
-        create_empty_config(&conf_path);
+    if let Some(dir) = conf_path.parent() {
+        let _ = fs::create_dir_all(dir);
     }
 
-    // Read config file
-    let conf = Ini::load_from_file(conf_path.to_str().unwrap()).unwrap();
+    let mut file = config.file.clone();
+
+    // Replaced by profiles
+    file.delete(Some("DB"));
+
+    // Sections of removed profiles
+    let removed: Vec<String> = file.sections()
+        .filter_map(|s| s.clone())
+        .filter(|s| s.starts_with("Profile ")
+                && config.profile_path(&s["Profile ".len()..]).is_none())
+        .collect();
+
+    for section in removed {
+        file.delete(Some(section));
+    }
+
+    let names: Vec<String> = config.profiles.iter().map(|p| p.name.clone()).collect();
+
+    file.with_section(Some("Profiles"))
+        .set("names", names.join(","))
+        .set("current", config.profile.clone());
+
+    for profile in config.profiles.iter() {
+        file.with_section(Some(format!("Profile {}", profile.name)))
+            .set("path", profile.db_path.clone());
+    }
+
+    file.with_section(Some("General"))
+        .set("base_currency", config.base_currency.clone())
+        .set("date_format", config.date_format.name())
+        .set("default_payment", config.default_payment.clone());
+
+    file.with_section(Some("Window"))
+        .set("width", config.window.width.to_string())
+        .set("height", config.window.height.to_string())
+        .set("maximized", config.window.maximized.to_string());
+
+    file.with_section(Some("Columns"))
+        .set("order", config.columns.order.join(","))
+        .set("hidden", config.columns.hidden.join(","));
+
+    file.with_section(Some("Trash"))
+        .set("retention_days", config.trash_retention_days.to_string());
+
+    file.with_section(Some("Backup"))
+        .set("enabled", config.backup.enabled.to_string())
+        .set("directory", config.backup.directory.clone())
+        .set("keep_daily", config.backup.keep_daily.to_string())
+        .set("keep_weekly", config.backup.keep_weekly.to_string())
+        .set("keep_monthly", config.backup.keep_monthly.to_string());
+
+    file.with_section(Some("Security"))
+        .set("lock_minutes", config.lock_minutes.to_string());
+
+    if let Err(err) = file.write_to_file(conf_path.to_str().unwrap()) {
+        eprintln!("{}", tr_format("Failed to write config file: {}", &[err.to_string().as_str()]));
+    }
+}
+
+/// Path of the config file
+///
+/// Follows the XDG base directory specification.
+fn config_path() -> PathBuf {
+    let mut conf_path = match env::var("XDG_CONFIG_HOME") {
+        Ok(ref dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => {
+            let mut dir = env::home_dir().unwrap_or(PathBuf::from("."));
+            dir.push(".config");
+            dir
+        }
+    };
+
+    conf_path.push("receipt-keeper");
+    conf_path.push("config.ini");
+
+    conf_path
+}
+
+/// Directory where backups are stored by default
+///
+/// Follows the XDG base directory specification.
+fn default_backup_dir() -> String {
+    let mut dir = match env::var("XDG_DATA_HOME") {
+        Ok(ref dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => {
+            let mut dir = env::home_dir().unwrap_or(PathBuf::from("."));
+            dir.push(".local");
+            dir.push("share");
+            dir
+        }
+    };
 
-    let db_sec = conf.section(Some("DB".to_owned())).unwrap();
-    let db_path = db_sec.get("path").unwrap();
+    dir.push("receipt-keeper");
+    dir.push("backups");
 
-    // return State{ db_path: db_path.to_string() };
-    db_path.to_string()
+    dir.to_string_lossy().into_owned()
 }
 
-/// Creates an empty config file
-fn create_empty_config(conf_path: &PathBuf) {
-    let mut conf = Ini::new();
+/// Path of the config file used by older versions, if it exists
+fn legacy_path() -> Option<PathBuf> {
+    let mut path = match env::home_dir() {
+        Some(dir) => dir,
+        None => return None
+    };
+
+    path.push(".receipt-keeper");
 
-    conf.with_section(Some("DB".to_owned()))
-        .set("path", "");
+    if path.is_file() { Some(path) } else { None }
+}
 
-    conf.write_to_file(conf_path.to_str().unwrap()).unwrap();
+/// Read a value from the config file
+///
+/// Missing and invalid values are `None`.
+fn get<T: FromStr>(file: &Ini, section: &str, key: &str) -> Option<T> {
+    file.get_from(Some(section), key)
+        .and_then(|value| value.trim().parse().ok())
 }
 
-/// Write configuration to file
-pub fn write_config_file(state: &State) {
-    let mut conf_path = env::home_dir().unwrap();
-    conf_path.push(".receipt-keeper");
+/// Build the configuration from the contents of a config file
+///
+/// Files without profiles get a default profile with the database path in
+/// the `[DB]` section, if any.
+fn parse(file: Ini) -> Config {
+    let mut config = Config::new();
+
+    // Profiles
+    let names: String = get(&file, "Profiles", "names").unwrap_or(String::new());
+    let mut profiles = Vec::new();
+
+    for name in names.split(',').map(|n| n.trim()).filter(|n| !n.is_empty()) {
+        profiles.push(Profile {
+            name: name.to_string(),
+            db_path: get(&file, format!("Profile {}", name).as_str(), "path")
+                .unwrap_or(String::new())
+        });
+    }
+
+    if profiles.is_empty() {
+        profiles.push(Profile {
+            name: DEFAULT_PROFILE.to_string(),
+            db_path: get(&file, "DB", "path").unwrap_or(String::new())
+        });
+    }
+
+    let current: String = get(&file, "Profiles", "current").unwrap_or(String::new());
 
-    let mut conf = Ini::new();
+    config.profile = if profiles.iter().any(|p| p.name == current) {
+        current
+    } else {
+        profiles[0].name.clone()
+    };
+
+    config.profiles = profiles;
+
+    // General
+    if let Some(currency) = get::<String>(&file, "General", "base_currency") {
+        if CURRENCIES.contains(&currency.as_str()) {
+            config.base_currency = currency;
+        }
+    }
+
+    if let Some(format) = get::<String>(&file, "General", "date_format") {
+        if let Some(format) = DateFormat::from_name(format.as_str()) {
+            config.date_format = format;
+        }
+    }
+
+    if let Some(payment) = get::<String>(&file, "General", "default_payment") {
+        if PAYMENTS.contains(&payment.as_str()) {
+            config.default_payment = payment;
+        }
+    }
+
+    // Window
+    match get::<i32>(&file, "Window", "width") {
+        Some(width) if width > 0 => config.window.width = width,
+        _ => {}
+    }
+
+    match get::<i32>(&file, "Window", "height") {
+        Some(height) if height > 0 => config.window.height = height,
+        _ => {}
+    }
+
+    if let Some(maximized) = get(&file, "Window", "maximized") {
+        config.window.maximized = maximized;
+    }
+
+    // Columns: unknown ones are ignored and missing ones are appended at the
+    // end, so that the layout survives changes in the available columns
+    if let Some(order) = get::<String>(&file, "Columns", "order") {
+        let mut columns: Vec<String> = order.split(',')
+            .map(|c| c.trim().to_string())
+            .filter(|c| COLUMNS.contains(&c.as_str()))
+            .collect();
+
+        for column in COLUMNS.iter() {
+            if !columns.iter().any(|c| c == column) {
+                columns.push(column.to_string());
+            }
+        }
+
+        config.columns.order = columns;
+    }
+
+    if let Some(hidden) = get::<String>(&file, "Columns", "hidden") {
+        config.columns.hidden = hidden.split(',')
+            .map(|c| c.trim().to_string())
+            .filter(|c| COLUMNS.contains(&c.as_str()))
+            .collect();
+    }
+
+    // Trash
+    match get::<i64>(&file, "Trash", "retention_days") {
+        Some(days) if days > 0 => config.trash_retention_days = days,
+        _ => {}
+    }
+
+    // Backup
+    if let Some(enabled) = get(&file, "Backup", "enabled") {
+        config.backup.enabled = enabled;
+    }
+
+    match get::<String>(&file, "Backup", "directory") {
+        Some(ref dir) if !dir.is_empty() => config.backup.directory = dir.clone(),
+        _ => {}
+    }
+
+    if let Some(keep) = get(&file, "Backup", "keep_daily") {
+        config.backup.keep_daily = keep;
+    }
+
+    if let Some(keep) = get(&file, "Backup", "keep_weekly") {
+        config.backup.keep_weekly = keep;
+    }
+
+    if let Some(keep) = get(&file, "Backup", "keep_monthly") {
+        config.backup.keep_monthly = keep;
+    }
+
+    // Security
+    if let Some(minutes) = get(&file, "Security", "lock_minutes") {
+        config.lock_minutes = minutes;
+    }
 
-    conf.with_section(Some("DB".to_owned()))
-        .set("path", state.db_path.clone());
+    config.file = file;
 
-    conf.write_to_file(conf_path.to_str().unwrap()).unwrap();
+    config
 }
@@ -0,0 +1,381 @@
+// MIT License
+//
+// Copyright (c) 2016 Rafael Medina García <rafamedgar@gmail.com>
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+/// Formatting and parsing of dates entered by the user
+
+use std::ffi::CStr;
+
+use chrono::*;
+use gettextrs::gettext;
+use libc;
+
+
+/// Format used to show and enter dates
+#[derive(Clone, Copy, PartialEq)]
+pub enum DateFormat {
+    // yyyy-mm-dd
+    Iso,
+    // dd/mm/yyyy
+    DayMonthYear,
+    // mm/dd/yyyy
+    MonthDayYear,
+    // One of the above, in the order of the date format of the locale
+    Locale
+}
+
+/// Formats that can be chosen, with their names in the config file
+pub const DATE_FORMATS: [(DateFormat, &'static str); 4] = [
+    (DateFormat::Iso, "iso"),
+    (DateFormat::DayMonthYear, "dmy"),
+    (DateFormat::MonthDayYear, "mdy"),
+    (DateFormat::Locale, "locale")
+];
+
+impl DateFormat {
+    /// Format with the given name in the config file
+    pub fn from_name(name: &str) -> Option<DateFormat> {
+        DATE_FORMATS.iter()
+            .find(|&&(_, n)| n == name)
+            .map(|&(format, _)| format)
+    }
+
+    /// Name of the format in the config file
+    pub fn name(&self) -> &'static str {
+        DATE_FORMATS.iter()
+            .find(|&&(format, _)| format == *self)
+            .map(|&(_, name)| name)
+            .unwrap()
+    }
+
+    /// Description of the format shown to the user
+    pub fn label(&self) -> String {
+        match *self {
+            DateFormat::Iso => gettext("yyyy-mm-dd"),
+            DateFormat::DayMonthYear => gettext("dd/mm/yyyy"),
+            DateFormat::MonthDayYear => gettext("mm/dd/yyyy"),
+            DateFormat::Locale => gettext("Locale default")
+        }
+    }
+
+    /// Hint shown in empty date entries
+    pub fn placeholder(&self) -> String {
+        self.resolve().label()
+    }
+
+    /// Show a date
+    pub fn format(&self, date: NaiveDate) -> String {
+        let pattern = match self.resolve() {
+            DateFormat::Iso => "%Y-%m-%d",
+            DateFormat::MonthDayYear => "%m/%d/%Y",
+            _ => "%d/%m/%Y"
+        };
+
+        date.format(pattern).to_string()
+    }
+
+    /// Show a date and time, up to minutes
+    pub fn format_datetime(&self, datetime: NaiveDateTime) -> String {
+        format!("{} {}", self.format(datetime.date()), datetime.format("%H:%M"))
+    }
+
+    /// Read a date entered by the user
+    ///
+    /// Besides dates in this format, ISO dates and relative dates such as
+    /// "today", "yesterday", "tomorrow", "friday" or "last friday" (both
+    /// the most recent friday before today) are accepted, as well as the
+    /// translations of "today", "yesterday" and "tomorrow". Any of `/`, `-`
+    /// and `.` separate the day, month and year, and two digit years are
+    /// taken to be within 80 years before and 19 years after this one.
+    pub fn parse(&self, text: &str) -> Option<NaiveDate> {
+        let text = text.trim().to_lowercase();
+
+        if text.is_empty() {
+            return None;
+        }
+
+        let today = Local::today().naive_local();
+
+        let relative = [
+            ("today", gettext("today"), today),
+            ("yesterday", gettext("yesterday"), today.pred()),
+            ("tomorrow", gettext("tomorrow"), today.succ())
+        ];
+
+        for &(word, ref translated, date) in relative.iter() {
+            if text == word || text == translated.to_lowercase() {
+                return Some(date);
+            }
+        }
+
+        let day_name = if text.starts_with("last ") { &text[5..] } else { text.as_str() };
+
+        if let Some(weekday) = parse_weekday(day_name.trim()) {
+            let mut date = today.pred();
+
+            while date.weekday() != weekday {
+                date = date.pred();
+            }
+
+            return Some(date);
+        }
+
+        let parts: Vec<&str> = text.split(|c| c == '/' || c == '-' || c == '.').collect();
+
+        if parts.len() != 3 {
+            return None;
+        }
+
+        let numbers: Vec<u32> = parts.iter().filter_map(|p| p.trim().parse().ok()).collect();
+
+        if numbers.len() != 3 {
+            return None;
+        }
+
+        // Years come first in ISO dates, whatever the format
+        let (year, month, day) = if parts[0].trim().len() == 4 {
+            (numbers[0], numbers[1], numbers[2])
+        } else {
+            match self.resolve() {
+                DateFormat::MonthDayYear => (numbers[2], numbers[0], numbers[1]),
+                DateFormat::Iso if parts[2].trim().len() <= 2 => (numbers[0], numbers[1], numbers[2]),
+                _ => (numbers[2], numbers[1], numbers[0])
+            }
+        };
+
+        let year = if year < 100 { expand_year(year, today.year()) } else { year as i32 };
+
+        NaiveDate::from_ymd_opt(year, month, day)
+    }
+
+    /// Actual format, replacing `Locale` with the one used in the locale
+    ///
+    /// The order of the day, month and year is taken from the date format of
+    /// the `LC_TIME` category of the locale, set by `i18n::init`.
+    fn resolve(&self) -> DateFormat {
+        if *self != DateFormat::Locale {
+            return *self;
+        }
+
+        let pattern = unsafe {
+            let pattern = libc::nl_langinfo(libc::D_FMT);
+
+            if pattern.is_null() {
+                return DateFormat::DayMonthYear;
+            }
+
+            CStr::from_ptr(pattern).to_string_lossy().into_owned()
+        };
+
+        order_of(pattern.as_str())
+    }
+}
+
+/// Format with the order of the day, month and year in a `strftime` pattern
+///
+/// Patterns starting with the year are shown as ISO dates. Patterns without a
+/// known order are taken to start with the day, the most common order.
+fn order_of(pattern: &str) -> DateFormat {
+    let mut fields = Vec::new();
+    let mut chars = pattern.chars();
+
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            continue;
+        }
+
+        // Skip flags and modifiers, e.g. %-d or %Ey
+        let mut conversion = chars.next();
+
+        while conversion.map_or(false, |c| "-_0^#EO".contains(c)) {
+            conversion = chars.next();
+        }
+
+        match conversion {
+            Some('d') | Some('e') => fields.push('d'),
+            Some('m') | Some('b') | Some('B') | Some('h') => fields.push('m'),
+            Some('Y') | Some('y') | Some('C') | Some('G') => fields.push('y'),
+            Some('D') => fields.extend(['m', 'd', 'y'].iter().cloned()),
+            Some('F') => fields.extend(['y', 'm', 'd'].iter().cloned()),
+            _ => {}
+        }
+    }
+
+    match fields.first() {
+        Some(&'y') => DateFormat::Iso,
+        Some(&'m') => DateFormat::MonthDayYear,
+        _ => DateFormat::DayMonthYear
+    }
+}
+
+/// Full year of a two digit year
+///
+/// Receipts are mostly from the past, but return and warranty dates can be
+/// some years ahead, so the year is taken within 80 years before and 19 years
+/// after `current`.
+fn expand_year(year: u32, current: i32) -> i32 {
+    let full = current - current % 100 + year as i32;
+
+    if full > current + 19 {
+        full - 100
+    } else if full < current - 80 {
+        full + 100
+    } else {
+        full
+    }
+}
+
+/// Day of the week from its English name or abbreviation
+fn parse_weekday(name: &str) -> Option<Weekday> {
+    let days = [
+        ("monday", Weekday::Mon),
+        ("tuesday", Weekday::Tue),
+        ("wednesday", Weekday::Wed),
+        ("thursday", Weekday::Thu),
+        ("friday", Weekday::Fri),
+        ("saturday", Weekday::Sat),
+        ("sunday", Weekday::Sun)
+    ];
+
+    if name.len() < 3 {
+        return None;
+    }
+
+    days.iter()
+        .find(|&&(day, _)| day.starts_with(name))
+        .map(|&(_, weekday)| weekday)
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::*;
+    use super::{expand_year, order_of, DateFormat};
+
+    fn date(year: i32, month: u32, day: u32) -> Option<NaiveDate> {
+        NaiveDate::from_ymd_opt(year, month, day)
+    }
+
+    #[test]
+    fn parses_relative_dates() {
+        let today = Local::today().naive_local();
+
+        assert_eq!(DateFormat::Iso.parse("today"), Some(today));
+        assert_eq!(DateFormat::Iso.parse(" Yesterday "), Some(today.pred()));
+        assert_eq!(DateFormat::Iso.parse("TOMORROW"), Some(today.succ()));
+    }
+
+    #[test]
+    fn parses_weekdays_as_the_last_one_before_today() {
+        let today = Local::today().naive_local();
+
+        for name in ["friday", "fri", "last friday", "last fri"].iter() {
+            let parsed = DateFormat::Iso.parse(name).unwrap();
+
+            assert_eq!(parsed.weekday(), Weekday::Fri);
+            assert!(parsed < today);
+            assert!(parsed >= today - Duration::days(7));
+        }
+
+        // Today's weekday is a week ago
+        let name = format!("{:?}", today.weekday()).to_lowercase();
+        assert_eq!(DateFormat::Iso.parse(name.as_str()), Some(today - Duration::days(7)));
+    }
+
+    #[test]
+    fn parses_dates_in_each_format() {
+        assert_eq!(DateFormat::Iso.parse("2024-03-05"), date(2024, 3, 5));
+        assert_eq!(DateFormat::DayMonthYear.parse("05/03/2024"), date(2024, 3, 5));
+        assert_eq!(DateFormat::MonthDayYear.parse("03/05/2024"), date(2024, 3, 5));
+
+        // Any separator
+        assert_eq!(DateFormat::DayMonthYear.parse("5.3.2024"), date(2024, 3, 5));
+        assert_eq!(DateFormat::DayMonthYear.parse("5-3-2024"), date(2024, 3, 5));
+    }
+
+    #[test]
+    fn parses_iso_dates_in_any_format() {
+        assert_eq!(DateFormat::DayMonthYear.parse("2024-03-05"), date(2024, 3, 5));
+        assert_eq!(DateFormat::MonthDayYear.parse("2024/03/05"), date(2024, 3, 5));
+    }
+
+    #[test]
+    fn parses_two_digit_years() {
+        let year = Local::today().year();
+        let expected = |short: u32| date(expand_year(short, year), 3, 5);
+
+        assert_eq!(DateFormat::DayMonthYear.parse("5/3/24"), expected(24));
+        assert_eq!(DateFormat::MonthDayYear.parse("3/5/99"), expected(99));
+        assert_eq!(DateFormat::Iso.parse("24-03-05"), expected(24));
+        assert_eq!(DateFormat::DayMonthYear.parse("5/3/00"), expected(0));
+    }
+
+    #[test]
+    fn expands_two_digit_years_around_the_current_one() {
+        assert_eq!(expand_year(24, 2026), 2024);
+        assert_eq!(expand_year(45, 2026), 2045);
+        assert_eq!(expand_year(46, 2026), 1946);
+        assert_eq!(expand_year(99, 2026), 1999);
+        assert_eq!(expand_year(0, 2026), 2000);
+        assert_eq!(expand_year(5, 2090), 2105);
+        assert_eq!(expand_year(10, 2090), 2010);
+    }
+
+    #[test]
+    fn reads_the_order_of_locale_formats() {
+        // en_US, en_PH, C
+        assert!(order_of("%m/%d/%Y") == DateFormat::MonthDayYear);
+        assert!(order_of("%m/%d/%y") == DateFormat::MonthDayYear);
+        assert!(order_of("%D") == DateFormat::MonthDayYear);
+
+        // en_CA, fr_CA, sv_SE, ja_JP
+        assert!(order_of("%Y-%m-%d") == DateFormat::Iso);
+        assert!(order_of("%F") == DateFormat::Iso);
+        assert!(order_of("%Y\u{5e74}%m\u{6708}%d\u{65e5}") == DateFormat::Iso);
+
+        // en_GB, de_DE, es_ES, and unknown orders
+        assert!(order_of("%d/%m/%Y") == DateFormat::DayMonthYear);
+        assert!(order_of("%d.%m.%Y") == DateFormat::DayMonthYear);
+        assert!(order_of("%-d. %B %Y") == DateFormat::DayMonthYear);
+        assert!(order_of("") == DateFormat::DayMonthYear);
+    }
+
+    #[test]
+    fn rejects_invalid_dates() {
+        let invalid = ["", "   ", "someday", "last", "fr", "1/2", "1/2/3/4", "a/b/c",
+                       "32/01/2024", "31/02/2023", "01/13/2024", "0/1/2024"];
+
+        for text in invalid.iter() {
+            assert_eq!(DateFormat::DayMonthYear.parse(text), None, "{}", text);
+        }
+
+        assert_eq!(DateFormat::MonthDayYear.parse("13/01/2024"), None);
+        assert_eq!(DateFormat::Iso.parse("2024-02-30"), None);
+    }
+
+    #[test]
+    fn formats_dates() {
+        let day = NaiveDate::from_ymd(2024, 3, 5);
+
+        assert_eq!(DateFormat::Iso.format(day), "2024-03-05");
+        assert_eq!(DateFormat::DayMonthYear.format(day), "05/03/2024");
+        assert_eq!(DateFormat::MonthDayYear.format(day), "03/05/2024");
+    }
+}
@@ -22,15 +22,22 @@
 
 /// Operations in the database
 
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::env;
 use std::os::raw::{c_int, c_double};
 use std::path::Path;
 
 use chrono::*;
-use rusqlite::Connection;
-use rusqlite::Result;
+use gettextrs::gettext;
+use rusqlite::{Connection, Row};
+use rusqlite::types::ToSql;
+use rusqlite::{Error, Result};
+
+use common::{CURRENCIES, PAYMENTS};
 
 /// Receipt model
-#[derive(Clone)]
+#[derive(Clone, PartialEq)]
 pub struct Receipt {
     pub id: i32,
     pub description: String,
@@ -38,7 +45,22 @@ pub struct Receipt {
     pub amount: c_double,
     pub currency: String,
     pub payment_type: String,
-    pub date_paid: NaiveDate
+    pub date_paid: NaiveDate,
+    pub return_by: Option<NaiveDate>,
+    pub warranty_until: Option<NaiveDate>,
+    // Incremented on every update
+    pub version: i32,
+    // Set while the receipt is in the trash
+    pub deleted_at: Option<NaiveDateTime>,
+    pub created_at: NaiveDateTime,
+    // Time of the last change to the details of the receipt
+    pub updated_at: NaiveDateTime,
+    // Statement the receipt was imported from, e.g. "OFX: march.ofx"
+    pub import_source: Option<String>,
+    // Identifier of the transaction in the statement
+    pub import_id: Option<String>,
+    // Account of the statement, if known
+    pub import_account: Option<String>
 }
 
 impl Receipt {
@@ -51,107 +73,1518 @@ impl Receipt {
             amount: 0.0,
             currency: "".to_string(),
             payment_type: "".to_string(),
-            date_paid: NaiveDate::from_ymd(1970, 1, 1)
+            date_paid: NaiveDate::from_ymd(1970, 1, 1),
+            return_by: None,
+            warranty_until: None,
+            version: 1,
+            deleted_at: None,
+            created_at: NaiveDate::from_ymd(1970, 1, 1).and_hms(0, 0, 0),
+            updated_at: NaiveDate::from_ymd(1970, 1, 1).and_hms(0, 0, 0),
+            import_source: None,
+            import_id: None,
+            import_account: None
+        }
+    }
+
+    /// Check the values of the receipt before storing it
+    ///
+    /// Returns every field with an invalid value. Dates are always valid
+    /// once parsed; callers report dates they cannot parse with
+    /// `Invalid::Date`.
+    pub fn validate(&self) -> ::std::result::Result<(), Vec<FieldError>> {
+        let mut errors = Vec::new();
+
+        {
+            let mut error = |field, reason| {
+                errors.push(FieldError { field: field, reason: reason });
+            };
+
+            if self.shop.trim().is_empty() {
+                error(Field::Shop, Invalid::Missing);
+            }
+
+            if !self.amount.is_finite() {
+                error(Field::Amount, Invalid::NotANumber);
+
+            } else if self.amount < 0.0 {
+                error(Field::Amount, Invalid::Negative);
+            }
+
+            if self.payment_type.is_empty() {
+                error(Field::PaymentType, Invalid::Missing);
+
+            } else if !PAYMENTS.contains(&self.payment_type.as_str()) {
+                error(Field::PaymentType, Invalid::Unknown);
+            }
+
+            if self.currency.is_empty() {
+                error(Field::Currency, Invalid::Missing);
+
+            } else if !CURRENCIES.contains(&self.currency.as_str()) {
+                error(Field::Currency, Invalid::Unknown);
+            }
+        }
+
+        if errors.is_empty() { Ok(()) } else { Err(errors) }
+    }
+}
+
+/// Field of a receipt entered by the user
+#[derive(Clone, Copy, PartialEq)]
+pub enum Field {
+    Shop,
+    Amount,
+    PaymentType,
+    Currency,
+    DatePaid,
+    ReturnBy,
+    WarrantyUntil
+}
+
+impl Field {
+    /// Name of the field shown to the user
+    pub fn label(&self) -> String {
+        match *self {
+            Field::Shop => gettext("Shop"),
+            Field::Amount => gettext("Cost"),
+            Field::PaymentType => gettext("Type"),
+            Field::Currency => gettext("Currency"),
+            Field::DatePaid => gettext("Date"),
+            Field::ReturnBy => gettext("Return by"),
+            Field::WarrantyUntil => gettext("Warranty until")
         }
     }
 }
 
-/// Obtain a connection to the database
-pub fn open_connection(db_path: &str) -> Result<Connection> {
-    let path = Path::new(db_path);
+/// Reason the value of a field is invalid
+#[derive(Clone, Copy, PartialEq)]
+pub enum Invalid {
+    /// Required value that is empty
+    Missing,
+    /// Value not among the accepted ones
+    Unknown,
+    /// Negative amount
+    Negative,
+    /// Amount that is not a finite number
+    NotANumber,
+    /// Text that is not a date
+    Date
+}
 
-    Connection::open(path)
+/// Invalid value in a field of a receipt
+#[derive(Clone, Copy, PartialEq)]
+pub struct FieldError {
+    pub field: Field,
+    pub reason: Invalid
 }
 
-/// Delete a receipt from the database
-pub fn delete_receipt(db_path: &str, id: i32) -> Result<c_int> {
-    let conn = try!(open_connection(&db_path));
+impl FieldError {
+    /// Explanation shown to the user
+    pub fn message(&self) -> String {
+        match self.reason {
+            Invalid::Missing => gettext("This field is required"),
+            Invalid::Unknown => gettext("Unknown value"),
+            Invalid::Negative => gettext("Cannot be negative"),
+            Invalid::NotANumber => gettext("Not a number"),
+            Invalid::Date => gettext("Not a valid date")
+        }
+    }
+}
 
-    conn.execute("
-        DELETE FROM receipts
-        WHERE id=$1",
-        &[&id])
+/// Receipt search filter
+///
+/// Empty strings, zero amounts and `None` dates are not used for filtering.
+/// `text` is searched in the full-text index of shops and descriptions.
+/// `entered_days` matches receipts entered in that many last days.
+/// Receipts in the trash are only matched when `deleted` is set, in which
+/// case no other receipt is.
+#[derive(Clone)]
+pub struct Filter {
+    pub text: String,
+    pub shop: String,
+    pub cost_from: c_double,
+    pub cost_to: c_double,
+    pub date_from: Option<NaiveDate>,
+    pub date_to: Option<NaiveDate>,
+    pub payment_type: String,
+    pub currency: String,
+    pub expiring_days: i64,
+    pub entered_days: i64,
+    pub deleted: bool
 }
 
-/// Fetch a single receipt from database
-pub fn get_receipt(db_path: &str, id: i32) -> Result<Receipt> {
-    let conn = try!(open_connection(&db_path));
+impl Filter {
+    /// Create a filter that matches every receipt
+    pub fn new() -> Filter {
+        Filter {
+            text: "".to_string(),
+            shop: "".to_string(),
+            cost_from: 0.0,
+            cost_to: 0.0,
+            date_from: None,
+            date_to: None,
+            payment_type: "".to_string(),
+            currency: "".to_string(),
+            expiring_days: 0,
+            entered_days: 0,
+            deleted: false
+        }
+    }
+}
 
-    conn.query_row("
-        SELECT * FROM receipts
-        WHERE id=$1",
-        &[&id], |row| {
-            Receipt {
-                id: row.get(0),
-                description: row.get(1),
-                shop: row.get(2),
-                amount: row.get(3),
-                currency: row.get(4),
-                payment_type: row.get(5),
-                date_paid: row.get(6)
-            }
-        })
+/// Column used to order receipts
+#[derive(Clone, Copy, PartialEq)]
+pub enum SortKey {
+    Id,
+    Shop,
+    Amount,
+    Currency,
+    PaymentType,
+    DatePaid,
+    ReturnBy,
+    WarrantyUntil,
+    Description,
+    CreatedAt
 }
 
-/// Obtain all receipts from the database
-pub fn get_all_receipts(db_path: &str) -> Result<Vec<Receipt>> {
-    let conn = try!(open_connection(&db_path));
+impl SortKey {
+    /// SQL expression used for ordering
+    ///
+    /// Nullable columns are compared as empty strings.
+    fn expression(&self) -> &'static str {
+        match *self {
+            SortKey::Id => "receipts.id",
+            SortKey::Shop => "receipts.shop",
+            SortKey::Amount => "receipts.amount",
+            SortKey::Currency => "receipts.currency",
+            SortKey::PaymentType => "receipts.payment_type",
+            SortKey::DatePaid => "receipts.date_paid",
+            SortKey::ReturnBy => "IFNULL(receipts.return_by, '')",
+            SortKey::WarrantyUntil => "IFNULL(receipts.warranty_until, '')",
+            SortKey::Description => "IFNULL(receipts.description, '')",
+            SortKey::CreatedAt => "receipts.created_at"
+        }
+    }
+
+    /// Value of the sort column for a receipt
+    fn value(&self, receipt: &Receipt) -> Box<ToSql> {
+        match *self {
+            SortKey::Id => Box::new(receipt.id),
+            SortKey::Shop => Box::new(receipt.shop.clone()),
+            SortKey::Amount => Box::new(receipt.amount),
+            SortKey::Currency => Box::new(receipt.currency.clone()),
+            SortKey::PaymentType => Box::new(receipt.payment_type.clone()),
+            SortKey::DatePaid => Box::new(receipt.date_paid),
+            SortKey::ReturnBy => Box::new(
+                receipt.return_by.map_or(String::new(), |d| d.to_string())),
+            SortKey::WarrantyUntil => Box::new(
+                receipt.warranty_until.map_or(String::new(), |d| d.to_string())),
+            SortKey::Description => Box::new(receipt.description.clone()),
+            SortKey::CreatedAt => Box::new(receipt.created_at)
+        }
+    }
+}
 
-    let mut query = conn.prepare("SELECT * from receipts").unwrap();
+/// Order of receipts in a page
+#[derive(Clone, Copy, PartialEq)]
+pub struct Sort {
+    pub key: SortKey,
+    pub descending: bool
+}
 
-    let mut rows = query.query_map(&[], |row| {
-        Receipt {
-            id: row.get(0),
-            description: row.get(1),
-            shop: row.get(2),
-            amount: row.get(3),
-            currency: row.get(4),
-            payment_type: row.get(5),
-            date_paid: row.get(6)
+/// Filter stored under a name
+#[derive(Clone)]
+pub struct SavedSearch {
+    pub id: i32,
+    pub name: String,
+    pub filter: Filter
+}
+
+/// Full-text search result
+///
+/// Matched terms in `highlight` are enclosed in `MATCH_START` and `MATCH_END`.
+pub struct SearchResult {
+    pub receipt: Receipt,
+    pub highlight: String
+}
+
+/// Marks the start of a matched term in search results
+pub const MATCH_START: char = '\u{1}';
+
+/// Marks the end of a matched term in search results
+pub const MATCH_END: char = '\u{2}';
+
+/// Kind of change made to a receipt
+#[derive(Clone, Copy, PartialEq)]
+pub enum HistoryAction {
+    Created,
+    Updated,
+    Deleted,
+    Restored,
+    Purged,
+    Repaired
+}
+
+impl HistoryAction {
+    fn to_str(&self) -> &'static str {
+        match *self {
+            HistoryAction::Created => "insert",
+            HistoryAction::Updated => "update",
+            HistoryAction::Deleted => "delete",
+            HistoryAction::Restored => "restore",
+            HistoryAction::Purged => "purge",
+            HistoryAction::Repaired => "repair"
+        }
+    }
+
+    fn from_str(action: &str) -> HistoryAction {
+        match action {
+            "insert" => HistoryAction::Created,
+            "delete" => HistoryAction::Deleted,
+            "restore" => HistoryAction::Restored,
+            "purge" => HistoryAction::Purged,
+            "repair" => HistoryAction::Repaired,
+            _ => HistoryAction::Updated
         }
-    }).unwrap();
+    }
+}
+
+/// Change made to a receipt, with the values before and after it
+///
+/// There are no old values for created or restored receipts, nor for
+/// repaired ones whose values could not be read, and no new values for
+/// deleted or purged ones.
+#[derive(Clone)]
+pub struct Revision {
+    pub id: i32,
+    pub action: HistoryAction,
+    pub changed_at: NaiveDateTime,
+    pub user: String,
+    pub old: Option<Receipt>,
+    pub new: Option<Receipt>
+}
+
+/// Line of an imported bank or card statement
+///
+/// Payments are matched with the receipts they paid for when reconciling.
+#[derive(Clone)]
+pub struct BankTransaction {
+    pub id: i32,
+    // Name of the statement format, e.g. "OFX"
+    pub format: String,
+    // Statement the transaction was imported from, e.g. "OFX: march.ofx"
+    pub source: String,
+    // Account of the statement, empty if unknown
+    pub account: String,
+    // Identifier given by the bank, if any
+    pub bank_id: Option<String>,
+    pub date_posted: NaiveDate,
+    // Negative for payments, positive for deposits and refunds
+    pub amount: c_double,
+    pub currency: String,
+    pub payee: String,
+    pub memo: String,
+    // Number of identical lines without identifier before it in its statement
+    pub occurrence: i32,
+    // Receipt the payment was matched with
+    pub receipt_id: Option<i32>,
+    // Set for payments that need no receipt
+    pub ignored: bool,
+    pub imported_at: NaiveDateTime
+}
 
-    let mut receipts = Vec::new();
+/// Build a receipt from a row of the receipts table
+fn receipt_from_row(row: &Row) -> Receipt {
+    Receipt {
+        id: row.get("id"),
+        description: row.get("description"),
+        shop: row.get("shop"),
+        amount: row.get("amount"),
+        currency: row.get("currency"),
+        payment_type: row.get("payment_type"),
+        date_paid: row.get("date_paid"),
+        return_by: row.get("return_by"),
+        warranty_until: row.get("warranty_until"),
+        version: row.get("version"),
+        deleted_at: row.get("deleted_at"),
+        created_at: row.get("created_at"),
+        updated_at: row.get("updated_at"),
+        import_source: row.get("import_source"),
+        import_id: row.get("import_id"),
+        import_account: row.get("import_account")
+    }
+}
 
-    for receipt in rows {
-        receipts.push(receipt.unwrap());
+/// Build a bank transaction from a row of the transactions table
+fn transaction_from_row(row: &Row) -> BankTransaction {
+    BankTransaction {
+        id: row.get("id"),
+        format: row.get("format"),
+        source: row.get("source"),
+        account: row.get("account"),
+        bank_id: row.get("bank_id"),
+        date_posted: row.get("date_posted"),
+        amount: row.get("amount"),
+        currency: row.get("currency"),
+        payee: row.get("payee"),
+        memo: row.get("memo"),
+        occurrence: row.get("occurrence"),
+        receipt_id: row.get("receipt_id"),
+        ignored: row.get("ignored"),
+        imported_at: row.get("imported_at")
     }
+}
+
+/// Convert a row of the history table into a revision
+fn revision_from_row(row: &Row) -> Revision {
+    let action: String = row.get("action");
 
-    Ok(receipts)
+    Revision {
+        id: row.get("id"),
+        action: HistoryAction::from_str(action.as_str()),
+        changed_at: row.get("changed_at"),
+        user: row.get("user"),
+        old: history_values(row, "old_"),
+        new: history_values(row, "new_")
+    }
 }
 
-/// Insert a new receipt in the database
-pub fn insert_receipt(db_path: &str, receipt: &Receipt) -> Result<c_int> {
-    let conn = try!(open_connection(&db_path));
+/// Values of a receipt stored in a row of the history table
+///
+/// Columns are named after the fields of the receipt with the given prefix.
+///
+/// Creation and modification times are not stored, the time of the change is
+/// used for both.
+fn history_values(row: &Row, prefix: &str) -> Option<Receipt> {
+    let column = |name: &str| format!("{}{}", prefix, name);
+    let changed_at: NaiveDateTime = row.get("changed_at");
 
-    conn.execute("
-        INSERT INTO receipts (description, shop, amount, currency, payment_type, date_paid)
-        VALUES ($1, $2, $3, $4, $5, $6)",
-        &[
-            &receipt.description,
-            &receipt.shop,
-            &receipt.amount,
-            &receipt.currency,
-            &receipt.payment_type,
-            &receipt.date_paid
-        ])
+    let version: Option<i32> = row.get(column("version").as_str());
+
+    version.map(|version| {
+        Receipt {
+            id: row.get("receipt_id"),
+            description: row.get(column("description").as_str()),
+            shop: row.get(column("shop").as_str()),
+            amount: row.get(column("amount").as_str()),
+            currency: row.get(column("currency").as_str()),
+            payment_type: row.get(column("payment_type").as_str()),
+            date_paid: row.get(column("date_paid").as_str()),
+            return_by: row.get(column("return_by").as_str()),
+            warranty_until: row.get(column("warranty_until").as_str()),
+            version: version,
+            deleted_at: None,
+            created_at: changed_at,
+            updated_at: changed_at,
+            import_source: None,
+            import_id: None,
+            import_account: None
+        }
+    })
 }
 
-/// Update the details of a receipt in the database
-pub fn update_receipt(db_path: &str, receipt: &Receipt) -> Result<c_int> {
-    let conn = try!(open_connection(&db_path));
+/// Name of the user running the application
+fn os_user() -> String {
+    env::var("USER")
+        .or(env::var("USERNAME"))
+        .unwrap_or(String::new())
+}
+
+/// Quote a string as an SQL literal
+pub fn quote(text: &str) -> String {
+    format!("'{}'", text.replace("'", "''"))
+}
+
+/// Set the passphrase used to read and write an encrypted database
+///
+/// Must be done before anything else is read from the connection. Builds
+/// without the `sqlcipher` feature link plain SQLite, which ignores it.
+pub fn apply_key(conn: &Connection, key: &str) -> Result<()> {
+    conn.execute_batch(format!("PRAGMA key = {};", quote(key)).as_str())
+}
+
+/// Database handle
+///
+/// Keeps a connection open for the lifetime of the handle.
+pub struct Database {
+    conn: Connection,
+    // Number of nested transactions
+    depth: Cell<u32>,
+    // Passphrase of an encrypted database
+    key: RefCell<Option<String>>
+}
+
+impl Database {
+    /// Open the database
+    ///
+    /// The connection uses write-ahead logging and waits for locks held by
+    /// other connections instead of failing immediately.
+    pub fn open(db_path: &str) -> Result<Database> {
+        Database::open_with_key(db_path, None)
+    }
+
+    /// Open an encrypted database with its passphrase
+    ///
+    /// Fails if the passphrase is wrong.
+    pub fn open_with_key(db_path: &str, key: Option<&str>) -> Result<Database> {
+        let conn = try!(Connection::open(Path::new(db_path)));
+
+        if let Some(key) = key {
+            try!(apply_key(&conn, key));
+        }
+
+        // Nothing is decrypted until the first read
+        try!(conn.query_row("SELECT count(*) FROM sqlite_master", &[], |_| ()));
+
+        try!(conn.execute_batch("
+            PRAGMA journal_mode = WAL;
+            PRAGMA busy_timeout = 5000;
+            "));
+
+        Ok(Database {
+            conn: conn,
+            depth: Cell::new(0),
+            key: RefCell::new(key.map(|k| k.to_string()))
+        })
+    }
+
+    /// Passphrase the database was opened with, if it is encrypted
+    pub fn key(&self) -> Option<String> {
+        self.key.borrow().clone()
+    }
+
+    /// Remember a new passphrase after the database has been rekeyed
+    pub fn set_key(&self, key: Option<&str>) {
+        *self.key.borrow_mut() = key.map(|k| k.to_string());
+    }
+
+    /// Underlying connection
+    pub fn connection(&self) -> &Connection {
+        &self.conn
+    }
+
+    /// Run several operations in a single transaction
+    ///
+    /// Changes are committed if the closure succeeds and rolled back
+    /// otherwise. Transactions can be nested, inner ones use savepoints.
+    pub fn transaction<T, F>(&self, f: F) -> Result<T>
+        where F: FnOnce(&Database) -> Result<T> {
+
+        let depth = self.depth.get();
+
+        let (begin, commit, rollback) = if depth == 0 {
+            ("BEGIN IMMEDIATE".to_string(), "COMMIT".to_string(), "ROLLBACK".to_string())
+        } else {
+            (format!("SAVEPOINT sp{}", depth),
+             format!("RELEASE sp{}", depth),
+             format!("ROLLBACK TO sp{0}; RELEASE sp{0}", depth))
+        };
+
+        try!(self.conn.execute_batch(begin.as_str()));
+        self.depth.set(depth + 1);
+
+        let result = f(self);
+        self.depth.set(depth);
+
+        match result {
+            Ok(value) => {
+                try!(self.conn.execute_batch(commit.as_str()));
+                Ok(value)
+            },
+
+            Err(err) => {
+                let _ = self.conn.execute_batch(rollback.as_str());
+                Err(err)
+            }
+        }
+    }
+
+    /// Roll back every open transaction
+    ///
+    /// Used after an operation panicked in the middle of a transaction.
+    pub fn rollback_all(&self) {
+        if self.depth.get() > 0 {
+            let _ = self.conn.execute_batch("ROLLBACK");
+            self.depth.set(0);
+        }
+    }
+
+    /// Counter of changes committed by other connections
+    ///
+    /// The value changes whenever another connection, possibly from another
+    /// process, commits changes to the database.
+    pub fn data_version(&self) -> Result<i32> {
+        self.conn.query_row("PRAGMA data_version", &[], |row| row.get(0))
+    }
+
+    /// Fetch a single receipt from database
+    pub fn get_receipt(&self, id: i32) -> Result<Receipt> {
+        let conn = &self.conn;
+
+        conn.query_row("
+            SELECT * FROM receipts
+            WHERE id=$1",
+            &[&id], |row| receipt_from_row(&row))
+    }
+
+    /// Obtain receipts whose return or warranty period ends in the next `days` days
+    ///
+    /// Periods that already ended are not included. Receipts are ordered by
+    /// the first period that ends in those days.
+    pub fn get_expiring_receipts(&self, days: i64) -> Result<Vec<Receipt>> {
+        let conn = &self.conn;
+
+        let today = Local::today().naive_local();
+        let limit = today + Duration::days(days);
+
+        let mut query = try!(conn.prepare("
+            SELECT * FROM receipts
+            WHERE deleted_at IS NULL
+                AND ((return_by BETWEEN $1 AND $2) OR (warranty_until BETWEEN $1 AND $2))
+            ORDER BY MIN(
+                IFNULL(CASE WHEN return_by BETWEEN $1 AND $2 THEN return_by END, warranty_until),
+                IFNULL(CASE WHEN warranty_until BETWEEN $1 AND $2 THEN warranty_until END, return_by))"));
+
+        let rows = try!(query.query_map(&[&today, &limit], receipt_from_row));
+
+        let mut receipts = Vec::new();
+
+        for receipt in rows {
+            receipts.push(try!(receipt));
+        }
+
+        Ok(receipts)
+    }
+
+    /// Find a page of receipts matching a filter
+    ///
+    /// Pages use keyset pagination: `after` is the last receipt of the previous
+    /// page, or `None` to obtain the first page. Receipts with the same sort
+    /// value are ordered by their ID.
+    ///
+    /// When the filter contains text, results include a highlight of the
+    /// matched terms. Otherwise the highlight is empty.
+    pub fn find_receipts_page(&self, filter: &Filter, sort: &Sort,
+                                  after: Option<&Receipt>, limit: i32)
+                                  -> Result<Vec<SearchResult>> {
+
+        let direction = if sort.descending { "DESC" } else { "ASC" };
+        let order = format!(
+            "ORDER BY {} {}, receipts.id {} LIMIT {}",
+            sort.key.expression(), direction, direction, limit);
+
+        match after {
+            Some(last) => {
+                let condition = format!(
+                    "({expr} {cmp} ? OR ({expr} = ? AND receipts.id {cmp} ?))",
+                    expr = sort.key.expression(),
+                    cmp = if sort.descending { "<" } else { ">" });
+
+                let value = sort.key.value(last);
+
+                self.select_receipts(
+                    filter,
+                    condition.as_str(),
+                    &[&*value, &*value, &last.id],
+                    order.as_str())
+            },
+
+            None => self.select_receipts(filter, "1", &[], order.as_str())
+        }
+    }
+
+    /// Fetch a single receipt if it matches a filter
+    pub fn find_receipt(&self, filter: &Filter, id: i32) -> Result<Option<SearchResult>> {
+        let mut results = try!(self.select_receipts(filter, "receipts.id = ?", &[&id], ""));
+
+        Ok(results.pop())
+    }
+
+    /// Select receipts matching a filter and an additional condition
+    ///
+    /// `tail` is appended to the query and may contain ORDER BY and LIMIT clauses.
+    fn select_receipts(&self, filter: &Filter, condition: &str,
+                           condition_params: &[&ToSql], tail: &str)
+                           -> Result<Vec<SearchResult>> {
+
+        let conn = &self.conn;
+
+        let values = FilterValues::new(filter);
+        let (conditions, mut params) = filter_conditions(filter, &values);
+
+        params.extend_from_slice(condition_params);
+
+        let sql = if values.fts_query.is_empty() {
+            format!("
+                SELECT receipts.*, '' AS shop_match, NULL AS description_match
+                FROM receipts
+                WHERE {} AND {}
+                {}", conditions, condition, tail)
+
+        } else {
+            format!("
+                SELECT receipts.*,
+                    highlight(receipts_fts, 0, char(1), char(2)) AS shop_match,
+                    snippet(receipts_fts, 1, char(1), char(2), '...', 8) AS description_match
+                FROM receipts_fts
+                JOIN receipts ON receipts.id = receipts_fts.rowid
+                WHERE {} AND {}
+                {}", conditions, condition, tail)
+        };
+
+        let mut query = try!(conn.prepare(sql.as_str()));
+
+        let rows = try!(query.query_map(&params, |row| {
+            let shop_match: String = row.get("shop_match");
+            let description_match: Option<String> = row.get("description_match");
+
+            let mut highlight = shop_match;
+
+            // Only show the description when it contains a match
+            if let Some(description) = description_match {
+                if description.contains(MATCH_START) {
+                    highlight.push_str(" - ");
+                    highlight.push_str(description.replace("\n", " ").as_str());
+                }
+            }
+
+            SearchResult {
+                receipt: receipt_from_row(row),
+                highlight: highlight
+            }
+        }));
+
+        let mut results = Vec::new();
+
+        for result in rows {
+            results.push(try!(result));
+        }
+
+        Ok(results)
+    }
+
+    /// Count the receipts matching a filter
+    pub fn count_receipts(&self, filter: &Filter) -> Result<i32> {
+        let conn = &self.conn;
+
+        let values = FilterValues::new(filter);
+        let (conditions, params) = filter_conditions(filter, &values);
+
+        let sql = if values.fts_query.is_empty() {
+            format!("SELECT COUNT(*) FROM receipts WHERE {}", conditions)
+
+        } else {
+            format!("
+                SELECT COUNT(*) FROM receipts_fts
+                JOIN receipts ON receipts.id = receipts_fts.rowid
+                WHERE {}", conditions)
+        };
+
+        conn.query_row(sql.as_str(), &params, |row| row.get(0))
+    }
+
+    /// Insert a new receipt in the database
+    ///
+    /// The receipt must have been checked with `validate`. Returns the ID of
+    /// the new receipt.
+    pub fn insert_receipt(&self, receipt: &Receipt) -> Result<i32> {
+        let now = Local::now().naive_local();
+
+        self.transaction(|db| {
+            try!(db.conn.execute("
+                INSERT INTO receipts (description, shop, amount, currency, payment_type, date_paid,
+                                      return_by, warranty_until, created_at, updated_at,
+                                      import_source, import_id, import_account)
+                VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $9, $10, $11, $12)",
+                &[
+                    &receipt.description,
+                    &receipt.shop,
+                    &receipt.amount,
+                    &receipt.currency,
+                    &receipt.payment_type,
+                    &receipt.date_paid,
+                    &receipt.return_by,
+                    &receipt.warranty_until,
+                    &now,
+                    &receipt.import_source,
+                    &receipt.import_id,
+                    &receipt.import_account
+                ]));
+
+            let id = db.conn.last_insert_rowid() as i32;
+
+            let inserted = try!(db.get_receipt(id));
+            try!(db.record_history(HistoryAction::Created, None, Some(&inserted)));
+
+            Ok(id)
+        })
+    }
+
+    /// Update the details of a receipt in the database
+    ///
+    /// The receipt must have been checked with `validate`. It is only updated
+    /// if its version matches the one in the database, that is, if nobody
+    /// changed it since it was loaded. Returns the number of updated rows, 0
+    /// when the versions do not match.
+    pub fn update_receipt(&self, receipt: &Receipt) -> Result<c_int> {
+        self.transaction(|db| {
+            let old = match db.get_receipt(receipt.id) {
+                Ok(r) => r,
+                Err(Error::QueryReturnedNoRows) => return Ok(0),
+                Err(err) => return Err(err)
+            };
+
+            let now = Local::now().naive_local();
+
+            let updated = try!(db.conn.execute("
+                UPDATE receipts
+                SET description=$1,shop=$2,amount=$3,currency=$4,payment_type=$5,date_paid=$6,
+                    return_by=$7,warranty_until=$8,updated_at=$9,version=version+1
+                WHERE id=$10 AND version=$11 AND deleted_at IS NULL",
+                &[
+                    &receipt.description,
+                    &receipt.shop,
+                    &receipt.amount,
+                    &receipt.currency,
+                    &receipt.payment_type,
+                    &receipt.date_paid,
+                    &receipt.return_by,
+                    &receipt.warranty_until,
+                    &now,
+                    &receipt.id,
+                    &receipt.version
+                ]));
+
+            if updated > 0 {
+                let new = try!(db.get_receipt(receipt.id));
+                try!(db.record_history(HistoryAction::Updated, Some(&old), Some(&new)));
+            }
+
+            Ok(updated)
+        })
+    }
+
+    /// Move a receipt to the trash
+    ///
+    /// Receipts in the trash are kept until they are purged.
+    pub fn delete_receipt(&self, id: i32) -> Result<c_int> {
+        self.transaction(|db| {
+            let old = match db.get_receipt(id) {
+                Ok(r) => r,
+                Err(Error::QueryReturnedNoRows) => return Ok(0),
+                Err(err) => return Err(err)
+            };
+
+            let now = Local::now().naive_local();
+
+            let deleted = try!(db.conn.execute("
+                UPDATE receipts
+                SET deleted_at=$1,version=version+1
+                WHERE id=$2 AND deleted_at IS NULL",
+                &[&now, &id]));
+
+            if deleted > 0 {
+                try!(db.record_history(HistoryAction::Deleted, Some(&old), None));
+            }
+
+            Ok(deleted)
+        })
+    }
 
-    conn.execute("
-        UPDATE receipts
-        SET description=$1,shop=$2,amount=$3,currency=$4,payment_type=$5,date_paid=$6
-        WHERE id=$7",
-        &[
-            &receipt.description,
+    /// Take a receipt out of the trash
+    pub fn restore_receipt(&self, id: i32) -> Result<c_int> {
+        self.transaction(|db| {
+            let restored = try!(db.conn.execute("
+                UPDATE receipts
+                SET deleted_at=NULL,version=version+1
+                WHERE id=$1 AND deleted_at IS NOT NULL",
+                &[&id]));
+
+            if restored > 0 {
+                let new = try!(db.get_receipt(id));
+                try!(db.record_history(HistoryAction::Restored, None, Some(&new)));
+            }
+
+            Ok(restored)
+        })
+    }
+
+    /// Permanently delete a receipt in the trash
+    ///
+    /// Its history is kept.
+    pub fn purge_receipt(&self, id: i32) -> Result<c_int> {
+        self.transaction(|db| {
+            let old = match db.get_receipt(id) {
+                Ok(r) => r,
+                Err(Error::QueryReturnedNoRows) => return Ok(0),
+                Err(err) => return Err(err)
+            };
+
+            let purged = try!(db.conn.execute("
+                DELETE FROM receipts
+                WHERE id=$1 AND deleted_at IS NOT NULL",
+                &[&id]));
+
+            if purged > 0 {
+                try!(db.record_history(HistoryAction::Purged, Some(&old), None));
+            }
+
+            Ok(purged)
+        })
+    }
+
+    /// Permanently delete receipts that have been in the trash for more than
+    /// `days` days
+    ///
+    /// Returns the number of purged receipts.
+    pub fn purge_deleted(&self, days: i64) -> Result<c_int> {
+        let limit = Local::now().naive_local() - Duration::days(days);
+
+        let ids: Vec<i32> = {
+            let mut query = try!(self.conn.prepare("
+                SELECT id FROM receipts
+                WHERE deleted_at < $1"));
+
+            let rows = try!(query.query_map(&[&limit], |row| row.get(0)));
+
+            let mut ids = Vec::new();
+
+            for id in rows {
+                ids.push(try!(id));
+            }
+
+            ids
+        };
+
+        self.transaction(|db| {
+            let mut purged = 0;
+
+            for id in ids.iter() {
+                purged += try!(db.purge_receipt(*id));
+            }
+
+            Ok(purged)
+        })
+    }
+
+    /// Obtain the history of a receipt, newest revision first
+    pub fn get_history(&self, receipt_id: i32) -> Result<Vec<Revision>> {
+        let conn = &self.conn;
+
+        let mut query = try!(conn.prepare("
+            SELECT * FROM receipt_history
+            WHERE receipt_id = $1
+            ORDER BY id DESC"));
+
+        let rows = try!(query.query_map(&[&receipt_id], revision_from_row));
+
+        let mut revisions = Vec::new();
+
+        for revision in rows {
+            revisions.push(try!(revision));
+        }
+
+        Ok(revisions)
+    }
+
+    /// Restore a receipt to the values it had after a revision
+    ///
+    /// The restore is recorded as a new revision. Returns the number of
+    /// updated rows, 0 when the revision deleted the receipt, its values do
+    /// not pass `validate`, or the receipt is in the trash or no longer exists.
+    pub fn restore_revision(&self, revision_id: i32) -> Result<c_int> {
+        self.transaction(|db| {
+            let revision = try!(db.conn.query_row("
+                SELECT * FROM receipt_history
+                WHERE id = $1",
+                &[&revision_id], |row| revision_from_row(&row)));
+
+            let mut restored = match revision.new {
+                Some(ref r) if r.validate().is_ok() => r.clone(),
+                _ => return Ok(0)
+            };
+
+            match db.get_receipt(restored.id) {
+                Ok(current) => restored.version = current.version,
+                Err(Error::QueryReturnedNoRows) => return Ok(0),
+                Err(err) => return Err(err)
+            }
+
+            db.update_receipt(&restored)
+        })
+    }
+
+    /// Add a repair made by the database doctor to the history of a receipt
+    ///
+    /// `old` is `None` when the values before the repair could not be read.
+    pub fn record_repair(&self, old: Option<&Receipt>, new: &Receipt) -> Result<c_int> {
+        self.record_history(HistoryAction::Repaired, old, Some(new))
+    }
+
+    /// Store a change to a receipt in its history
+    fn record_history(&self, action: HistoryAction, old: Option<&Receipt>,
+                      new: Option<&Receipt>) -> Result<c_int> {
+
+        let receipt_id = old.or(new).map(|r| r.id).unwrap_or(-1);
+        let changed_at = Local::now().naive_local();
+
+        self.conn.execute("
+            INSERT INTO receipt_history (
+                receipt_id, action, changed_at, user,
+                old_description, old_shop, old_amount, old_currency, old_payment_type,
+                old_date_paid, old_return_by, old_warranty_until, old_version,
+                new_description, new_shop, new_amount, new_currency, new_payment_type,
+                new_date_paid, new_return_by, new_warranty_until, new_version)
+            VALUES ($1, $2, $3, $4,
+                    $5, $6, $7, $8, $9, $10, $11, $12, $13,
+                    $14, $15, $16, $17, $18, $19, $20, $21, $22)",
+            &[
+                &receipt_id,
+                &action.to_str(),
+                &changed_at,
+                &os_user(),
+                &old.map(|r| r.description.clone()),
+                &old.map(|r| r.shop.clone()),
+                &old.map(|r| r.amount),
+                &old.map(|r| r.currency.clone()),
+                &old.map(|r| r.payment_type.clone()),
+                &old.map(|r| r.date_paid),
+                &old.and_then(|r| r.return_by),
+                &old.and_then(|r| r.warranty_until),
+                &old.map(|r| r.version),
+                &new.map(|r| r.description.clone()),
+                &new.map(|r| r.shop.clone()),
+                &new.map(|r| r.amount),
+                &new.map(|r| r.currency.clone()),
+                &new.map(|r| r.payment_type.clone()),
+                &new.map(|r| r.date_paid),
+                &new.and_then(|r| r.return_by),
+                &new.and_then(|r| r.warranty_until),
+                &new.map(|r| r.version)
+            ])
+    }
+
+    /// Receipts that look like duplicates of a receipt
+    ///
+    /// Duplicates are receipts from the same shop, with the same cost and
+    /// currency, paid within `days` days of each other. Receipts have no
+    /// attachments, so there are no attachment hashes to compare. Receipts in
+    /// the trash and pairs dismissed with `dismiss_duplicates` are left out.
+    pub fn find_duplicates_of(&self, receipt: &Receipt, days: i64) -> Result<Vec<Receipt>> {
+        let conn = &self.conn;
+
+        let mut query = try!(conn.prepare("
+            SELECT * FROM receipts
+            WHERE deleted_at IS NULL AND id != $1
+                AND lower(trim(shop)) = lower(trim($2))
+                AND abs(amount - $3) < 0.005
+                AND currency = $4
+                AND abs(julianday(date_paid) - julianday($5)) <= $6
+                AND NOT EXISTS (
+                    SELECT 1 FROM dismissed_duplicates
+                    WHERE receipt_id = min($1, receipts.id) AND other_id = max($1, receipts.id))
+            ORDER BY id"));
+
+        let rows = try!(query.query_map(&[
+            &receipt.id,
             &receipt.shop,
             &receipt.amount,
             &receipt.currency,
-            &receipt.payment_type,
             &receipt.date_paid,
-            &receipt.id
-        ])
+            &days
+        ], receipt_from_row));
+
+        let mut receipts = Vec::new();
+
+        for receipt in rows {
+            receipts.push(try!(receipt));
+        }
+
+        Ok(receipts)
+    }
+
+    /// Receipt imported from a transaction of a statement
+    ///
+    /// `format` is the name of the statement format, `account` the account
+    /// of the statement, empty if unknown, and `import_id` the identifier of
+    /// the transaction. Receipts in the trash are included.
+    pub fn find_imported(&self, format: &str, account: &str, import_id: &str)
+                         -> Result<Option<Receipt>> {
+        let conn = &self.conn;
+
+        let mut query = try!(conn.prepare("
+            SELECT * FROM receipts
+            WHERE import_id = $1 AND IFNULL(import_account, '') = $2
+                AND substr(import_source, 1, length($3) + 1) = $3 || ':'
+            LIMIT 1"));
+
+        let mut rows = try!(query.query_map(&[&import_id, &account, &format], receipt_from_row));
+
+        match rows.next() {
+            Some(receipt) => receipt.map(Some),
+            None => Ok(None)
+        }
+    }
+
+    /// Groups of receipts that look like duplicates of each other
+    ///
+    /// Uses the same criteria as `find_duplicates_of`. Receipts in a group
+    /// are ordered by ID, and groups by their first receipt.
+    pub fn find_duplicate_groups(&self, days: i64) -> Result<Vec<Vec<Receipt>>> {
+        let conn = &self.conn;
+
+        let mut query = try!(conn.prepare("
+            SELECT a.id, b.id FROM receipts a
+            JOIN receipts b ON a.id < b.id
+                AND lower(trim(a.shop)) = lower(trim(b.shop))
+                AND abs(a.amount - b.amount) < 0.005
+                AND a.currency = b.currency
+                AND abs(julianday(a.date_paid) - julianday(b.date_paid)) <= $1
+            WHERE a.deleted_at IS NULL AND b.deleted_at IS NULL
+                AND NOT EXISTS (
+                    SELECT 1 FROM dismissed_duplicates
+                    WHERE receipt_id = a.id AND other_id = b.id)"));
+
+        let rows = try!(query.query_map(&[&days], |row| (row.get(0), row.get(1))));
+
+        // Receipts are grouped with the lowest ID they are connected to
+        let mut group_of: HashMap<i32, i32> = HashMap::new();
+
+        for pair in rows {
+            let (a, b): (i32, i32) = try!(pair);
+
+            let group_a = *group_of.get(&a).unwrap_or(&a);
+            let group_b = *group_of.get(&b).unwrap_or(&b);
+            let (low, high) = if group_a < group_b { (group_a, group_b) } else { (group_b, group_a) };
+
+            for group in group_of.values_mut().filter(|g| **g == high) {
+                *group = low;
+            }
+
+            group_of.insert(a, low);
+            group_of.insert(b, low);
+        }
+
+        let mut ids: Vec<(i32, i32)> = group_of.into_iter().map(|(id, group)| (group, id)).collect();
+        ids.sort();
+
+        let mut groups: Vec<Vec<Receipt>> = Vec::new();
+        let mut last_group = None;
+
+        for (group, id) in ids {
+            let receipt = try!(self.get_receipt(id));
+
+            if last_group == Some(group) {
+                groups.last_mut().unwrap().push(receipt);
+            } else {
+                groups.push(vec![receipt]);
+                last_group = Some(group);
+            }
+        }
+
+        Ok(groups)
+    }
+
+    /// Stop reporting receipts as duplicates of each other
+    pub fn dismiss_duplicates(&self, ids: &[i32]) -> Result<()> {
+        self.transaction(|db| {
+            for (index, a) in ids.iter().enumerate() {
+                for b in ids[index + 1..].iter() {
+                    let (low, high) = if a < b { (a, b) } else { (b, a) };
+
+                    try!(db.conn.execute("
+                        INSERT OR IGNORE INTO dismissed_duplicates (receipt_id, other_id)
+                        VALUES ($1, $2)",
+                        &[low, high]));
+                }
+            }
+
+            Ok(())
+        })
+    }
+
+    /// Merge duplicates into a receipt
+    ///
+    /// The description and the return and warranty dates of `keep` are taken
+    /// from the first of `others` that has them when missing, and `others`
+    /// are moved to the trash. Nothing is merged if `keep` does not pass
+    /// `validate`. Returns the number of receipts moved.
+    pub fn merge_receipts(&self, keep: i32, others: &[i32]) -> Result<c_int> {
+        self.transaction(|db| {
+            let original = try!(db.get_receipt(keep));
+            let mut merged = original.clone();
+            let mut moved = 0;
+
+            let others: Vec<i32> = others.iter().cloned().filter(|&id| id != keep).collect();
+
+            for &id in others.iter() {
+                let other = try!(db.get_receipt(id));
+
+                if merged.description.trim().is_empty() {
+                    merged.description = other.description.clone();
+                }
+
+                if merged.return_by.is_none() {
+                    merged.return_by = other.return_by;
+                }
+
+                if merged.warranty_until.is_none() {
+                    merged.warranty_until = other.warranty_until;
+                }
+            }
+
+            if merged.validate().is_err() {
+                return Ok(0);
+            }
+
+            for &id in others.iter() {
+                moved += try!(db.delete_receipt(id));
+            }
+
+            if merged != original {
+                try!(db.update_receipt(&merged));
+            }
+
+            Ok(moved)
+        })
+    }
+
+    /// Store a line of a statement
+    pub fn insert_transaction(&self, transaction: &BankTransaction) -> Result<i32> {
+        let now = Local::now().naive_local();
+
+        try!(self.conn.execute("
+            INSERT INTO transactions (format, source, account, bank_id, date_posted, amount,
+                                      currency, payee, memo, occurrence, receipt_id, ignored,
+                                      imported_at)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13)",
+            &[
+                &transaction.format,
+                &transaction.source,
+                &transaction.account,
+                &transaction.bank_id,
+                &transaction.date_posted,
+                &transaction.amount,
+                &transaction.currency,
+                &transaction.payee,
+                &transaction.memo,
+                &transaction.occurrence,
+                &transaction.receipt_id,
+                &transaction.ignored,
+                &now
+            ]));
+
+        Ok(self.conn.last_insert_rowid() as i32)
+    }
+
+    /// ID of a stored transaction that is the same as `transaction`
+    ///
+    /// Transactions are the same if they have the same identifier in
+    /// statements of the same format and account, or all the same details
+    /// when the bank gave them no identifier. Identical lines of a statement
+    /// differ in their occurrence.
+    pub fn find_transaction(&self, transaction: &BankTransaction) -> Result<Option<i32>> {
+        let conn = &self.conn;
+
+        let result = match transaction.bank_id {
+            Some(ref bank_id) => conn.query_row("
+                SELECT id FROM transactions
+                WHERE format = $1 AND account = $2 AND bank_id = $3",
+                &[&transaction.format, &transaction.account, bank_id], |row| row.get(0)),
+
+            None => conn.query_row("
+                SELECT id FROM transactions
+                WHERE format = $1 AND account = $2 AND bank_id IS NULL AND date_posted = $3
+                    AND abs(amount - $4) < 0.005 AND currency = $5 AND payee = $6 AND memo = $7
+                    AND occurrence = $8",
+                &[
+                    &transaction.format,
+                    &transaction.account,
+                    &transaction.date_posted,
+                    &transaction.amount,
+                    &transaction.currency,
+                    &transaction.payee,
+                    &transaction.memo,
+                    &transaction.occurrence
+                ], |row| row.get(0))
+        };
+
+        match result {
+            Ok(id) => Ok(Some(id)),
+            Err(Error::QueryReturnedNoRows) => Ok(None),
+            Err(err) => Err(err)
+        }
+    }
+
+    /// Payments not matched with a receipt, oldest first
+    ///
+    /// Payments matched with receipts that were deleted afterwards are
+    /// included, ignored ones are not.
+    pub fn get_unmatched_transactions(&self) -> Result<Vec<BankTransaction>> {
+        let conn = &self.conn;
+
+        let mut query = try!(conn.prepare("
+            SELECT * FROM transactions
+            WHERE amount < 0 AND ignored = 0
+                AND (receipt_id IS NULL OR NOT EXISTS (
+                    SELECT 1 FROM receipts
+                    WHERE id = transactions.receipt_id AND deleted_at IS NULL))
+            ORDER BY date_posted, id"));
+
+        let rows = try!(query.query_map(&[], transaction_from_row));
+
+        let mut transactions = Vec::new();
+
+        for transaction in rows {
+            transactions.push(try!(transaction));
+        }
+
+        Ok(transactions)
+    }
+
+    /// Receipts paid with one of `payment_types` not matched with a
+    /// transaction, oldest first
+    ///
+    /// Only receipts paid within the dates covered by the imported
+    /// statements are included.
+    pub fn get_unmatched_receipts(&self, payment_types: &[&str]) -> Result<Vec<Receipt>> {
+        let conn = &self.conn;
+
+        let placeholders: Vec<String> = (1..payment_types.len() + 1).map(|i| format!("${}", i)).collect();
+        let params: Vec<&ToSql> = payment_types.iter().map(|t| t as &ToSql).collect();
+
+        let mut query = try!(conn.prepare(format!("
+            SELECT * FROM receipts
+            WHERE deleted_at IS NULL AND payment_type IN ({})
+                AND date_paid BETWEEN (SELECT min(date_posted) FROM transactions)
+                                  AND (SELECT max(date_posted) FROM transactions)
+                AND NOT EXISTS (
+                    SELECT 1 FROM transactions
+                    WHERE receipt_id = receipts.id)
+            ORDER BY date_paid, id", placeholders.join(", ")).as_str()));
+
+        let rows = try!(query.query_map(&params, receipt_from_row));
+
+        let mut receipts = Vec::new();
+
+        for receipt in rows {
+            receipts.push(try!(receipt));
+        }
+
+        Ok(receipts)
+    }
+
+    /// Match a payment with a receipt, or undo the match with `None`
+    pub fn match_transaction(&self, transaction_id: i32, receipt_id: Option<i32>) -> Result<c_int> {
+        self.conn.execute("
+            UPDATE transactions
+            SET receipt_id = $1
+            WHERE id = $2",
+            &[&receipt_id, &transaction_id])
+    }
+
+    /// Mark a payment as not needing a receipt
+    pub fn ignore_transaction(&self, transaction_id: i32) -> Result<c_int> {
+        self.conn.execute("
+            UPDATE transactions
+            SET ignored = 1
+            WHERE id = $1",
+            &[&transaction_id])
+    }
+
+    /// Obtain all saved searches ordered by name
+    pub fn get_saved_searches(&self) -> Result<Vec<SavedSearch>> {
+        let conn = &self.conn;
+
+        let mut query = try!(conn.prepare("SELECT * FROM saved_searches ORDER BY name"));
+
+        let rows = try!(query.query_map(&[], |row| {
+            SavedSearch {
+                id: row.get("id"),
+                name: row.get("name"),
+                filter: Filter {
+                    text: row.get("text"),
+                    shop: row.get("shop"),
+                    cost_from: row.get("cost_from"),
+                    cost_to: row.get("cost_to"),
+                    date_from: row.get("date_from"),
+                    date_to: row.get("date_to"),
+                    payment_type: row.get("payment_type"),
+                    currency: row.get("currency"),
+                    expiring_days: row.get("expiring_days"),
+                    entered_days: row.get("entered_days"),
+                    // Searches are never saved while viewing the trash
+                    deleted: false
+                }
+            }
+        }));
+
+        let mut searches = Vec::new();
+
+        for search in rows {
+            searches.push(try!(search));
+        }
+
+        Ok(searches)
+    }
+
+    /// Save a filter under a name
+    ///
+    /// An existing search with the same name is replaced.
+    pub fn save_search(&self, name: &str, filter: &Filter) -> Result<c_int> {
+        let conn = &self.conn;
+
+        conn.execute("
+            INSERT OR REPLACE INTO saved_searches (name, text, shop, cost_from, cost_to,
+                date_from, date_to, payment_type, currency, expiring_days, entered_days)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11)",
+            &[
+                &name,
+                &filter.text,
+                &filter.shop,
+                &filter.cost_from,
+                &filter.cost_to,
+                &filter.date_from,
+                &filter.date_to,
+                &filter.payment_type,
+                &filter.currency,
+                &filter.expiring_days,
+                &filter.entered_days
+            ])
+    }
+
+    /// Delete a saved search
+    pub fn delete_saved_search(&self, id: i32) -> Result<c_int> {
+        let conn = &self.conn;
+
+        conn.execute("
+            DELETE FROM saved_searches
+            WHERE id=$1",
+            &[&id])
+    }
+}
+
+/// Values derived from a filter that are bound as query parameters
+struct FilterValues {
+    fts_query: String,
+    shop_pattern: String,
+    today: NaiveDate,
+    expiry_limit: NaiveDate,
+    entered_since: NaiveDateTime
+}
+
+impl FilterValues {
+    fn new(filter: &Filter) -> FilterValues {
+        let today = Local::today().naive_local();
+
+        FilterValues {
+            fts_query: build_fts_query(filter.text.as_str()),
+            shop_pattern: format!("%{}%", filter.shop),
+            today: today,
+            expiry_limit: today + Duration::days(filter.expiring_days),
+            entered_since: Local::now().naive_local() - Duration::days(filter.entered_days)
+        }
+    }
+}
+
+/// Build the WHERE clause for a filter and its parameters
+///
+/// Unset fields of the filter are ignored.
+fn filter_conditions<'a>(filter: &'a Filter, values: &'a FilterValues)
+                         -> (String, Vec<&'a ToSql>) {
+    let mut conditions: Vec<&str> = Vec::new();
+    let mut params: Vec<&ToSql> = Vec::new();
+
+    if filter.deleted {
+        conditions.push("receipts.deleted_at IS NOT NULL");
+    } else {
+        conditions.push("receipts.deleted_at IS NULL");
+    }
+
+    if !values.fts_query.is_empty() {
+        conditions.push("receipts_fts MATCH ?");
+        params.push(&values.fts_query);
+    }
+
+    if !filter.shop.is_empty() {
+        conditions.push("receipts.shop LIKE ?");
+        params.push(&values.shop_pattern);
+    }
+
+    if filter.cost_from > 0.0 {
+        conditions.push("receipts.amount >= ?");
+        params.push(&filter.cost_from);
+    }
+
+    if filter.cost_to > 0.0 {
+        conditions.push("receipts.amount <= ?");
+        params.push(&filter.cost_to);
+    }
+
+    if let Some(ref date_from) = filter.date_from {
+        conditions.push("receipts.date_paid >= ?");
+        params.push(date_from);
+    }
+
+    if let Some(ref date_to) = filter.date_to {
+        conditions.push("receipts.date_paid <= ?");
+        params.push(date_to);
+    }
+
+    if !filter.payment_type.is_empty() {
+        conditions.push("receipts.payment_type = ?");
+        params.push(&filter.payment_type);
+    }
+
+    if !filter.currency.is_empty() {
+        conditions.push("receipts.currency = ?");
+        params.push(&filter.currency);
+    }
+
+    if filter.expiring_days > 0 {
+        conditions.push("(receipts.return_by BETWEEN ? AND ?
+            OR receipts.warranty_until BETWEEN ? AND ?)");
+        params.push(&values.today);
+        params.push(&values.expiry_limit);
+        params.push(&values.today);
+        params.push(&values.expiry_limit);
+    }
+
+    if filter.entered_days > 0 {
+        conditions.push("receipts.created_at >= ?");
+        params.push(&values.entered_since);
+    }
+
+    (conditions.join(" AND "), params)
+}
+
+/// Convert user input into an FTS5 query
+///
+/// Quoted text is kept as a phrase, while any other word becomes a quoted
+/// prefix query so that FTS5 operators in the input are not interpreted.
+fn build_fts_query(text: &str) -> String {
+    let mut terms = Vec::new();
+
+    for (index, part) in text.split('"').enumerate() {
+        if index % 2 == 1 {
+            // Inside quotes
+            let phrase = part.trim();
+
+            if !phrase.is_empty() {
+                terms.push(format!("\"{}\"", phrase));
+            }
+
+            continue;
+        }
+
+        for word in part.split_whitespace() {
+            let word = word.trim_right_matches('*');
+
+            if !word.is_empty() {
+                terms.push(format!("\"{}\"*", word));
+            }
+        }
+    }
+
+    terms.join(" ")
 }
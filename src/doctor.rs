@@ -0,0 +1,445 @@
+// MIT License
+//
+// Copyright (c) 2016 Rafael Medina García <rafamedgar@gmail.com>
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+/// Database integrity checks
+///
+/// Besides the integrity check of SQLite, the schema is compared with the one
+/// created by the migrations and receipts are checked for values the
+/// application cannot handle. Some of the problems found can be fixed.
+
+use chrono::Local;
+use gettextrs::gettext;
+use rusqlite::types::ToSql;
+
+use db::{Database, Field};
+use i18n::tr_format;
+use migrations;
+use migrations::LATEST_VERSION;
+
+
+/// Tables and columns created by the migrations up to `LATEST_VERSION`
+const SCHEMA: [(&'static str, &'static [&'static str]); 7] = [
+    ("__revision", &["version"]),
+    ("receipts", &["id", "description", "shop", "amount", "currency", "payment_type",
+                   "date_paid", "return_by", "warranty_until", "version", "deleted_at",
+                   "created_at", "updated_at", "import_source", "import_id",
+                   "import_account"]),
+    ("receipts_fts", &["shop", "description"]),
+    ("saved_searches", &["id", "name", "text", "shop", "cost_from", "cost_to", "date_from",
+                         "date_to", "payment_type", "currency", "expiring_days",
+                         "entered_days"]),
+    ("receipt_history", &["id", "receipt_id", "action", "changed_at", "user",
+                          "old_description", "old_shop", "old_amount", "old_currency",
+                          "old_payment_type", "old_date_paid", "old_return_by",
+                          "old_warranty_until", "old_version", "new_description", "new_shop",
+                          "new_amount", "new_currency", "new_payment_type", "new_date_paid",
+                          "new_return_by", "new_warranty_until", "new_version"]),
+    ("dismissed_duplicates", &["receipt_id", "other_id"]),
+    ("transactions", &["id", "format", "source", "account", "bank_id", "date_posted", "amount",
+                       "currency", "payee", "memo", "occurrence", "receipt_id", "ignored",
+                       "imported_at"])
+];
+
+/// Date columns of receipts and whether they are required
+const DATE_COLUMNS: [(&'static str, bool); 3] = [
+    ("date_paid", true),
+    ("return_by", false),
+    ("warranty_until", false)
+];
+
+/// Problem found in a database
+///
+/// Receipts are identified by their ID.
+#[derive(Clone, PartialEq)]
+pub enum Problem {
+    /// Damage reported by the integrity check of SQLite
+    Corruption(String),
+    /// The revision table does not hold a single version
+    Revision,
+    /// The database is not at the version of the application
+    Version(i32),
+    /// A table created by the migrations is missing
+    MissingTable(String),
+    /// A column is missing from a table, given as table and column
+    MissingColumn(String, String),
+    /// The full-text index does not match the receipts
+    SearchIndex,
+    /// Receipt without a shop
+    Shop(i32),
+    /// Currency not in `CURRENCIES`
+    Currency(i32, String),
+    /// Payment type not in `PAYMENTS`
+    PaymentType(i32, String),
+    /// Negative amount or one that is not a number
+    Amount(i32, String),
+    /// Date that cannot be read, given with its column
+    Date(i32, &'static str, String)
+}
+
+impl Problem {
+    /// Description shown to the user
+    pub fn describe(&self) -> String {
+        match *self {
+            Problem::Corruption(ref message) => {
+                tr_format("The database is damaged: {}", &[message.as_str()])
+            },
+
+            Problem::Revision => gettext("The version of the database is missing"),
+
+            Problem::Version(version) if version > LATEST_VERSION => {
+                gettext("The database was created by a newer version of Receipt Keeper")
+            },
+
+            Problem::Version(version) => {
+                tr_format("The database is at version {} and has to be updated",
+                          &[version.to_string().as_str()])
+            },
+
+            Problem::MissingTable(ref table) => {
+                tr_format("Table {} is missing", &[table.as_str()])
+            },
+
+            Problem::MissingColumn(ref table, ref column) => {
+                tr_format("Column {} is missing from table {}", &[column.as_str(), table.as_str()])
+            },
+
+            Problem::SearchIndex => gettext("The search index is out of date"),
+
+            Problem::Shop(id) => {
+                tr_format("Receipt {} has no shop", &[id.to_string().as_str()])
+            },
+
+            Problem::Currency(id, ref currency) => {
+                tr_format("Receipt {} has an unknown currency: {}",
+                          &[id.to_string().as_str(), currency.as_str()])
+            },
+
+            Problem::PaymentType(id, ref payment_type) => {
+                tr_format("Receipt {} has an unknown payment type: {}",
+                          &[id.to_string().as_str(), payment_type.as_str()])
+            },
+
+            Problem::Amount(id, ref amount) => {
+                tr_format("Receipt {} has an invalid cost: {}",
+                          &[id.to_string().as_str(), amount.as_str()])
+            },
+
+            Problem::Date(id, column, ref date) => {
+                let field = match column {
+                    "return_by" => gettext("Return by"),
+                    "warranty_until" => gettext("Warranty until"),
+                    _ => gettext("Date")
+                };
+
+                tr_format("Receipt {} has an invalid date in {}: {}",
+                          &[id.to_string().as_str(), field.as_str(), date.as_str()])
+            }
+        }
+    }
+
+    /// Receipt with the problem, if it is about a single receipt
+    fn receipt_id(&self) -> Option<i32> {
+        match *self {
+            Problem::Shop(id) | Problem::Currency(id, _) | Problem::PaymentType(id, _) |
+            Problem::Amount(id, _) | Problem::Date(id, _, _) => Some(id),
+            _ => None
+        }
+    }
+
+    /// Whether the problem keeps the receipt from being read
+    fn is_unreadable(&self) -> bool {
+        match *self {
+            Problem::Amount(_, ref amount) => amount.parse::<f64>().is_err(),
+            Problem::Date(_, _, _) => true,
+            _ => false
+        }
+    }
+
+    /// Whether `repair` can fix the problem
+    ///
+    /// Damaged databases and schemas should be restored from a backup, and
+    /// receipts without a shop edited.
+    pub fn is_fixable(&self) -> bool {
+        match *self {
+            Problem::Corruption(_) | Problem::Revision => false,
+            Problem::MissingTable(_) | Problem::MissingColumn(_, _) => false,
+            Problem::Shop(_) => false,
+            Problem::Version(version) => version < LATEST_VERSION,
+            _ => true
+        }
+    }
+}
+
+/// Look for problems in a database
+///
+/// Receipts are only checked when the schema is the expected one.
+pub fn check(db: &Database) -> Result<Vec<Problem>, String> {
+    let conn = db.connection();
+    let mut problems = Vec::new();
+
+    {
+        let mut stmt = try!(conn.prepare("PRAGMA integrity_check").map_err(|e| e.to_string()));
+        let rows = try!(stmt.query_map(&[], |row| -> String { row.get(0) })
+                        .map_err(|e| e.to_string()));
+
+        for message in rows {
+            let message = try!(message.map_err(|e| e.to_string()));
+
+            if message != "ok" {
+                problems.push(Problem::Corruption(message));
+            }
+        }
+    }
+
+    let versions: Vec<i32> = {
+        let mut stmt = try!(conn.prepare("SELECT version FROM __revision")
+                            .map_err(|e| e.to_string()));
+        let rows = try!(stmt.query_map(&[], |row| row.get(0)).map_err(|e| e.to_string()));
+
+        rows.filter_map(|v| v.ok()).collect()
+    };
+
+    match versions.len() {
+        1 if versions[0] == LATEST_VERSION => {},
+        1 => problems.push(Problem::Version(versions[0])),
+        _ => problems.push(Problem::Revision)
+    }
+
+    if !problems.is_empty() {
+        return Ok(problems);
+    }
+
+    for &(table, columns) in SCHEMA.iter() {
+        let found = try!(table_columns(db, table));
+
+        if found.is_empty() {
+            problems.push(Problem::MissingTable(table.to_string()));
+            continue;
+        }
+
+        for column in columns.iter().filter(|c| !found.iter().any(|f| f == *c)) {
+            problems.push(Problem::MissingColumn(table.to_string(), column.to_string()));
+        }
+    }
+
+    if !problems.is_empty() {
+        return Ok(problems);
+    }
+
+    if conn.execute_batch("INSERT INTO receipts_fts (receipts_fts) VALUES ('integrity-check');")
+        .is_err() {
+        problems.push(Problem::SearchIndex);
+    }
+
+    // Values that cannot be read into a receipt
+    for (id, value) in try!(query_values(db, "
+        SELECT id, IFNULL(CAST(amount AS TEXT), '') FROM receipts
+        WHERE typeof(amount) NOT IN ('integer', 'real')")) {
+        problems.push(Problem::Amount(id, value));
+    }
+
+    for &(column, required) in DATE_COLUMNS.iter() {
+        // Dates are stored as YYYY-MM-DD, adding days makes SQLite check
+        // the day of the month
+        let mut condition = format!("IFNULL(date({0}, '+0 days'), '') IS NOT {0}", column);
+
+        if !required {
+            condition = format!("{} IS NOT NULL AND {}", column, condition);
+        }
+
+        let sql = format!("SELECT id, IFNULL(CAST({} AS TEXT), '') FROM receipts WHERE {}",
+                          column, condition);
+
+        for (id, value) in try!(query_values(db, sql.as_str())) {
+            problems.push(Problem::Date(id, column, value));
+        }
+    }
+
+    // The other receipts are checked like the ones entered by the user
+    let unreadable: Vec<i32> = problems.iter()
+        .filter(|p| p.is_unreadable())
+        .filter_map(|p| p.receipt_id())
+        .collect();
+
+    for (id, _) in try!(query_values(db, "SELECT id, '' FROM receipts")) {
+        if unreadable.contains(&id) {
+            continue;
+        }
+
+        let receipt = try!(db.get_receipt(id).map_err(|e| e.to_string()));
+
+        for error in receipt.validate().err().unwrap_or(Vec::new()) {
+            problems.push(match error.field {
+                Field::Shop => Problem::Shop(id),
+                Field::Currency => Problem::Currency(id, receipt.currency.clone()),
+                Field::PaymentType => Problem::PaymentType(id, receipt.payment_type.clone()),
+                _ => Problem::Amount(id, receipt.amount.to_string())
+            });
+        }
+    }
+
+    Ok(problems)
+}
+
+/// Fix the problems that can be fixed
+///
+/// Invalid currencies and payment types are replaced with the given ones,
+/// negative costs are made positive and other invalid costs set to zero.
+/// Receipts without a valid date take the day they were entered, and invalid
+/// return and warranty dates are removed. Each repaired receipt gets an
+/// entry in its history. Returns the number of problems fixed.
+pub fn repair(db: &Database, problems: &[Problem], currency: &str, payment_type: &str)
+              -> Result<usize, String> {
+
+    let mut fixable: Vec<Problem> = problems.iter().filter(|p| p.is_fixable()).cloned().collect();
+    let mut fixed = 0;
+
+    if fixable.iter().any(|p| match *p { Problem::Version(_) => true, _ => false }) {
+        if migrations::migrate(db) < 0 {
+            return Err(gettext("The database could not be updated"));
+        }
+        fixed += 1;
+
+        // Receipts are only checked once the schema is up to date
+        fixable = try!(check(db)).into_iter().filter(|p| p.is_fixable()).collect();
+    }
+
+    let now = Local::now().naive_local();
+
+    // Receipts with problems, in the order they were found
+    let mut ids: Vec<i32> = Vec::new();
+
+    for id in fixable.iter().filter_map(|p| p.receipt_id()) {
+        if !ids.contains(&id) {
+            ids.push(id);
+        }
+    }
+
+    let result = db.transaction(|db| {
+        let conn = db.connection();
+
+        if fixable.contains(&Problem::SearchIndex) {
+            try!(conn.execute_batch(
+                "INSERT INTO receipts_fts (receipts_fts) VALUES ('rebuild');"));
+        }
+
+        for &id in ids.iter() {
+            let readable = !fixable.iter()
+                .any(|p| p.receipt_id() == Some(id) && p.is_unreadable());
+
+            let old = if readable { Some(try!(db.get_receipt(id))) } else { None };
+
+            for problem in fixable.iter().filter(|p| p.receipt_id() == Some(id)) {
+                match *problem {
+                    Problem::Currency(_, _) => {
+                        try!(update(db, id, "currency = $1", &[&currency], &now));
+                    },
+
+                    Problem::PaymentType(_, _) => {
+                        try!(update(db, id, "payment_type = $1", &[&payment_type], &now));
+                    },
+
+                    Problem::Amount(_, _) => {
+                        try!(update(db, id, "amount = CASE WHEN typeof(amount) IN ('integer', 'real') \
+                                             AND abs(amount) <= 1.7976931348623157e308 \
+                                             THEN abs(amount) ELSE 0.0 END", &[], &now));
+                    },
+
+                    Problem::Date(_, "date_paid", _) => {
+                        try!(update(db, id, "date_paid = IFNULL(date(created_at), date('now', 'localtime'))",
+                                    &[], &now));
+                    },
+
+                    Problem::Date(_, column, _) => {
+                        try!(update(db, id, format!("{} = NULL", column).as_str(), &[], &now));
+                    },
+
+                    _ => {}
+                }
+            }
+
+            let new = try!(db.get_receipt(id));
+            try!(db.record_repair(old.as_ref(), &new));
+        }
+
+        Ok(fixed + fixable.len())
+    });
+
+    let fixed = try!(result.map_err(|e| e.to_string()));
+
+    // Receipts that could not be read are only fully checked once repaired
+    let repaired: Vec<i32> = fixable.iter()
+        .filter(|p| p.is_unreadable())
+        .filter_map(|p| p.receipt_id())
+        .collect();
+
+    if repaired.is_empty() {
+        return Ok(fixed);
+    }
+
+    let remaining: Vec<Problem> = try!(check(db)).into_iter()
+        .filter(|p| p.is_fixable() && p.receipt_id().map_or(false, |id| repaired.contains(&id)))
+        .collect();
+
+    if remaining.is_empty() {
+        Ok(fixed)
+    } else {
+        repair(db, &remaining, currency, payment_type).map(|more| fixed + more)
+    }
+}
+
+/// Change a receipt with the assignments in `set`
+///
+/// Parameters in `set` are numbered from `$1`. The receipt gets a new
+/// version so that windows editing it notice the change.
+fn update(db: &Database, id: i32, set: &str, params: &[&ToSql], now: &ToSql)
+          -> ::rusqlite::Result<i32> {
+
+    let n = params.len();
+    let sql = format!("UPDATE receipts SET {}, updated_at = ${}, version = version + 1
+                       WHERE id = ${}", set, n + 1, n + 2);
+
+    let mut values: Vec<&ToSql> = params.to_vec();
+    values.push(now);
+    values.push(&id);
+
+    db.connection().execute(sql.as_str(), &values)
+}
+
+/// Names of the columns of a table, empty if it does not exist
+fn table_columns(db: &Database, table: &str) -> Result<Vec<String>, String> {
+    let mut stmt = try!(db.connection().prepare(format!("PRAGMA table_info({})", table).as_str())
+                        .map_err(|e| e.to_string()));
+    let rows = try!(stmt.query_map(&[], |row| -> String { row.get(1) })
+                    .map_err(|e| e.to_string()));
+
+    rows.map(|r| r.map_err(|e| e.to_string())).collect()
+}
+
+/// IDs of receipts and a value, from a query selecting both
+fn query_values(db: &Database, sql: &str) -> Result<Vec<(i32, String)>, String> {
+    let mut stmt = try!(db.connection().prepare(sql).map_err(|e| e.to_string()));
+    let rows = try!(stmt.query_map(&[], |row| (row.get(0), row.get(1)))
+                    .map_err(|e| e.to_string()));
+
+    rows.map(|r| r.map_err(|e| e.to_string())).collect()
+}
@@ -0,0 +1,125 @@
+// MIT License
+//
+// Copyright (c) 2016 Rafael Medina García <rafamedgar@gmail.com>
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+/// Encryption of databases at rest
+///
+/// Databases are encrypted with SQLCipher, which is linked in place of
+/// SQLite when building with the `sqlcipher` feature. Encrypted files do not start with the SQLite header, so they
+/// can be told apart before the passphrase is known.
+
+use std::fs;
+use std::fs::File;
+use std::io::Read;
+
+use gettextrs::gettext;
+
+use db::{quote, Database};
+use i18n::tr_format;
+
+
+/// First bytes of an unencrypted database
+const SQLITE_HEADER: &'static [u8; 16] = b"SQLite format 3\0";
+
+/// Whether the database at `db_path` is encrypted
+///
+/// Missing and empty files are not.
+pub fn is_encrypted(db_path: &str) -> bool {
+    let mut header = [0u8; 16];
+
+    match File::open(db_path).and_then(|mut file| file.read_exact(&mut header)) {
+        Ok(_) => &header != SQLITE_HEADER,
+        Err(_) => false
+    }
+}
+
+/// Whether this build links SQLCipher
+pub fn is_available() -> bool {
+    cfg!(feature = "sqlcipher")
+}
+
+/// Message shown when an encrypted database is opened without SQLCipher
+pub fn unavailable_message(db_path: &str) -> String {
+    tr_format("{} is encrypted, but this build of Receipt Keeper does not support encryption",
+              &[db_path])
+}
+
+/// Whether the SQLite library in use can encrypt databases
+pub fn is_supported(db: &Database) -> bool {
+    is_available() && db.connection()
+        .query_row("PRAGMA cipher_version", &[], |row| -> String { row.get(0) })
+        .is_ok()
+}
+
+/// Change the passphrase of the database at `db_path`
+///
+/// An empty passphrase stores the database unencrypted. Encrypting or
+/// decrypting writes a new file in place of the database, in which case
+/// `true` is returned and the database has to be opened again.
+pub fn change_passphrase(db: &Database, db_path: &str, passphrase: &str) -> Result<bool, String> {
+    if !is_supported(db) {
+        return Err(gettext("This version of SQLite does not support encryption"));
+    }
+
+    match db.key() {
+        Some(_) if !passphrase.is_empty() => {
+            try!(db.connection()
+                 .execute_batch(format!("PRAGMA rekey = {};", quote(passphrase)).as_str())
+                 .map_err(|e| e.to_string()));
+
+            db.set_key(Some(passphrase));
+
+            Ok(false)
+        },
+
+        None if passphrase.is_empty() => Ok(false),
+
+        _ => export(db, db_path, passphrase).map(|_| true)
+    }
+}
+
+/// Replace the database with a copy encrypted with another passphrase
+fn export(db: &Database, db_path: &str, passphrase: &str) -> Result<(), String> {
+    let conn = db.connection();
+    let partial = format!("{}.part", db_path);
+
+    let _ = fs::remove_file(&partial);
+
+    // The write-ahead log belongs to the file being replaced
+    try!(conn.execute_batch("PRAGMA journal_mode = DELETE;").map_err(|e| e.to_string()));
+
+    let attach = format!("ATTACH DATABASE {} AS export KEY {};",
+                         quote(partial.as_str()), quote(passphrase));
+
+    let result = conn.execute_batch(attach.as_str())
+        .and_then(|_| conn.query_row("SELECT sqlcipher_export('export')", &[], |_| ()))
+        .and_then(|_| conn.execute_batch("DETACH DATABASE export;"))
+        .map_err(|e| e.to_string())
+        .and_then(|_| fs::rename(&partial, db_path).map_err(|e| e.to_string()));
+
+    if result.is_err() {
+        let _ = conn.execute_batch("DETACH DATABASE export;");
+        let _ = conn.execute_batch("PRAGMA journal_mode = WAL;");
+        let _ = fs::remove_file(&partial);
+    }
+
+    result
+}
@@ -0,0 +1,118 @@
+// MIT License
+//
+// Copyright (c) 2016 Rafael Medina García <rafamedgar@gmail.com>
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+/// Dialog to resolve conflicting changes to a receipt
+
+use gettextrs::gettext;
+use gtk;
+use gtk::prelude::*;
+use gtk::{Align, ApplicationWindow, Dialog, Grid, Label, ResponseType};
+
+use dates::DateFormat;
+use db::Receipt;
+
+
+/// Choice made in the conflict dialog
+pub enum Resolution {
+    /// Overwrite the stored receipt with the changes in the window
+    KeepMine,
+    /// Discard the changes in the window and show the stored receipt
+    UseTheirs,
+    /// Keep editing
+    Cancel
+}
+
+const RESPONSE_MINE: i32 = 1;
+const RESPONSE_THEIRS: i32 = 2;
+
+/// Ask the user how to resolve a conflict between the receipt being edited
+/// and the one stored in the database
+///
+/// Both versions are shown side by side, with differing fields in bold.
+pub fn run(parent: &ApplicationWindow, mine: &Receipt, theirs: &Receipt,
+           date_format: DateFormat) -> Resolution {
+    let dialog = Dialog::new_with_buttons(
+        Some(gettext("Conflicting changes").as_str()),
+        Some(parent),
+        gtk::DIALOG_MODAL,
+        &[
+            (gettext("Cancel").as_str(), ResponseType::Cancel.into()),
+            (gettext("Use saved version").as_str(), RESPONSE_THEIRS),
+            (gettext("Keep my changes").as_str(), RESPONSE_MINE)
+        ]
+    );
+
+    let grid = Grid::new();
+    grid.set_border_width(10);
+    grid.set_row_spacing(5);
+    grid.set_column_spacing(15);
+
+    let lbl_message = Label::new(Some(
+        gettext("The receipt was changed elsewhere after you started editing it.").as_str()));
+    lbl_message.set_halign(Align::Start);
+    grid.attach(&lbl_message, 0, 0, 3, 1);
+
+    let headers = [String::new(), gettext("Your changes"), gettext("Saved version")];
+
+    for (column, header) in headers.iter().enumerate() {
+        let lbl_header = Label::new(None);
+        lbl_header.set_markup(format!("<b>{}</b>", header).as_str());
+        lbl_header.set_halign(Align::Start);
+        grid.attach(&lbl_header, column as i32, 1, 1, 1);
+    }
+
+    let rows = ::gui::receipt_fields(mine, date_format).into_iter()
+        .zip(::gui::receipt_fields(theirs, date_format).into_iter());
+
+    for (row, ((name, value_mine), (_, value_theirs))) in rows.enumerate() {
+        let differs = value_mine != value_theirs;
+
+        let lbl_name = Label::new(Some(name.as_str()));
+        lbl_name.set_halign(Align::Start);
+        grid.attach(&lbl_name, 0, row as i32 + 2, 1, 1);
+
+        for (column, value) in [value_mine, value_theirs].iter().enumerate() {
+            let mut markup = ::gui::highlight_markup(value.as_str());
+
+            if differs {
+                markup = format!("<b>{}</b>", markup);
+            }
+
+            let lbl_value = Label::new(None);
+            lbl_value.set_markup(markup.as_str());
+            lbl_value.set_halign(Align::Start);
+            grid.attach(&lbl_value, column as i32 + 1, row as i32 + 2, 1, 1);
+        }
+    }
+
+    dialog.get_content_area().add(&grid);
+    dialog.set_default_response(RESPONSE_MINE);
+    dialog.show_all();
+
+    let response = dialog.run();
+    dialog.destroy();
+
+    match response {
+        RESPONSE_MINE => Resolution::KeepMine,
+        RESPONSE_THEIRS => Resolution::UseTheirs,
+        _ => Resolution::Cancel
+    }
+}
@@ -0,0 +1,97 @@
+// MIT License
+//
+// Copyright (c) 2016 Rafael Medina García <rafamedgar@gmail.com>
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+/// Dialog listing the problems found in the database
+
+use gettextrs::gettext;
+use gtk;
+use gtk::prelude::*;
+use gtk::{Align, ApplicationWindow, Dialog, Grid, Label, ResponseType, ScrolledWindow};
+
+use doctor::Problem;
+
+
+/// Show the problems found in the database
+///
+/// Returns whether the user chose to fix them, which is only offered when
+/// some can be fixed.
+pub fn run(parent: &ApplicationWindow, problems: &[Problem]) -> bool {
+    let fixable = problems.iter().any(|p| p.is_fixable());
+
+    let dialog = Dialog::new_with_buttons(
+        Some(gettext("Database check").as_str()),
+        Some(parent),
+        gtk::DIALOG_MODAL,
+        &[(gettext("Close").as_str(), ResponseType::Close.into())]
+    );
+
+    if fixable {
+        dialog.add_button(gettext("Fix problems").as_str(), ResponseType::Accept.into());
+        dialog.set_default_response(ResponseType::Accept.into());
+    }
+
+    let grid = Grid::new();
+    grid.set_border_width(10);
+    grid.set_row_spacing(5);
+    grid.set_column_spacing(15);
+
+    let message = if fixable {
+        gettext("Problems were found in the database. It is backed up before fixing them.")
+    } else {
+        gettext("Problems were found in the database. Restore it from a backup to fix them.")
+    };
+
+    let lbl_message = Label::new(Some(message.as_str()));
+    lbl_message.set_halign(Align::Start);
+    grid.attach(&lbl_message, 0, 0, 2, 1);
+
+    for (row, problem) in problems.iter().enumerate() {
+        let lbl_problem = Label::new(Some(problem.describe().as_str()));
+        lbl_problem.set_halign(Align::Start);
+        lbl_problem.set_line_wrap(true);
+        grid.attach(&lbl_problem, 0, row as i32 + 1, 1, 1);
+
+        let status = if problem.is_fixable() {
+            gettext("Can be fixed")
+        } else {
+            gettext("Cannot be fixed")
+        };
+
+        let lbl_status = Label::new(None);
+        lbl_status.set_markup(format!("<i>{}</i>", status).as_str());
+        lbl_status.set_halign(Align::End);
+        grid.attach(&lbl_status, 1, row as i32 + 1, 1, 1);
+    }
+
+    let scrolled = ScrolledWindow::new(None, None);
+    scrolled.set_min_content_height(200);
+    scrolled.set_min_content_width(500);
+    scrolled.add(&grid);
+
+    dialog.get_content_area().pack_start(&scrolled, true, true, 0);
+    dialog.show_all();
+
+    let response = dialog.run();
+    dialog.destroy();
+
+    fixable && response == ResponseType::Accept.into()
+}
@@ -0,0 +1,227 @@
+// MIT License
+//
+// Copyright (c) 2016 Rafael Medina García <rafamedgar@gmail.com>
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+/// Window to review receipts that look like duplicates
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use gettextrs::gettext;
+use gtk::prelude::*;
+use gtk::{
+    Align,
+    Application,
+    ApplicationWindow,
+    Box,
+    Button,
+    Frame,
+    Grid,
+    Label,
+    MessageType,
+    Orientation,
+    RadioButton,
+    ScrolledWindow,
+    WindowPosition
+};
+
+use common;
+use common::{ReceiptEvent, State};
+use db::Receipt;
+use gui::lock;
+use gui::main_window;
+use i18n;
+use i18n::tr_format;
+
+
+/// Create the window listing groups of likely duplicates
+///
+/// Each group can be merged into one of its receipts, moving the others to
+/// the trash, or dismissed so it is not reported again.
+pub fn create_window(app: &Application, state: &Rc<RefCell<State>>) -> ApplicationWindow {
+    let window = ApplicationWindow::new(app);
+    window.set_title(gettext("Duplicates").as_str());
+    window.set_position(WindowPosition::CenterOnParent);
+    window.set_default_size(600, 400);
+
+    let groups_box = Box::new(Orientation::Vertical, 10);
+    groups_box.set_border_width(10);
+
+    let scrolled = ScrolledWindow::new(None, None);
+    scrolled.add(&groups_box);
+    window.add(&scrolled);
+
+    lock::track_activity(state, &window);
+    load_groups(state, &window, &groups_box);
+
+    window.show_all();
+    window
+}
+
+/// Search duplicates and show them in the window
+fn load_groups(state: &Rc<RefCell<State>>, window: &ApplicationWindow, groups_box: &Box) {
+    let state = state.clone();
+    let window = window.clone();
+    let groups_box = groups_box.clone();
+    let worker = state.borrow().worker().clone();
+
+    worker.run(
+        move |db| db.find_duplicate_groups(common::DUPLICATE_DAYS),
+        move |result| {
+            for child in groups_box.get_children() {
+                groups_box.remove(&child);
+            }
+
+            match result {
+                Ok(ref groups) if groups.is_empty() => {
+                    let lbl_empty = Label::new(Some(gettext("No duplicates found").as_str()));
+                    groups_box.pack_start(&lbl_empty, true, true, 0);
+                },
+
+                Ok(groups) => {
+                    for group in groups.iter() {
+                        let frame = create_group(&state, &window, &groups_box, group);
+                        groups_box.pack_start(&frame, false, false, 0);
+                    }
+                },
+
+                Err(_) => {
+                    let lbl_error = Label::new(Some(gettext("Error searching duplicates").as_str()));
+                    groups_box.pack_start(&lbl_error, true, true, 0);
+                }
+            }
+
+            groups_box.show_all();
+        });
+}
+
+/// Create the frame of a group of duplicates
+///
+/// The first receipt of the group is the one kept by default.
+fn create_group(state: &Rc<RefCell<State>>, window: &ApplicationWindow, groups_box: &Box,
+                group: &[Receipt]) -> Frame {
+    let date_format = state.borrow().config.date_format;
+
+    let frame = Frame::new(Some(group[0].shop.as_str()));
+
+    let grid = Grid::new();
+    grid.set_border_width(10);
+    grid.set_row_spacing(5);
+    grid.set_column_spacing(15);
+
+    let mut radios: Vec<RadioButton> = Vec::new();
+
+    for (row, receipt) in group.iter().enumerate() {
+        let label = tr_format("Keep #{}", &[receipt.id.to_string().as_str()]);
+        let radio = RadioButton::new_with_label_from_widget(radios.first(), label.as_str());
+        grid.attach(&radio, 0, row as i32, 1, 1);
+
+        let values = [
+            date_format.format(receipt.date_paid),
+            i18n::format_money(receipt.amount, receipt.currency.as_str()),
+            receipt.payment_type.clone(),
+            receipt.description.clone()
+        ];
+
+        for (column, value) in values.iter().enumerate() {
+            let lbl_value = Label::new(Some(value.as_str()));
+            lbl_value.set_halign(Align::Start);
+            lbl_value.set_line_wrap(true);
+            grid.attach(&lbl_value, column as i32 + 1, row as i32, 1, 1);
+        }
+
+        radios.push(radio);
+    }
+
+    let buttons_box = Box::new(Orientation::Horizontal, 5);
+    buttons_box.set_halign(Align::End);
+
+    let btn_dismiss = Button::new_with_label(gettext("Not duplicates").as_str());
+    let btn_merge = Button::new_with_label(gettext("Merge").as_str());
+    btn_merge.set_tooltip_text(Some(gettext("Keep the chosen receipt and move the others to the trash").as_str()));
+
+    buttons_box.pack_start(&btn_dismiss, false, false, 0);
+    buttons_box.pack_start(&btn_merge, false, false, 0);
+    grid.attach(&buttons_box, 0, group.len() as i32, 5, 1);
+
+    let ids: Vec<i32> = group.iter().map(|r| r.id).collect();
+
+    // Dismiss the group
+    {
+        let state = state.clone();
+        let window = window.clone();
+        let groups_box = groups_box.clone();
+        let ids = ids.clone();
+
+        btn_dismiss.connect_clicked(move |_| {
+            let state = state.clone();
+            let window = window.clone();
+            let groups_box = groups_box.clone();
+            let ids = ids.clone();
+            let worker = state.borrow().worker().clone();
+
+            worker.run(
+                move |db| db.dismiss_duplicates(&ids),
+                move |result| {
+                    if result.is_err() {
+                        main_window::show_message(&window, MessageType::Error, &gettext("Error dismissing the duplicates"));
+                    }
+
+                    load_groups(&state, &window, &groups_box);
+                });
+        });
+    }
+
+    // Merge the group into the chosen receipt
+    {
+        let state = state.clone();
+        let window = window.clone();
+        let groups_box = groups_box.clone();
+
+        btn_merge.connect_clicked(move |_| {
+            let keep = match radios.iter().position(|r| r.get_active()) {
+                Some(index) => ids[index],
+                None => return
+            };
+
+            let state = state.clone();
+            let window = window.clone();
+            let groups_box = groups_box.clone();
+            let ids = ids.clone();
+            let worker = state.borrow().worker().clone();
+
+            worker.run(
+                move |db| db.merge_receipts(keep, &ids),
+                move |result| {
+                    if result.is_err() {
+                        main_window::show_message(&window, MessageType::Error, &gettext("Error merging the receipts"));
+                    }
+
+                    // Notify other windows
+                    common::emit(&state, ReceiptEvent::External);
+                    load_groups(&state, &window, &groups_box);
+                });
+        });
+    }
+
+    frame.add(&grid);
+    frame
+}
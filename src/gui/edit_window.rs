@@ -22,15 +22,17 @@
 
 /// Edit/View dialog definition
 
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
 use std::os::raw::{c_int, c_double};
 use std::rc::Rc;
 
 use chrono::NaiveDate;
+use gettextrs::gettext;
 use glib;
 use gtk;
 use gtk::prelude::*;
 use gtk::{
+    Align,
     Application,
     ApplicationWindow,
     Builder,
@@ -52,8 +54,10 @@ use gtk::{
     HeaderBar,
     InfoBar,
     Label,
+    ListBox,
     ListStore,
     MessageDialog,
+    Orientation,
     Popover,
     ScrolledWindow,
     Separator,
@@ -62,47 +66,63 @@ use gtk::{
     ToggleButton,
     TreeIter,
     TreeView,
-    TreeViewColumn
+    TreeViewColumn,
+    Widget
 };
-use regex::Regex;
+use rusqlite;
 
-use common::{State, RE_DATE, CURRENCIES, PAYMENTS};
+use common;
+use common::{ReceiptEvent, State, CURRENCIES, PAYMENTS};
+use dates::DateFormat;
 use db;
-use db::Receipt;
-use gui::main_window;
+use db::{Field, FieldError, HistoryAction, Invalid, Receipt};
+use gui::conflict_dialog;
+use gui::conflict_dialog::Resolution;
+use gui::lock;
+use i18n;
+use i18n::tr_format;
+use worker::Worker;
 
 
 /// Creates a view/edit dialog
 pub fn create_window(app: &Application, state: &Rc<RefCell<State>>,
                      receipt_id: i32) -> ApplicationWindow {
 
-    let mut title = "";
+    let title;
     let mut is_modal = false;
-    let receipt: Receipt;
+
+    // Filled in once the receipt is loaded
+    let receipt = Rc::new(RefCell::new(Receipt::new()));
+
+    // Whether the fields were changed since the receipt was loaded or saved
+    let dirty = Rc::new(Cell::new(false));
+
+    let date_format = state.borrow().config.date_format;
 
     if receipt_id >= 0 {
-        title = "Edit receipt";
-        receipt = db::get_receipt(state.borrow().db_path.as_str(), receipt_id).unwrap();
+        title = gettext("Edit receipt");
 
     } else {
-        title = "New receipt";
+        title = gettext("New receipt");
         is_modal = true;
-        receipt = Receipt::new();
     }
 
     let window = ApplicationWindow::new(&app);
-    window.set_title(title);
+    window.set_title(title.as_str());
     // window.set_border_width(10);
     window.set_default_size(600, 400);
     window.set_modal(is_modal);
     window.set_position(WindowPosition::Center);
 
+    lock::track_activity(state, &window);
+
     let builder = Builder::new();
+    builder.set_translation_domain(Some(i18n::DOMAIN));
     builder.add_from_string(include_str!("edit_window.ui"));
 
     // Header bar
     let header_bar: HeaderBar = builder.get_object("header_bar").unwrap();
-    header_bar.set_title(Some(&title));
+    header_bar.set_title(Some(title.as_str()));
     window.set_titlebar(Some(&header_bar));
 
     // Container
@@ -118,30 +138,92 @@ pub fn create_window(app: &Application, state: &Rc<RefCell<State>>,
 
     // Load data
     if receipt_id >= 0 {
-        let entry_shop: Entry = builder.get_object("entry_shop").unwrap();
-        entry_shop.set_text(receipt.shop.as_str());
+        load_receipt(state.borrow().worker(), &builder, &receipt, &dirty, date_format, receipt_id);
+        load_history(state, &builder, receipt_id);
 
-        let entry_desc: TextView = builder.get_object("entry_desc").unwrap();
-        let desc_buffer = entry_desc.get_buffer().unwrap();
-        desc_buffer.set_text(receipt.description.as_str());
-
-        let spin_cost: SpinButton = builder.get_object("spin_cost").unwrap();
-        spin_cost.set_value(receipt.amount);
+    } else {
+        // New receipts have no history
+        let scroll_history: ScrolledWindow = builder.get_object("scroll_history").unwrap();
+        scroll_history.hide();
 
         let combo_type: ComboBox = builder.get_object("combo_type").unwrap();
-        let payment_type = receipt.payment_type.clone();
+        let payment_type = state.borrow().config.default_payment.clone();
         set_active_combo!(combo_type, PAYMENTS, payment_type);
 
         let combo_currency: ComboBox = builder.get_object("combo_currency").unwrap();
-        let currency = receipt.currency.clone();
+        let currency = state.borrow().config.base_currency.clone();
         set_active_combo!(combo_currency, CURRENCIES, currency);
-
-        let entry_date: Entry = builder.get_object("entry_date").unwrap();
-        entry_date.set_text(receipt.date_paid.format("%d/%m/%Y").to_string().as_str());
     }
 
+    // Follow changes made to the receipt elsewhere
+    let subscription = if receipt_id >= 0 {
+        let builder = builder.clone();
+        let receipt = receipt.clone();
+        let dirty = dirty.clone();
+        let worker = state.borrow().worker().clone();
+        let state_events = state.clone();
+
+        let subscription = state.borrow_mut().events.subscribe(move |event| {
+            let state = &state_events;
+
+            match event {
+                ReceiptEvent::Updated(id) if id == receipt_id => {
+                    check_change(&worker, &builder, &receipt, &dirty, date_format, receipt_id);
+                    load_history(state, &builder, receipt_id);
+                },
+
+                ReceiptEvent::Deleted(id) if id == receipt_id => {
+                    show_deleted(&builder);
+                    load_history(state, &builder, receipt_id);
+                },
+
+                ReceiptEvent::External => {
+                    check_change(&worker, &builder, &receipt, &dirty, date_format, receipt_id);
+                    load_history(state, &builder, receipt_id);
+                },
+
+                _ => {}
+            }
+        });
+
+        Some(subscription)
+
+    } else {
+        None
+    };
+
     // Events
 
+    // Track unsaved changes
+    {
+        let builder = builder.clone();
+        let entry_shop: Entry = builder.get_object("entry_shop").unwrap();
+        let entry_desc: TextView = builder.get_object("entry_desc").unwrap();
+        let spin_cost: SpinButton = builder.get_object("spin_cost").unwrap();
+        let combo_type: ComboBox = builder.get_object("combo_type").unwrap();
+        let combo_currency: ComboBox = builder.get_object("combo_currency").unwrap();
+        let entry_date: Entry = builder.get_object("entry_date").unwrap();
+        let entry_return: Entry = builder.get_object("entry_return").unwrap();
+        let entry_warranty: Entry = builder.get_object("entry_warranty").unwrap();
+
+        let d = dirty.clone();
+        entry_shop.connect_changed(move |_| d.set(true));
+        let d = dirty.clone();
+        entry_desc.get_buffer().unwrap().connect_changed(move |_| d.set(true));
+        let d = dirty.clone();
+        spin_cost.connect_value_changed(move |_| d.set(true));
+        let d = dirty.clone();
+        combo_type.connect_changed(move |_| d.set(true));
+        let d = dirty.clone();
+        combo_currency.connect_changed(move |_| d.set(true));
+        let d = dirty.clone();
+        entry_date.connect_changed(move |_| d.set(true));
+        let d = dirty.clone();
+        entry_return.connect_changed(move |_| d.set(true));
+        let d = dirty.clone();
+        entry_warranty.connect_changed(move |_| d.set(true));
+    }
+
     // Hide the information bar
     {
         let builder = builder.clone();
@@ -168,20 +250,63 @@ pub fn create_window(app: &Application, state: &Rc<RefCell<State>>,
         });
     }
 
+    // Toogle date chooser (return by)
+    {
+        let builder = builder.clone();
+        let btn_return: Button = builder.get_object("btn_return").unwrap();
+
+        let popover_date: Popover = builder.get_object("popover_date").unwrap();
+        let entry_return: Entry = builder.get_object("entry_return").unwrap();
+
+        btn_return.connect_clicked(move |_| {
+            popover_date.set_relative_to(Some(&entry_return));
+            popover_date.show_all();
+        });
+    }
+
+    // Toogle date chooser (warranty until)
+    {
+        let builder = builder.clone();
+        let btn_warranty: Button = builder.get_object("btn_warranty").unwrap();
+
+        let popover_date: Popover = builder.get_object("popover_date").unwrap();
+        let entry_warranty: Entry = builder.get_object("entry_warranty").unwrap();
+
+        btn_warranty.connect_clicked(move |_| {
+            popover_date.set_relative_to(Some(&entry_warranty));
+            popover_date.show_all();
+        });
+    }
+
     // Calendar chooser
     // NOTE: Months start in 0
     {
         let builder = builder.clone();
         let calendar: Calendar = builder.get_object("calendar").unwrap();
 
+        let popover_date: Popover = builder.get_object("popover_date").unwrap();
         let entry_date: Entry = builder.get_object("entry_date").unwrap();
+        let entry_return: Entry = builder.get_object("entry_return").unwrap();
+        let entry_warranty: Entry = builder.get_object("entry_warranty").unwrap();
+
+        for entry in [&entry_date, &entry_return, &entry_warranty].iter() {
+            entry.set_placeholder_text(Some(date_format.placeholder().as_str()));
+        }
 
         calendar.connect_day_selected(move |calendar| {
             let (year, month, day) = calendar.get_date();
+            let text = date_format.format(NaiveDate::from_ymd(year as i32, month + 1, day));
+            let relative = popover_date.get_relative_to().unwrap();
 
-            entry_date.set_text(
-                format!("{:02}/{:02}/{}", day, month+1, year).as_str()
-            );
+            if relative.eq(&entry_return) {
+                entry_return.set_text(text.as_str());
+
+            } else if relative.eq(&entry_warranty) {
+                entry_warranty.set_text(text.as_str());
+
+            } else {
+                entry_date.set_text(text.as_str());
+            }
         });
     }
 
@@ -200,44 +325,19 @@ pub fn create_window(app: &Application, state: &Rc<RefCell<State>>,
         let combo_type: ComboBox = builder.get_object("combo_type").unwrap();
         let combo_currency: ComboBox = builder.get_object("combo_currency").unwrap();
         let entry_date: Entry = builder.get_object("entry_date").unwrap();
+        let entry_return: Entry = builder.get_object("entry_return").unwrap();
+        let entry_warranty: Entry = builder.get_object("entry_warranty").unwrap();
         let receipt_id = receipt_id.clone();
+        let receipt_cell = receipt.clone();
+        let dirty = dirty.clone();
         let window = window.clone();
 
-        btn_save.connect_clicked(move |_| {
-            // Check shop, date and comboboxes
-            let mut error_check = Vec::new();
-
-            let value_shop = entry_shop.get_text().unwrap();
-            if value_shop.is_empty() {
-                error_check.push("shop");
-            }
+        // Duplicates the user was already warned about
+        let warned: Rc<RefCell<Vec<i32>>> = Rc::new(RefCell::new(Vec::new()));
 
-            if combo_type.get_active() < 0 {
-                error_check.push("type");
-            }
-
-            if combo_currency.get_active() < 0 {
-                error_check.push("currency");
-            }
-
-            let value_date = entry_date.get_text().unwrap();
-            let re_date = Regex::new(RE_DATE).unwrap();
-            if value_date.is_empty() || !re_date.is_match(value_date.as_str()) {
-                error_check.push("date");
-            }
-
-            if error_check.len() > 0 {
-                // Show information and abort saving
-                lbl_info.set_text("Check fields:");
-                lbl_info_data.set_text(error_check.join(", ").as_str());
-
-                revealer.set_reveal_child(true);
-
-                return;
-            }
-
-            // Create receipt
-            let mut receipt = receipt.clone();
+        btn_save.connect_clicked(move |_| {
+            let mut receipt = receipt_cell.borrow().clone();
+            let mut errors = Vec::new();
 
             let buffer = entry_desc.get_buffer().unwrap();
             receipt.description = buffer.get_text(
@@ -246,52 +346,161 @@ pub fn create_window(app: &Application, state: &Rc<RefCell<State>>,
                 true
             ).unwrap();
 
-            receipt.shop = value_shop;
+            receipt.shop = entry_shop.get_text().unwrap();
             receipt.amount = spin_cost.get_value();
+            receipt.payment_type = combo_value(&combo_type);
+            receipt.currency = combo_value(&combo_currency);
 
-            let iter_type = combo_type.get_active_iter().unwrap();
-            let model_type = combo_type.get_model().unwrap();
-            receipt.payment_type = model_type.get_value(&iter_type, 0).get::<String>().unwrap();
+            let value_date = entry_date.get_text().unwrap();
 
-            let iter_currency = combo_currency.get_active_iter().unwrap();
-            let model_currency = combo_currency.get_model().unwrap();
-            receipt.currency = model_currency.get_value(&iter_currency, 0).get::<String>().unwrap();
+            match date_format.parse(value_date.as_str()) {
+                Some(date) => receipt.date_paid = date,
+                None => errors.push(FieldError { field: Field::DatePaid, reason: Invalid::Date })
+            }
 
-            receipt.date_paid = NaiveDate::parse_from_str(value_date.as_str(), "%d/%m/%Y").unwrap();
+            // Return and warranty dates are optional
+            let value_return = entry_return.get_text().unwrap();
+            receipt.return_by = date_format.parse(value_return.as_str());
 
-            // Save receipt
-            let status;
-            if receipt_id < 0 {
-                // Creating
-                status = db::insert_receipt(&state.borrow().db_path, &receipt);
+            if !value_return.trim().is_empty() && receipt.return_by.is_none() {
+                errors.push(FieldError { field: Field::ReturnBy, reason: Invalid::Date });
+            }
 
-            } else {
-                // Updating
-                status = db::update_receipt(&state.borrow().db_path, &receipt);
+            let value_warranty = entry_warranty.get_text().unwrap();
+            receipt.warranty_until = date_format.parse(value_warranty.as_str());
 
-                lbl_info.set_text("Receipt updated");
-                lbl_info_data.set_text("");
+            if !value_warranty.trim().is_empty() && receipt.warranty_until.is_none() {
+                errors.push(FieldError { field: Field::WarrantyUntil, reason: Invalid::Date });
             }
 
-            // Check status after saving
-            if status.unwrap() < 1 {
-                // Error, show infobar
-                lbl_info.set_text("Error storing receipt");
-                lbl_info_data.set_text("");
+            if let Err(mut invalid) = receipt.validate() {
+                errors.append(&mut invalid);
+            }
 
-                revealer.set_reveal_child(true);
+            show_field_errors(&builder, &errors);
 
-            } else {
-                // Refresh table
-                glib::idle_add(main_window::refresh_table);
+            if !errors.is_empty() {
+                // Show information and abort saving
+                let fields: Vec<String> = errors.iter().map(|e| e.field.label()).collect();
 
-                // Check if creating and close modal window
-                if receipt_id < 0 {
-                    window.close();
-                }
+                lbl_info.set_text(gettext("Check fields:").as_str());
+                lbl_info_data.set_text(fields.join(", ").as_str());
 
                 revealer.set_reveal_child(true);
+
+                return;
             }
+
+            // Save receipt
+            let state = state.clone();
+            let builder = builder.clone();
+            let receipt_cell = receipt_cell.clone();
+            let mine = receipt.clone();
+            let dirty = dirty.clone();
+            let revealer = revealer.clone();
+            let lbl_info = lbl_info.clone();
+            let lbl_info_data = lbl_info_data.clone();
+            let window = window.clone();
+            let warned = warned.clone();
+            let known = warned.borrow().clone();
+            let worker = state.borrow().worker().clone();
+
+            worker.run(
+                move |db| {
+                    // Warn about new duplicates once, saving again keeps the receipt
+                    let duplicates = try!(db.find_duplicates_of(&receipt, common::DUPLICATE_DAYS));
+
+                    if duplicates.iter().any(|d| !known.contains(&d.id)) {
+                        return Ok(SaveResult::Duplicate(duplicates));
+                    }
+
+                    if receipt_id < 0 {
+                        // Creating
+                        let id = try!(db.insert_receipt(&receipt));
+                        db.get_receipt(id).map(SaveResult::Saved)
+
+                    } else {
+                        // Updating, unless it was changed since it was loaded
+                        match try!(db.update_receipt(&receipt)) {
+                            0 => match db.get_receipt(receipt_id) {
+                                Ok(ref current) if current.deleted_at.is_some() => Ok(SaveResult::Deleted),
+                                Ok(current) => Ok(SaveResult::Conflict(current)),
+                                Err(rusqlite::Error::QueryReturnedNoRows) => Ok(SaveResult::Deleted),
+                                Err(err) => Err(err)
+                            },
+
+                            _ => db.get_receipt(receipt_id).map(SaveResult::Saved)
+                        }
+                    }
+                },
+                move |result| {
+                    match result {
+                        Ok(SaveResult::Saved(saved)) => {
+                            let saved_id = saved.id;
+
+                            *receipt_cell.borrow_mut() = saved;
+                            dirty.set(false);
+
+                            // Notify other windows
+                            if receipt_id < 0 {
+                                common::emit(&state, ReceiptEvent::Created(saved_id));
+
+                            } else {
+                                common::emit(&state, ReceiptEvent::Updated(saved_id));
+                            }
+
+                            if receipt_id >= 0 {
+                                lbl_info.set_text(gettext("Receipt updated").as_str());
+                                lbl_info_data.set_text("");
+                            }
+
+                            // Check if creating and close modal window
+                            if receipt_id < 0 {
+                                window.close();
+                            }
+
+                            revealer.set_reveal_child(true);
+                        },
+
+                        Ok(SaveResult::Conflict(current)) => {
+                            match conflict_dialog::run(&window, &mine, &current, date_format) {
+                                Resolution::KeepMine => {
+                                    // Save again on top of the stored version
+                                    receipt_cell.borrow_mut().version = current.version;
+
+                                    let btn_save: Button = builder.get_object("btn_save").unwrap();
+                                    btn_save.clicked();
+                                },
+
+                                Resolution::UseTheirs => {
+                                    show_receipt(&builder, &receipt_cell, &dirty, date_format, current);
+                                },
+
+                                Resolution::Cancel => {}
+                            }
+                        },
+
+                        Ok(SaveResult::Deleted) => {
+                            show_deleted(&builder);
+                        },
+
+                        Ok(SaveResult::Duplicate(duplicates)) => {
+                            let found: Vec<String> = duplicates.iter()
+                                .map(|d| tr_format("#{} paid on {}", &[d.id.to_string().as_str(),
+                                                                        date_format.format(d.date_paid).as_str()]))
+                                .collect();
+
+                            warned.borrow_mut().extend(duplicates.iter().map(|d| d.id));
+
+                            lbl_info.set_text(gettext("Similar receipts found, save again to keep this one:").as_str());
+                            lbl_info_data.set_text(found.join(", ").as_str());
+
+                            revealer.set_reveal_child(true);
+                        },
+
+                        Err(_) => show_error(&builder, &gettext("Error storing receipt"))
+                    }
+                });
         });
     }
 
@@ -309,9 +518,327 @@ pub fn create_window(app: &Application, state: &Rc<RefCell<State>>,
                 borrowed.window_map.remove(&receipt_id);
             }
 
+            if let Some(subscription) = subscription {
+                borrowed.events.unsubscribe(subscription);
+            }
+
             Inhibit(false)
         });
     }
 
     window
 }
+
+/// Load a receipt and show it in the window
+fn load_receipt(worker: &Worker, builder: &Builder, receipt: &Rc<RefCell<Receipt>>,
+                dirty: &Rc<Cell<bool>>, date_format: DateFormat, receipt_id: i32) {
+
+    let builder = builder.clone();
+    let receipt = receipt.clone();
+    let dirty = dirty.clone();
+
+    worker.run(
+        move |db| db.get_receipt(receipt_id),
+        move |result| {
+            if let Ok(loaded) = result {
+                show_receipt(&builder, &receipt, &dirty, date_format, loaded);
+            }
+        });
+}
+
+/// Show a receipt in the window, replacing any unsaved changes
+fn show_receipt(builder: &Builder, receipt: &Rc<RefCell<Receipt>>,
+                dirty: &Rc<Cell<bool>>, date_format: DateFormat, loaded: Receipt) {
+
+    let entry_shop: Entry = builder.get_object("entry_shop").unwrap();
+    entry_shop.set_text(loaded.shop.as_str());
+
+    let entry_desc: TextView = builder.get_object("entry_desc").unwrap();
+    let desc_buffer = entry_desc.get_buffer().unwrap();
+    desc_buffer.set_text(loaded.description.as_str());
+
+    let spin_cost: SpinButton = builder.get_object("spin_cost").unwrap();
+    spin_cost.set_value(loaded.amount);
+
+    let combo_type: ComboBox = builder.get_object("combo_type").unwrap();
+    let payment_type = loaded.payment_type.clone();
+    set_active_combo!(combo_type, PAYMENTS, payment_type);
+
+    let combo_currency: ComboBox = builder.get_object("combo_currency").unwrap();
+    let currency = loaded.currency.clone();
+    set_active_combo!(combo_currency, CURRENCIES, currency);
+
+    let entry_date: Entry = builder.get_object("entry_date").unwrap();
+    entry_date.set_text(date_format.format(loaded.date_paid).as_str());
+
+    // Optional dates are cleared when reloading a receipt without them
+    let entry_return: Entry = builder.get_object("entry_return").unwrap();
+    entry_return.set_text(loaded.return_by
+        .map(|d| date_format.format(d))
+        .unwrap_or(String::new()).as_str());
+
+    let entry_warranty: Entry = builder.get_object("entry_warranty").unwrap();
+    entry_warranty.set_text(loaded.warranty_until
+        .map(|d| date_format.format(d))
+        .unwrap_or(String::new()).as_str());
+
+    show_field_errors(builder, &[]);
+
+    let lbl_timestamps: Label = builder.get_object("lbl_timestamps").unwrap();
+    lbl_timestamps.set_text(tr_format(
+        "Entered {}, last changed {}",
+        &[date_format.format_datetime(loaded.created_at).as_str(),
+          date_format.format_datetime(loaded.updated_at).as_str()]).as_str());
+
+    // Receipts in the trash cannot be edited until restored
+    let btn_save: Button = builder.get_object("btn_save").unwrap();
+
+    if loaded.deleted_at.is_some() {
+        show_deleted(builder);
+
+    } else if !btn_save.get_sensitive() {
+        let revealer: Revealer = builder.get_object("revealer").unwrap();
+
+        revealer.set_reveal_child(false);
+        btn_save.set_sensitive(true);
+    }
+
+    *receipt.borrow_mut() = loaded;
+    dirty.set(false);
+}
+
+/// Check whether another window or process changed or deleted the receipt
+///
+/// The receipt is reloaded unless there are unsaved changes, in which case
+/// the user is warned instead and saving shows the conflict.
+fn check_change(worker: &Worker, builder: &Builder, receipt: &Rc<RefCell<Receipt>>,
+                dirty: &Rc<Cell<bool>>, date_format: DateFormat, receipt_id: i32) {
+
+    let builder = builder.clone();
+    let receipt = receipt.clone();
+    let dirty = dirty.clone();
+
+    worker.run(
+        move |db| db.get_receipt(receipt_id),
+        move |result| {
+            let current = match result {
+                Ok(r) => r,
+                Err(rusqlite::Error::QueryReturnedNoRows) => {
+                    show_deleted(&builder);
+                    return;
+                },
+                Err(_) => return
+            };
+
+            if current == *receipt.borrow() {
+                return;
+            }
+
+            if dirty.get() && current.deleted_at.is_some() {
+                show_deleted(&builder);
+
+            } else if dirty.get() {
+                let revealer: Revealer = builder.get_object("revealer").unwrap();
+                let lbl_info: Label = builder.get_object("lbl_info").unwrap();
+                let lbl_info_data: Label = builder.get_object("lbl_info_data").unwrap();
+                let btn_save: Button = builder.get_object("btn_save").unwrap();
+
+                lbl_info.set_text(gettext("Receipt was changed elsewhere.").as_str());
+                lbl_info_data.set_text(gettext("Saving will ask which changes to keep").as_str());
+                revealer.set_reveal_child(true);
+
+                // It may have been restored from the trash
+                btn_save.set_sensitive(true);
+
+            } else {
+                show_receipt(&builder, &receipt, &dirty, date_format, current);
+            }
+        });
+}
+
+/// Load the history of a receipt in the history tab
+///
+/// Each revision shows the fields that changed. All revisions but the
+/// latest can be restored.
+fn load_history(state: &Rc<RefCell<State>>, builder: &Builder, receipt_id: i32) {
+    let state = state.clone();
+    let builder = builder.clone();
+    let worker = state.borrow().worker().clone();
+    let date_format = state.borrow().config.date_format;
+
+    worker.run(move |db| db.get_history(receipt_id), move |history| {
+        let list_history: ListBox = builder.get_object("list_history").unwrap();
+
+        for child in list_history.get_children() {
+            list_history.remove(&child);
+        }
+
+        let history = match history {
+            Ok(h) => h,
+            Err(_) => return
+        };
+
+        for (index, revision) in history.iter().enumerate() {
+            let row_box = Box::new(Orientation::Vertical, 5);
+            row_box.set_border_width(10);
+
+            let action = match revision.action {
+                HistoryAction::Created => gettext("Created"),
+                HistoryAction::Updated => gettext("Updated"),
+                HistoryAction::Deleted => gettext("Deleted"),
+                HistoryAction::Restored => gettext("Restored"),
+                HistoryAction::Purged => gettext("Purged"),
+                HistoryAction::Repaired => gettext("Repaired")
+            };
+
+            let user = if revision.user.is_empty() { gettext("unknown user") }
+                       else { revision.user.clone() };
+
+            let header = tr_format(
+                "<b>{}</b> {} by {}",
+                &[action.as_str(),
+                  date_format.format_datetime(revision.changed_at).as_str(),
+                  ::gui::highlight_markup(user.as_str()).as_str()]
+            );
+
+            let lbl_header = Label::new(None);
+            lbl_header.set_markup(header.as_str());
+            lbl_header.set_halign(Align::Start);
+            row_box.pack_start(&lbl_header, false, false, 0);
+
+            // Changed fields
+            let changes = match (revision.old.as_ref(), revision.new.as_ref()) {
+                (Some(old), Some(new)) => {
+                    ::gui::receipt_fields(old, date_format).into_iter()
+                        .zip(::gui::receipt_fields(new, date_format).into_iter())
+                        .filter(|&((_, ref before), (_, ref after))| before != after)
+                        .map(|((name, before), (_, after))| {
+                            format!("{}: {} \u{2192} {}", name, before, after)
+                        })
+                        .collect()
+                },
+
+                (None, Some(new)) => {
+                    ::gui::receipt_fields(new, date_format).into_iter()
+                        .filter(|&(_, ref value)| !value.is_empty())
+                        .map(|(name, value)| format!("{}: {}", name, value))
+                        .collect()
+                },
+
+                _ => Vec::new()
+            };
+
+            let lbl_changes = Label::new(Some(changes.join("\n").as_str()));
+            lbl_changes.set_halign(Align::Start);
+            lbl_changes.set_line_wrap(true);
+            row_box.pack_start(&lbl_changes, false, false, 0);
+
+            // Restore previous revisions
+            if index > 0 && revision.new.is_some() {
+                let btn_restore = Button::new_with_label(gettext("Restore").as_str());
+                btn_restore.set_halign(Align::End);
+                row_box.pack_start(&btn_restore, false, false, 0);
+
+                let state = state.clone();
+                let builder = builder.clone();
+                let revision_id = revision.id;
+
+                btn_restore.connect_clicked(move |_| {
+                    let state = state.clone();
+                    let builder = builder.clone();
+                    let worker = state.borrow().worker().clone();
+
+                    worker.run(
+                        move |db| db.restore_revision(revision_id),
+                        move |status| {
+                            match status {
+                                Ok(0) => show_error(&builder, &gettext("Receipts in the trash or with invalid values cannot be restored")),
+                                Ok(_) => common::emit(&state, ReceiptEvent::Updated(receipt_id)),
+                                Err(_) => show_error(&builder, &gettext("Error restoring the revision"))
+                            }
+                        });
+                });
+            }
+
+            list_history.insert(&row_box, index as i32);
+        }
+
+        list_history.show_all();
+    });
+}
+
+/// Widgets used to enter each field of a receipt
+const FIELD_WIDGETS: [(Field, &'static str); 7] = [
+    (Field::Shop, "entry_shop"),
+    (Field::Amount, "spin_cost"),
+    (Field::PaymentType, "combo_type"),
+    (Field::Currency, "combo_currency"),
+    (Field::DatePaid, "entry_date"),
+    (Field::ReturnBy, "entry_return"),
+    (Field::WarrantyUntil, "entry_warranty")
+];
+
+/// Highlight the widgets of invalid fields and explain the error in their
+/// tooltip
+///
+/// Widgets of fields without errors are shown normally.
+fn show_field_errors(builder: &Builder, errors: &[FieldError]) {
+    for &(field, id) in FIELD_WIDGETS.iter() {
+        let widget: Widget = builder.get_object(id).unwrap();
+        let error = errors.iter().find(|e| e.field == field);
+
+        if let Some(context) = widget.get_style_context() {
+            if error.is_some() {
+                context.add_class("error");
+            } else {
+                context.remove_class("error");
+            }
+        }
+
+        let message = error.map(|e| e.message());
+        widget.set_tooltip_text(message.as_ref().map(|m| m.as_str()));
+    }
+}
+
+/// Value in the first column of the active row of a combobox, empty if none
+fn combo_value(combo: &ComboBox) -> String {
+    match (combo.get_active_iter(), combo.get_model()) {
+        (Some(iter), Some(model)) => model.get_value(&iter, 0).get::<String>().unwrap_or(String::new()),
+        _ => String::new()
+    }
+}
+
+/// Tell the user the receipt was deleted and prevent saving it
+fn show_deleted(builder: &Builder) {
+    let revealer: Revealer = builder.get_object("revealer").unwrap();
+    let lbl_info: Label = builder.get_object("lbl_info").unwrap();
+    let lbl_info_data: Label = builder.get_object("lbl_info_data").unwrap();
+    let btn_save: Button = builder.get_object("btn_save").unwrap();
+
+    lbl_info.set_text(gettext("Receipt was moved to the trash").as_str());
+    lbl_info_data.set_text("");
+    revealer.set_reveal_child(true);
+
+    btn_save.set_sensitive(false);
+}
+
+/// Show an error in the info bar
+fn show_error(builder: &Builder, text: &str) {
+    let revealer: Revealer = builder.get_object("revealer").unwrap();
+    let lbl_info: Label = builder.get_object("lbl_info").unwrap();
+    let lbl_info_data: Label = builder.get_object("lbl_info_data").unwrap();
+
+    lbl_info.set_text(text);
+    lbl_info_data.set_text("");
+    revealer.set_reveal_child(true);
+}
+
+/// Outcome of saving a receipt
+enum SaveResult {
+    Saved(Receipt),
+    // The receipt was changed elsewhere, holds the stored version
+    Conflict(Receipt),
+    Deleted,
+    // Other receipts look like the same purchase
+    Duplicate(Vec<Receipt>)
+}
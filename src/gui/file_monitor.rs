@@ -0,0 +1,109 @@
+// MIT License
+//
+// Copyright (c) 2016 Rafael Medina García <rafamedgar@gmail.com>
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+/// File change monitoring
+///
+/// The gio bindings do not wrap `GFileMonitor` yet, so monitors are created
+/// through the FFI functions directly.
+
+use std::mem;
+use std::os::raw::c_char;
+use std::ptr;
+
+use gio_ffi;
+use glib::translate::*;
+use glib_ffi::gpointer;
+use gobject_ffi;
+
+
+/// Monitor of a file, released when dropped
+pub struct Monitor {
+    monitor: *mut gio_ffi::GFileMonitor
+}
+
+impl Monitor {
+    /// Stop watching the file
+    pub fn cancel(&self) {
+        unsafe {
+            gio_ffi::g_file_monitor_cancel(self.monitor);
+        }
+    }
+}
+
+impl Drop for Monitor {
+    fn drop(&mut self) {
+        // Also releases the callback
+        unsafe {
+            gobject_ffi::g_object_unref(self.monitor as *mut gobject_ffi::GObject);
+        }
+    }
+}
+
+/// Watch a file for changes
+///
+/// `callback` is called in the main thread every time the file is changed,
+/// created or deleted, until the monitor is cancelled or dropped. Returns
+/// `None` if the file cannot be watched.
+pub fn watch<F: Fn() + 'static>(path: &str, callback: F) -> Option<Monitor> {
+    let callback: Box<Box<Fn()>> = Box::new(Box::new(callback));
+
+    unsafe {
+        let path: Stash<*const c_char, str> = path.to_glib_none();
+        let file = gio_ffi::g_file_new_for_path(path.0 as *mut c_char);
+
+        let monitor = gio_ffi::g_file_monitor_file(
+            file,
+            gio_ffi::G_FILE_MONITOR_NONE,
+            ptr::null_mut(),
+            ptr::null_mut()
+        );
+
+        gobject_ffi::g_object_unref(file as *mut gobject_ffi::GObject);
+
+        if monitor.is_null() {
+            return None;
+        }
+
+        gobject_ffi::g_signal_connect_data(
+            monitor as *mut gobject_ffi::GObject,
+            b"changed\0".as_ptr() as *const c_char,
+            Some(mem::transmute(changed_trampoline as usize)),
+            Box::into_raw(callback) as gpointer,
+            Some(destroy_callback),
+            gobject_ffi::GConnectFlags::empty()
+        );
+
+        Some(Monitor { monitor: monitor })
+    }
+}
+
+unsafe extern "C" fn changed_trampoline(_monitor: *mut gio_ffi::GFileMonitor,
+                                        _file: *mut gio_ffi::GFile,
+                                        _other_file: *mut gio_ffi::GFile,
+                                        _event: gio_ffi::GFileMonitorEvent,
+                                        data: gpointer) {
+    let callback = &*(data as *const Box<Fn()>);
+    callback();
+}
+
+unsafe extern "C" fn destroy_callback(data: gpointer, _closure: *mut gobject_ffi::GClosure) {
+    drop(Box::from_raw(data as *mut Box<Fn()>));
+}
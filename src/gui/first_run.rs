@@ -0,0 +1,227 @@
+// MIT License
+//
+// Copyright (c) 2016 Rafael Medina García <rafamedgar@gmail.com>
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+/// First-run assistant to create or open a database
+
+use std::cell::RefCell;
+use std::path::Path;
+use std::rc::Rc;
+
+use gettextrs::gettext;
+use gtk::prelude::*;
+use gtk::{
+    Application,
+    Assistant,
+    AssistantPageType,
+    Box,
+    Builder,
+    Button,
+    Entry,
+    FileChooserAction,
+    Label,
+    RadioButton,
+    Window
+};
+
+use common::State;
+use config;
+use db;
+use encryption;
+use i18n;
+use i18n::tr_format;
+use migrations;
+
+
+/// Creates the first-run assistant
+///
+/// Once the assistant is finished, the chosen database is stored in the
+/// current profile and the main window is opened in its place.
+pub fn create_window(app: &Application, state: &Rc<RefCell<State>>) -> Window {
+    let builder = Builder::new();
+    builder.set_translation_domain(Some(i18n::DOMAIN));
+    builder.add_from_string(include_str!("first_run.ui"));
+
+    let assistant: Assistant = builder.get_object("assistant").unwrap();
+    assistant.set_application(Some(app));
+
+    // Validate the path when it or the kind of database changes
+    {
+        let builder = builder.clone();
+        let entry_file: Entry = builder.get_object("entry_file").unwrap();
+        let radio_create: RadioButton = builder.get_object("radio_create").unwrap();
+
+        {
+            let builder = builder.clone();
+            entry_file.connect_changed(move |_| check_page(&builder));
+        }
+
+        radio_create.connect_toggled(move |_| check_page(&builder));
+    }
+
+    // Choose the database file
+    {
+        let builder = builder.clone();
+        let btn_file: Button = builder.get_object("btn_file").unwrap();
+
+        let assistant = assistant.clone();
+        let entry_file: Entry = builder.get_object("entry_file").unwrap();
+        let radio_create: RadioButton = builder.get_object("radio_create").unwrap();
+
+        btn_file.connect_clicked(move |_| {
+            let action = if radio_create.get_active() {
+                FileChooserAction::Save
+            } else {
+                FileChooserAction::Open
+            };
+
+            if let Some(path) = ::gui::choose_database(&assistant, action) {
+                entry_file.set_text(path.as_str());
+            }
+        });
+    }
+
+    // Summary before applying
+    {
+        let builder = builder.clone();
+
+        assistant.connect_prepare(move |assistant, page| {
+            if assistant.get_page_type(page) != AssistantPageType::Confirm {
+                return;
+            }
+
+            let entry_file: Entry = builder.get_object("entry_file").unwrap();
+            let radio_create: RadioButton = builder.get_object("radio_create").unwrap();
+            let lbl_summary: Label = builder.get_object("lbl_summary").unwrap();
+
+            let path = entry_file.get_text().unwrap_or(String::new());
+
+            lbl_summary.set_text(if radio_create.get_active() {
+                tr_format("A new database will be created in {}", &[path.as_str()])
+            } else {
+                tr_format("The database in {} will be opened and updated if needed",
+                          &[path.as_str()])
+            }.as_str());
+        });
+    }
+
+    // Store the database in the current profile
+    {
+        let builder = builder.clone();
+        let state = state.clone();
+
+        assistant.connect_apply(move |_| {
+            let entry_file: Entry = builder.get_object("entry_file").unwrap();
+            let db_path = entry_file.get_text().unwrap_or(String::new());
+
+            let mut borrowed = state.borrow_mut();
+            let current = borrowed.config.profile.clone();
+
+            for profile in borrowed.config.profiles.iter_mut().filter(|p| p.name == current) {
+                profile.db_path = db_path.clone();
+            }
+
+            borrowed.db_path = db_path;
+            config::save(&borrowed.config);
+        });
+    }
+
+    // Continue to the main window
+    {
+        let state = state.clone();
+        let app = app.clone();
+
+        assistant.connect_close(move |assistant| {
+            // The database is created and migrated when opened
+            let window = ::gui::open_profile(&app, &state);
+
+            window.show_all();
+            app.add_window(&window);
+
+            assistant.destroy();
+        });
+    }
+
+    assistant.connect_cancel(|assistant| {
+        assistant.destroy();
+    });
+
+    assistant.upcast()
+}
+
+/// Check the chosen database and allow continuing if it is valid
+fn check_page(builder: &Builder) {
+    let assistant: Assistant = builder.get_object("assistant").unwrap();
+    let page_file: Box = builder.get_object("page_file").unwrap();
+    let entry_file: Entry = builder.get_object("entry_file").unwrap();
+    let radio_create: RadioButton = builder.get_object("radio_create").unwrap();
+    let lbl_file_error: Label = builder.get_object("lbl_file_error").unwrap();
+
+    let path = entry_file.get_text().unwrap_or(String::new());
+
+    let result = if path.is_empty() {
+        Err(String::new())
+    } else {
+        check_database(path.as_str(), radio_create.get_active())
+    };
+
+    lbl_file_error.set_text(result.as_ref().err().map_or("", |e| e.as_str()));
+    assistant.set_page_complete(&page_file, result.is_ok());
+}
+
+/// Check that a database can be created in or opened from a path
+///
+/// Existing databases must have been created by the application.
+fn check_database(db_path: &str, create: bool) -> Result<(), String> {
+    let path = Path::new(db_path);
+
+    if create {
+        if path.exists() {
+            return Err(gettext("A file with that name already exists"));
+        }
+
+        return match path.parent() {
+            Some(dir) if dir.as_os_str().is_empty() || dir.is_dir() => Ok(()),
+            _ => Err(gettext("The folder does not exist"))
+        };
+    }
+
+    if !path.is_file() {
+        return Err(gettext("The file does not exist"));
+    }
+
+    // Checked once the passphrase is entered
+    if encryption::is_encrypted(db_path) {
+        return if encryption::is_available() { Ok(()) }
+               else { Err(encryption::unavailable_message(db_path)) };
+    }
+
+    let database = match db::Database::open(db_path) {
+        Ok(d) => d,
+        Err(_) => return Err(gettext("The file is not a database"))
+    };
+
+    if migrations::current_version(&database) < 1 {
+        return Err(gettext("The file is not a Receipt Keeper database"));
+    }
+
+    Ok(())
+}
@@ -0,0 +1,152 @@
+// MIT License
+//
+// Copyright (c) 2016 Rafael Medina García <rafamedgar@gmail.com>
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+/// Dialog to preview the transactions of a statement before importing them
+
+use gettextrs::gettext;
+use gtk;
+use gtk::prelude::*;
+use gtk::{Align, ApplicationWindow, CheckButton, Dialog, Grid, Label, ResponseType, ScrolledWindow};
+
+use dates::DateFormat;
+use i18n;
+use i18n::tr_format;
+use statements::{Statement, Status};
+
+
+/// Show the transactions of a statement and how they would be imported
+///
+/// New payments are selected, possible duplicates can be selected by the
+/// user, and the rest cannot be imported. Returns the indices of the
+/// transactions chosen, or `None` if the user cancelled.
+pub fn run(parent: &ApplicationWindow, statement: &Statement, statuses: &[Status],
+           date_format: DateFormat) -> Option<Vec<usize>> {
+
+    let dialog = Dialog::new_with_buttons(
+        Some(gettext("Import statement").as_str()),
+        Some(parent),
+        gtk::DIALOG_MODAL,
+        &[
+            (gettext("Cancel").as_str(), ResponseType::Cancel.into()),
+            (gettext("Import").as_str(), ResponseType::Accept.into())
+        ]
+    );
+
+    dialog.set_default_response(ResponseType::Accept.into());
+
+    let grid = Grid::new();
+    grid.set_border_width(10);
+    grid.set_row_spacing(5);
+    grid.set_column_spacing(15);
+
+    let lbl_source = Label::new(Some(statement.source.as_str()));
+    lbl_source.set_halign(Align::Start);
+    grid.attach(&lbl_source, 0, 0, 6, 1);
+
+    let mut checks = Vec::new();
+
+    for (row, (transaction, status)) in statement.transactions.iter().zip(statuses.iter()).enumerate() {
+        let row = row as i32 + 1;
+
+        let check = CheckButton::new();
+        check.set_active(status.is_selected());
+        check.set_sensitive(status.can_import());
+        grid.attach(&check, 0, row, 1, 1);
+
+        let payee = if transaction.payee.is_empty() { &transaction.memo } else { &transaction.payee };
+
+        // Receipts are created with the payment type shown
+        let values = [
+            date_format.format(transaction.date),
+            payee.clone(),
+            transaction.payment_type.to_string(),
+            i18n::format_money(transaction.amount, transaction.currency.as_str())
+        ];
+
+        for (column, value) in values.iter().enumerate() {
+            let lbl_value = Label::new(Some(value.as_str()));
+            lbl_value.set_halign(if column == 3 { Align::End } else { Align::Start });
+            lbl_value.set_line_wrap(true);
+            grid.attach(&lbl_value, column as i32 + 1, row, 1, 1);
+        }
+
+        let lbl_status = Label::new(None);
+        lbl_status.set_markup(format!("<i>{}</i>", status.label()).as_str());
+        lbl_status.set_halign(Align::Start);
+        grid.attach(&lbl_status, 5, row, 1, 1);
+
+        checks.push(check);
+    }
+
+    if statement.transactions.is_empty() {
+        let lbl_empty = Label::new(Some(gettext("The statement has no transactions").as_str()));
+        grid.attach(&lbl_empty, 0, 1, 6, 1);
+    }
+
+    let lbl_selected = Label::new(Some(selected_text(&checks).as_str()));
+    lbl_selected.set_halign(Align::Start);
+
+    let scrolled = ScrolledWindow::new(None, None);
+    scrolled.set_min_content_height(300);
+    scrolled.set_min_content_width(600);
+    scrolled.add(&grid);
+
+    let content = dialog.get_content_area();
+    content.pack_start(&scrolled, true, true, 0);
+    content.pack_start(&lbl_selected, false, false, 5);
+
+    // Keep the count of selected transactions up to date
+    for check in checks.iter() {
+        let checks = checks.clone();
+        let lbl_selected = lbl_selected.clone();
+
+        check.connect_toggled(move |_| {
+            lbl_selected.set_text(selected_text(&checks).as_str());
+        });
+    }
+
+    dialog.show_all();
+
+    let response = dialog.run();
+
+    let chosen = checks.iter()
+        .enumerate()
+        .filter(|&(_, check)| check.get_active())
+        .map(|(index, _)| index)
+        .collect();
+
+    dialog.destroy();
+
+    if response == ResponseType::Accept.into() {
+        Some(chosen)
+    } else {
+        None
+    }
+}
+
+/// Number of selected transactions shown below the list
+fn selected_text(checks: &[CheckButton]) -> String {
+    let selected = checks.iter().filter(|c| c.get_active()).count();
+
+    tr_format("{} of {} transactions selected",
+              &[selected.to_string().as_str(), checks.len().to_string().as_str()])
+}
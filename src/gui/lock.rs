@@ -0,0 +1,265 @@
+// MIT License
+//
+// Copyright (c) 2016 Rafael Medina García <rafamedgar@gmail.com>
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+/// Passphrase prompt and automatic locking of encrypted databases
+
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
+use std::time::Instant;
+
+use gettextrs::gettext;
+use glib::Continue;
+use gtk;
+use gtk::prelude::*;
+use gtk::{
+    Application,
+    ApplicationWindow,
+    Box,
+    Builder,
+    Button,
+    Entry,
+    HeaderBar,
+    Inhibit,
+    Label,
+    Stack,
+    Widget,
+    Window,
+    WindowPosition
+};
+
+use common::State;
+use db;
+use i18n;
+use i18n::tr_format;
+
+
+/// Seconds between checks for inactivity
+const CHECK_SECONDS: u32 = 10;
+
+/// Creates a window asking for the passphrase of the current database
+///
+/// Once the right passphrase is entered, the main window is opened in its
+/// place.
+pub fn create_window(app: &Application, state: &Rc<RefCell<State>>) -> Window {
+    let window = ApplicationWindow::new(app);
+
+    window.set_title(gettext("Receipt Keeper").as_str());
+    window.set_border_width(10);
+    window.set_position(WindowPosition::Center);
+    window.set_default_size(400, 300);
+
+    let db_path = state.borrow().db_path.clone();
+    let builder = build_prompt(
+        tr_format("{} is encrypted. Enter its passphrase to open it.", &[db_path.as_str()]));
+
+    let lock_box: Box = builder.get_object("lock_box").unwrap();
+    window.add(&lock_box);
+
+    {
+        let app = app.clone();
+        let state = state.clone();
+        let window = window.clone();
+
+        connect_unlock(&builder, move |passphrase| {
+            if db::Database::open_with_key(db_path.as_str(), Some(passphrase)).is_err() {
+                return false;
+            }
+
+            state.borrow_mut().key = Some(passphrase.to_string());
+
+            let main = ::gui::open_profile(&app, &state);
+
+            main.show_all();
+            app.add_window(&main);
+
+            window.destroy();
+
+            true
+        });
+    }
+
+    window.upcast()
+}
+
+/// Lock the main window after a while without activity
+///
+/// Only encrypted databases are locked. The contents of `stack` and
+/// `header_bar` are hidden along with the other windows of the application
+/// until the passphrase is entered again.
+pub fn enable_auto_lock(app: &Application,
+                        state: &Rc<RefCell<State>>,
+                        window: &ApplicationWindow,
+                        stack: &Stack,
+                        header_bar: &HeaderBar) {
+
+    if state.borrow().key.is_none() {
+        return;
+    }
+
+    track_activity(state, window);
+
+    let builder = build_prompt(
+        gettext("Receipt Keeper is locked. Enter the passphrase to continue."));
+
+    let lock_box: Box = builder.get_object("lock_box").unwrap();
+    stack.add_named(&lock_box, "lock");
+
+    // Widgets hidden while locked
+    let hidden: Rc<RefCell<Vec<Widget>>> = Rc::new(RefCell::new(Vec::new()));
+
+    {
+        let state = state.clone();
+        let stack = stack.clone();
+        let hidden = hidden.clone();
+
+        connect_unlock(&builder, move |passphrase| {
+            if state.borrow().key.as_ref().map(|k| k.as_str()) != Some(passphrase) {
+                return false;
+            }
+
+            state.borrow_mut().last_activity = Instant::now();
+            stack.set_visible_child_name("main");
+
+            for widget in hidden.borrow_mut().drain(..) {
+                widget.show();
+            }
+
+            true
+        });
+    }
+
+    let closed = Rc::new(Cell::new(false));
+
+    {
+        let closed = closed.clone();
+        window.connect_destroy(move |_| closed.set(true));
+    }
+
+    let app = app.clone();
+    let state = state.clone();
+    let window = window.clone();
+    let stack = stack.clone();
+    let header_bar = header_bar.clone();
+
+    gtk::timeout_add_seconds(CHECK_SECONDS, move || {
+        if closed.get() {
+            return Continue(false);
+        }
+
+        let minutes = state.borrow().config.lock_minutes as u64;
+        let idle = state.borrow().last_activity.elapsed().as_secs();
+        let locked = stack.get_visible_child_name().map_or(false, |name| name == "lock");
+
+        if minutes == 0 || idle < minutes * 60 || locked {
+            return Continue(true);
+        }
+
+        let mut hidden = hidden.borrow_mut();
+
+        for other in app.get_windows().into_iter().filter(|w| *w != window && w.get_visible()) {
+            other.hide();
+            hidden.push(other.upcast());
+        }
+
+        for child in header_bar.get_children().into_iter().filter(|c| c.get_visible()) {
+            child.hide();
+            hidden.push(child);
+        }
+
+        stack.set_visible_child_name("lock");
+
+        let entry_passphrase: Entry = builder.get_object("entry_passphrase").unwrap();
+        entry_passphrase.grab_focus();
+
+        Continue(true)
+    });
+}
+
+/// Count key presses, clicks and scrolling in a window as activity
+pub fn track_activity<W: WidgetExt>(state: &Rc<RefCell<State>>, window: &W) {
+    {
+        let state = state.clone();
+
+        window.connect_key_press_event(move |_, _| {
+            state.borrow_mut().last_activity = Instant::now();
+            Inhibit(false)
+        });
+    }
+
+    {
+        let state = state.clone();
+
+        window.connect_button_press_event(move |_, _| {
+            state.borrow_mut().last_activity = Instant::now();
+            Inhibit(false)
+        });
+    }
+
+    let state = state.clone();
+
+    window.connect_scroll_event(move |_, _| {
+        state.borrow_mut().last_activity = Instant::now();
+        Inhibit(false)
+    });
+}
+
+/// Build the passphrase prompt with a message above the entry
+fn build_prompt(message: String) -> Builder {
+    let builder = Builder::new();
+    builder.set_translation_domain(Some(i18n::DOMAIN));
+    builder.add_from_string(include_str!("lock.ui"));
+
+    let lbl_lock: Label = builder.get_object("lbl_lock").unwrap();
+    lbl_lock.set_text(message.as_str());
+
+    builder
+}
+
+/// Check the passphrase when the unlock button is clicked or the entry
+/// activated
+///
+/// `check` returns whether the passphrase is right.
+fn connect_unlock<F: Fn(&str) -> bool + 'static>(builder: &Builder, check: F) {
+    let btn_unlock: Button = builder.get_object("btn_unlock").unwrap();
+    let entry_passphrase: Entry = builder.get_object("entry_passphrase").unwrap();
+    let lbl_lock_error: Label = builder.get_object("lbl_lock_error").unwrap();
+
+    let unlock = Rc::new(move || {
+        let passphrase = entry_passphrase.get_text().unwrap_or(String::new());
+
+        if check(passphrase.as_str()) {
+            entry_passphrase.set_text("");
+            lbl_lock_error.set_text("");
+        } else {
+            lbl_lock_error.set_text(gettext("Wrong passphrase").as_str());
+            entry_passphrase.grab_focus();
+        }
+    });
+
+    {
+        let unlock = unlock.clone();
+        btn_unlock.connect_clicked(move |_| unlock());
+    }
+
+    let entry_passphrase: Entry = builder.get_object("entry_passphrase").unwrap();
+    entry_passphrase.connect_activate(move |_| unlock());
+}
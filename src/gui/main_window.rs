@@ -22,17 +22,20 @@
 
 /// Main window definition
 
-use std::cell::RefCell;
-use std::collections::HashMap;
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, HashSet};
 use std::mem;
+use std::path::PathBuf;
 use std::rc::Rc;
 use std::thread;
 
-use glib;
+use chrono::{Duration, Local, NaiveDate};
+use gettextrs::gettext;
 use gtk;
 use gtk::prelude::*;
 use gtk::{
     AboutDialog,
+    Align,
     Application,
     ApplicationWindow,
     Box,
@@ -55,14 +58,25 @@ use gtk::{
     Button,
     CellRendererText,
     Calendar,
+    CheckButton,
     ComboBox,
+    ComboBoxText,
     Entry,
+    FileChooserAction,
+    FileChooserDialog,
+    FileFilter,
+    InfoBar,
     Label,
+    ListBox,
     ListStore,
     Popover,
     ScrolledWindow,
+    SearchEntry,
     Separator,
+    SortColumn,
+    SortType,
     SpinButton,
+    Stack,
     ToggleButton,
     TreeIter,
     TreeSelection,
@@ -71,11 +85,21 @@ use gtk::{
 };
 use rusqlite::Connection;
 
-use common::{State, CURRENCIES, PAYMENTS};
+use common;
+use common::{ColumnLayout, ReceiptEvent, State, COLUMNS, CURRENCIES, EXPIRY_DAYS, PAGE_SIZE, PAYMENTS,
+             RECENT_DAYS};
+use config;
+use config::WindowGeometry;
+use dates::DateFormat;
 use migrations;
 use db;
 use db::Receipt;
-use gui::{edit_window, settings_window};
+use gui::{duplicates_window, edit_window, file_monitor, import_dialog, lock, notification, reconcile_window,
+          settings_window};
+use i18n;
+use i18n::tr_format;
+use statements;
+use worker::Worker;
 
 
 /// Creates the main window
@@ -84,21 +108,35 @@ pub fn create_window(app: &Application,
 
     let window = ApplicationWindow::new(&app);
 
-    window.set_title("Receipt Keeper");
+    window.set_title(gettext("Receipt Keeper").as_str());
     window.set_border_width(10);
-    window.set_default_size(800, 600);
     window.set_position(WindowPosition::Center);
 
+    // Size from the last time the window was closed
+    {
+        let geometry = state.borrow().config.window;
+        window.set_default_size(geometry.width, geometry.height);
+
+        if geometry.maximized {
+            window.maximize();
+        }
+    }
+
     let builder = Builder::new();
+    builder.set_translation_domain(Some(i18n::DOMAIN));
     builder.add_from_string(include_str!("main_window.ui"));
 
     // Header bar
     let header_bar: HeaderBar = builder.get_object("header_bar").unwrap();
     window.set_titlebar(Some(&header_bar));
 
-    // Window container
+    // Window container, with room for the lock screen
     let main_box: Box = builder.get_object("main_box").unwrap();
-    window.add(&main_box);
+    let stack = Stack::new();
+    stack.add_named(&main_box, "main");
+    window.add(&stack);
+
+    lock::enable_auto_lock(app, state, &window, &stack, &header_bar);
 
     // Fill stores
     let store_currency: ListStore = builder.get_object("store_currency").unwrap();
@@ -107,12 +145,216 @@ pub fn create_window(app: &Application,
     let store_type: ListStore = builder.get_object("store_type").unwrap();
     fill_store!(combo => store_type, PAYMENTS);
 
+    // Newest receipts first
     let store_table: ListStore = builder.get_object("store_table").unwrap();
-    let receipts = db::get_all_receipts(&state.borrow().db_path).unwrap();
-    fill_store!(table => store_table, receipts);
+    store_table.set_sort_column_id(SortColumn::Index(10), SortType::Descending);
+
+    // Table columns
+    apply_column_layout(&builder, &state.borrow().config.columns);
+
+    {
+        let builder = builder.clone();
+        let columns_box: Box = builder.get_object("columns_box").unwrap();
+        let hidden = state.borrow().config.columns.hidden.clone();
+
+        for name in COLUMNS.iter() {
+            let column = get_column(&builder, name);
+            let title = column.get_title().unwrap_or(name.to_string());
+
+            let check = CheckButton::new_with_label(title.as_str());
+            check.set_active(!hidden.iter().any(|h| h == name));
+            columns_box.pack_start(&check, false, false, 0);
+
+            let state = state.clone();
+            let name = name.to_string();
+
+            check.connect_toggled(move |check| {
+                column.set_visible(check.get_active());
+
+                let mut borrowed = state.borrow_mut();
+                borrowed.config.columns.hidden.retain(|h| *h != name);
+
+                if !check.get_active() {
+                    borrowed.config.columns.hidden.push(name.clone());
+                }
+
+                config::save(&borrowed.config);
+            });
+        }
+
+        columns_box.show_all();
+    }
+
+    // Store column order after dragging a column header
+    {
+        let builder = builder.clone();
+        let state = state.clone();
+        let view_table: TreeView = builder.get_object("view_table").unwrap();
+
+        view_table.connect_columns_changed(move |view| {
+            let order: Vec<String> = view.get_columns().iter()
+                .filter_map(|column| {
+                    COLUMNS.iter()
+                        .find(|name| get_column(&builder, name).eq(column))
+                        .map(|name| name.to_string())
+                })
+                .collect();
+
+            // Also emitted while the view is being destroyed
+            if order.len() != COLUMNS.len() {
+                return;
+            }
+
+            if let Ok(mut borrowed) = state.try_borrow_mut() {
+                if borrowed.config.columns.order != order {
+                    borrowed.config.columns.order = order;
+                    config::save(&borrowed.config);
+                }
+            }
+        });
+    }
+
+    // Receipt table state
+    let table = Rc::new(RefCell::new(Table {
+        builder: builder.clone(),
+        worker: state.borrow().worker().clone(),
+        last: None,
+        loaded: HashSet::new(),
+        complete: false,
+        loading: false,
+        generation: 0,
+        date_format: state.borrow().config.date_format,
+        saved_searches: Vec::new()
+    }));
+
+    // Keep the table up to date with changes made in other windows
+    {
+        let table = table.clone();
+
+        let subscription = state.borrow_mut().events.subscribe(move |event| {
+            match event.id() {
+                Some(id) => update_row(&table, id),
+                None => refresh_table(&table)
+            }
+
+            refresh_saved_searches(&table);
+        });
+
+        // The window is closed when switching profiles and when quitting
+        let state = state.clone();
+
+        window.connect_delete_event(move |window, _| {
+            let mut borrowed = state.borrow_mut();
+            borrowed.events.unsubscribe(subscription);
+
+            // Remember the size of the window
+            let (width, height) = window.get_size();
+            let maximized = window.is_maximized();
+
+            if maximized {
+                borrowed.config.window.maximized = true;
+            } else {
+                borrowed.config.window = WindowGeometry {
+                    width: width,
+                    height: height,
+                    maximized: false
+                };
+            }
+
+            config::save(&borrowed.config);
+
+            Inhibit(false)
+        });
+    }
+
+    // Watch for changes made by other processes
+    {
+        let db_path = state.borrow().db_path.clone();
+        let data_version = Rc::new(Cell::new(None));
+        let mut monitors = Vec::new();
+
+        // Changes are written to the write-ahead log first
+        for path in [db_path.clone(), format!("{}-wal", db_path)].iter() {
+            let state = state.clone();
+            let data_version = data_version.clone();
+
+            let monitor = file_monitor::watch(path.as_str(), move || {
+                check_data_version(&state, &data_version);
+            });
+
+            monitors.extend(monitor);
+        }
+
+        check_data_version(state, &data_version);
+
+        // The window is destroyed when switching profiles
+        window.connect_destroy(move |_| {
+            for monitor in monitors.iter() {
+                monitor.cancel();
+            }
+        });
+    }
+
+    // Profile switcher, only shown when there is more than one profile
+    {
+        let builder = builder.clone();
+        let combo_profile: ComboBoxText = builder.get_object("combo_profile").unwrap();
+
+        let state = state.clone();
+        let app = window.get_application().unwrap();
+
+        {
+            let borrowed = state.borrow();
+
+            for profile in borrowed.config.profiles.iter() {
+                combo_profile.append(Some(profile.name.as_str()), profile.name.as_str());
+            }
+
+            combo_profile.set_active_id(borrowed.config.profile.as_str());
+            combo_profile.set_visible(borrowed.config.profiles.len() > 1);
+        }
+
+        combo_profile.connect_changed(move |combo| {
+            if let Some(name) = combo.get_active_id() {
+                if name != state.borrow().config.profile {
+                    switch_profile(&app, &state, name.as_str());
+                }
+            }
+        });
+    }
 
     // Events
 
+    // Reload the table when changing the sort column
+    {
+        let builder = builder.clone();
+        let table = table.clone();
+        let store_table: ListStore = builder.get_object("store_table").unwrap();
+
+        store_table.connect_sort_column_changed(move |_| {
+            refresh_table(&table);
+        });
+    }
+
+    // Load more receipts when reaching the end of the table
+    {
+        let builder = builder.clone();
+        let table = table.clone();
+        let scroll_table: ScrolledWindow = builder.get_object("scroll_table").unwrap();
+
+        let adjustment = scroll_table.get_vadjustment().unwrap();
+
+        adjustment.connect_value_changed(move |adjustment| {
+            let remaining = adjustment.get_upper()
+                - adjustment.get_value()
+                - adjustment.get_page_size();
+
+            if remaining < adjustment.get_page_size() {
+                load_page(&table);
+            }
+        });
+    }
+
     // Create a new record
     {
         let builder = builder.clone();
@@ -127,41 +369,94 @@ pub fn create_window(app: &Application,
     }
 
     // Delete selected record
-    // {
-    //     let builder = builder.clone();
-    //     let app = window.get_application().unwrap();
-    //     let state = state.clone();
+    //
+    // Receipts are moved to the trash, receipts in the trash are removed
+    // permanently after confirmation
+    {
+        let builder = builder.clone();
+        let window = window.clone();
+        let state = state.clone();
+
+        let btn_remove: Button = builder.get_object("btn_remove").unwrap();
+        let toggle_trash: ToggleButton = builder.get_object("toggle_trash").unwrap();
+
+        let table_selection: TreeSelection = builder.get_object("table_selection").unwrap();
 
-    //     let btn_remove: Button = builder.get_object("btn_remove").unwrap();
+        btn_remove.connect_clicked(move |_| {
+            let (model, iter) = table_selection.get_selected().unwrap();
+            let id = model.get_value(&iter, 0).get::<i32>().unwrap();
 
-    //     let table_selection: TreeSelection = builder.get_object("table_selection").unwrap();
+            let state = state.clone();
+            let builder = builder.clone();
+            let worker = state.borrow().worker().clone();
+
+            if !toggle_trash.get_active() {
+                worker.run(
+                    move |db| db.delete_receipt(id),
+                    move |result| {
+                        match result {
+                            Ok(1) => common::emit(&state, ReceiptEvent::Deleted(id)),
+                            Ok(_) => {},
+                            Err(_) => show_error(&builder, &gettext("Error removing the receipt"))
+                        }
+                    });
+
+                return;
+            }
 
-    //     btn_remove.connect_clicked(move |_| {
-    //         let (model, iter) = table_selection.get_selected().unwrap();
-    //         let id = model.get_value(&iter, 0).get::<i32>().unwrap();
+            let dialog = MessageDialog::new(
+                Some(&window),
+                gtk::DIALOG_MODAL,
+                MessageType::Question,
+                ButtonsType::YesNo,
+                tr_format("Permanently delete receipt {}?", &[id.to_string().as_str()]).as_str()
+            );
+
+            let response = dialog.run();
+            dialog.destroy();
+
+            if response == ResponseType::Yes.into() {
+                worker.run(
+                    move |db| db.purge_receipt(id),
+                    move |result| {
+                        match result {
+                            Ok(1) => common::emit(&state, ReceiptEvent::Deleted(id)),
+                            Ok(_) => {},
+                            Err(_) => show_error(&builder, &gettext("Error removing the receipt"))
+                        }
+                    });
+            }
+        });
+    }
 
-    //         // Modal dialog
-    //         // FIXME
-    //         let dialog = MessageDialog::new(
-    //             None,
-    //             DialogFlags::empty(),
-    //             MessageType::Question,
-    //             ButtonsType::YesNo,
-    //             format!("Delete receipt {}", id).as_str()
-    //         );
+    // Restore selected record from the trash
+    {
+        let builder = builder.clone();
+        let state = state.clone();
 
-    //         let response = dialog.run();
+        let btn_restore: Button = builder.get_object("btn_restore").unwrap();
 
-    //         if response == ResponseType::Yes.into() {
-    //             let status = db::delete_receipt(&state.borrow().db_path, id);
+        let table_selection: TreeSelection = builder.get_object("table_selection").unwrap();
 
-    //             //TODO check result, show info
+        btn_restore.connect_clicked(move |_| {
+            let (model, iter) = table_selection.get_selected().unwrap();
+            let id = model.get_value(&iter, 0).get::<i32>().unwrap();
 
-    //             // Refresh table
-    //             glib::idle_add(refresh_table);
-    //         }
-    //     });
-    // }
+            let state = state.clone();
+            let builder = builder.clone();
+            let worker = state.borrow().worker().clone();
+
+            worker.run(
+                move |db| db.restore_receipt(id),
+                move |result| {
+                    match result {
+                        Ok(1) => common::emit(&state, ReceiptEvent::Updated(id)),
+                        Ok(_) => {},
+                        Err(_) => show_error(&builder, &gettext("Error restoring the receipt"))
+                    }
+                });
+        });
+    }
 
     // Toggle search box
     {
@@ -180,6 +475,51 @@ pub fn create_window(app: &Application,
         });
     }
 
+    // Full-text search
+    {
+        let builder = builder.clone();
+        let table = table.clone();
+        let entry_search: SearchEntry = builder.get_object("entry_search").unwrap();
+
+        entry_search.connect_search_changed(move |_| {
+            refresh_table(&table);
+        });
+    }
+
+    // Toggle receipts expiring soon
+    {
+        let builder = builder.clone();
+        let table = table.clone();
+        let toggle_expiring: ToggleButton = builder.get_object("toggle_expiring").unwrap();
+
+        toggle_expiring.connect_toggled(move |_| {
+            refresh_table(&table);
+        });
+    }
+
+    // Toggle deleted receipts
+    {
+        let builder = builder.clone();
+        let table = table.clone();
+        let toggle_trash: ToggleButton = builder.get_object("toggle_trash").unwrap();
+
+        let btn_restore: Button = builder.get_object("btn_restore").unwrap();
+        let btn_remove: Button = builder.get_object("btn_remove").unwrap();
+
+        toggle_trash.connect_toggled(move |toggle| {
+            let trash = toggle.get_active();
+
+            btn_restore.set_visible(trash);
+            btn_remove.set_tooltip_text(Some(if trash {
+                gettext("Permanently delete selected receipt")
+            } else {
+                gettext("Remove selected receipt")
+            }.as_str()));
+
+            refresh_table(&table);
+        });
+    }
+
     // Toogle date chooser (from)
     {
         let builder = builder.clone();
@@ -218,29 +558,26 @@ pub fn create_window(app: &Application,
         let entry_date_from: Entry = builder.get_object("entry_date_from").unwrap();
         let entry_date_to: Entry = builder.get_object("entry_date_to").unwrap();
 
+        let date_format = state.borrow().config.date_format;
+        entry_date_from.set_placeholder_text(Some(date_format.placeholder().as_str()));
+        entry_date_to.set_placeholder_text(Some(date_format.placeholder().as_str()));
 
         calendar.connect_day_selected(move |calendar| {
             let (year, month, day) = calendar.get_date();
+            let text = date_format.format(NaiveDate::from_ymd(year as i32, month + 1, day));
 
             if popover_date.get_relative_to().unwrap().eq(&entry_date_from) {
-                entry_date_from.set_text(
-                    format!("{:02}/{:02}/{}", day, month+1, year).as_str()
-                );
+                entry_date_from.set_text(text.as_str());
 
             } else {
-                entry_date_to.set_text(
-                    format!("{:02}/{:02}/{}", day, month+1, year).as_str()
-                );
+                entry_date_to.set_text(text.as_str());
             }
         });
     }
 
-    // Clear search
+    // Filter receipts when the search fields change
     {
         let builder = builder.clone();
-        let btn_clear: Button = builder.get_object("btn_clear_search").unwrap();
-
-        let popover_menu: Popover = builder.get_object("popover_menu").unwrap();
         let entry_shop: Entry = builder.get_object("entry_shop").unwrap();
         let spin_cost_from: SpinButton = builder.get_object("spin_cost_from").unwrap();
         let spin_cost_to: SpinButton = builder.get_object("spin_cost_to").unwrap();
@@ -248,20 +585,261 @@ pub fn create_window(app: &Application,
         let entry_date_to: Entry = builder.get_object("entry_date_to").unwrap();
         let combo_type: ComboBox = builder.get_object("combo_type").unwrap();
         let combo_currency: ComboBox = builder.get_object("combo_currency").unwrap();
+        let check_recent: CheckButton = builder.get_object("check_recent").unwrap();
+
+        let t = table.clone();
+        entry_shop.connect_changed(move |_| refresh_table(&t));
+        let t = table.clone();
+        spin_cost_from.connect_value_changed(move |_| refresh_table(&t));
+        let t = table.clone();
+        spin_cost_to.connect_value_changed(move |_| refresh_table(&t));
+        let t = table.clone();
+        entry_date_from.connect_changed(move |_| refresh_table(&t));
+        let t = table.clone();
+        entry_date_to.connect_changed(move |_| refresh_table(&t));
+        let t = table.clone();
+        combo_type.connect_changed(move |_| refresh_table(&t));
+        let t = table.clone();
+        combo_currency.connect_changed(move |_| refresh_table(&t));
+        let t = table.clone();
+        check_recent.connect_toggled(move |_| refresh_table(&t));
+    }
+
+    // Clear search
+    {
+        let builder = builder.clone();
+        let btn_clear: Button = builder.get_object("btn_clear_search").unwrap();
+
+        let popover_menu: Popover = builder.get_object("popover_menu").unwrap();
+        let list_saved: ListBox = builder.get_object("list_saved").unwrap();
+
+        let date_format = state.borrow().config.date_format;
 
         btn_clear.connect_clicked(move |_| {
-            entry_shop.set_text("");
-            spin_cost_from.set_value(0.0);
-            spin_cost_to.set_value(0.0);
-            entry_date_from.set_text("");
-            entry_date_to.set_text("");
-            combo_type.set_active(-1);
-            combo_currency.set_active(-1);
+            apply_filter(&builder, &db::Filter::new(), date_format);
+            list_saved.unselect_all();
 
             // Hide menu
             popover_menu.hide();
+        });
+    }
+
+    // Apply a saved search
+    {
+        let builder = builder.clone();
+        let table = table.clone();
+        let list_saved: ListBox = builder.get_object("list_saved").unwrap();
+
+        let toggle_search: ToggleButton = builder.get_object("toggle_search").unwrap();
+        let entry_search_name: Entry = builder.get_object("entry_search_name").unwrap();
+
+        let date_format = state.borrow().config.date_format;
+
+        list_saved.connect_row_activated(move |_, row| {
+            // Searches are kept along with the rows that show them
+            let search = match table.borrow().saved_searches.get(row.get_index() as usize) {
+                Some(search) => search.clone(),
+                None => return
+            };
+
+            apply_filter(&builder, &search.filter, date_format);
+            entry_search_name.set_text(search.name.as_str());
+            toggle_search.set_active(true);
+        });
+    }
+
+    // Toggle remove search button
+    {
+        let builder = builder.clone();
+        let list_saved: ListBox = builder.get_object("list_saved").unwrap();
+
+        let btn_remove_search: Button = builder.get_object("btn_remove_search").unwrap();
+
+        list_saved.connect_row_selected(move |_, row| {
+            btn_remove_search.set_sensitive(row.is_some());
+        });
+    }
+
+    // Save current search
+    {
+        let builder = builder.clone();
+        let state = state.clone();
+        let table = table.clone();
+        let btn_save_search: Button = builder.get_object("btn_save_search").unwrap();
+
+        let entry_search_name: Entry = builder.get_object("entry_search_name").unwrap();
+
+        btn_save_search.connect_clicked(move |_| {
+            let name = entry_search_name.get_text().unwrap_or(String::new());
+
+            if name.trim().is_empty() {
+                entry_search_name.grab_focus();
+                return;
+            }
+
+            let filter = read_filter(&builder, state.borrow().config.date_format);
+            let name = name.trim().to_string();
+            let table = table.clone();
+
+            let builder = builder.clone();
+
+            state.borrow().worker().run(
+                move |db| db.save_search(name.as_str(), &filter),
+                move |status| {
+                    if status.is_err() {
+                        show_error(&builder, &gettext("Error saving the search"));
+                    }
+
+                    refresh_saved_searches(&table);
+                });
+        });
+    }
+
+    // Remove selected saved search
+    {
+        let builder = builder.clone();
+        let state = state.clone();
+        let table = table.clone();
+        let btn_remove_search: Button = builder.get_object("btn_remove_search").unwrap();
+
+        let list_saved: ListBox = builder.get_object("list_saved").unwrap();
+
+        btn_remove_search.connect_clicked(move |_| {
+            let row = match list_saved.get_selected_row() {
+                Some(r) => r,
+                None => return
+            };
+
+            let id = match table.borrow().saved_searches.get(row.get_index() as usize) {
+                Some(search) => search.id,
+                None => return
+            };
+
+            let table = table.clone();
+            let builder = builder.clone();
+
+            state.borrow().worker().run(
+                move |db| db.delete_saved_search(id),
+                move |status| {
+                    if status.is_err() {
+                        show_error(&builder, &gettext("Error removing the search"));
+                    }
+
+                    refresh_saved_searches(&table);
+                });
+        });
+    }
+
+    // Hide the information bar
+    {
+        let info_bar: InfoBar = builder.get_object("info_bar").unwrap();
+        let revealer: Revealer = builder.get_object("revealer_info").unwrap();
+
+        info_bar.connect_response(move |_, _| {
+            revealer.set_reveal_child(false);
+        });
+    }
+
+    // Show duplicates window
+    {
+        let builder = builder.clone();
+        let app = window.get_application().unwrap();
+        let state = state.clone();
+        let btn_duplicates: Button = builder.get_object("btn_duplicates").unwrap();
+        let popover_menu: Popover = builder.get_object("popover_menu").unwrap();
+
+        btn_duplicates.connect_clicked(move |_| {
+            popover_menu.hide();
+            duplicates_window::create_window(&app, &state);
+        });
+    }
+
+    // Import a bank or card statement
+    {
+        let builder = builder.clone();
+        let state = state.clone();
+        let window = window.clone();
+        let btn_import: Button = builder.get_object("btn_import").unwrap();
+        let popover_menu: Popover = builder.get_object("popover_menu").unwrap();
+
+        btn_import.connect_clicked(move |_| {
+            popover_menu.hide();
+
+            let path = match choose_statement(&window) {
+                Some(path) => path,
+                None => return
+            };
+
+            let state = state.clone();
+            let window = window.clone();
+            let date_format = state.borrow().config.date_format;
+            let worker = state.borrow().worker().clone();
+
+            worker.run(
+                move |db| {
+                    let statement = try!(statements::read(&path));
+                    let statuses = try!(statements::check(db, &statement).map_err(|e| e.to_string()));
+
+                    Ok((statement, statuses))
+                },
+                move |result: Result<_, String>| {
+                    let (statement, statuses) = match result {
+                        Ok(checked) => checked,
+                        Err(err) => {
+                            show_message(&window, MessageType::Error, &err);
+                            return;
+                        }
+                    };
+
+                    // Transactions are stored for reconciliation even if no receipts are created
+                    let chosen = match import_dialog::run(&window, &statement, &statuses, date_format) {
+                        Some(chosen) => chosen,
+                        None => return
+                    };
+
+                    let worker = state.borrow().worker().clone();
+
+                    worker.run(
+                        move |db| statements::import(db, &statement, &chosen),
+                        move |result| {
+                            match result {
+                                Ok(imported) => {
+                                    common::emit(&state, ReceiptEvent::External);
+
+                                    let mut text = tr_format("{} receipts imported, {} payments matched with receipts",
+                                                             &[imported.receipts.len().to_string().as_str(),
+                                                               imported.matched.to_string().as_str()]);
+
+                                    if !imported.rejected.is_empty() {
+                                        text.push_str("\n");
+                                        text.push_str(&tr_format("{} payments were not imported because they have no payee",
+                                                                 &[imported.rejected.len().to_string().as_str()]));
+                                    }
+
+                                    show_message(&window, MessageType::Info, &text);
+                                },
+
+                                Err(_) => {
+                                    show_message(&window, MessageType::Error,
+                                                 &gettext("Error importing the statement"));
+                                }
+                            }
+                        });
+                });
+        });
+    }
 
-            // TODO filter results
+    // Show reconciliation window
+    {
+        let builder = builder.clone();
+        let app = window.get_application().unwrap();
+        let state = state.clone();
+        let btn_reconcile: Button = builder.get_object("btn_reconcile").unwrap();
+        let popover_menu: Popover = builder.get_object("popover_menu").unwrap();
+
+        btn_reconcile.connect_clicked(move |_| {
+            popover_menu.hide();
+            reconcile_window::create_window(&app, &state);
         });
     }
 
@@ -292,22 +870,25 @@ pub fn create_window(app: &Application,
         });
     }
 
-    // Toggle remove and edit buttons
+    // Toggle remove, edit and restore buttons
     {
         let builder = builder.clone();
         let table_selection: TreeSelection = builder.get_object("table_selection").unwrap();
 
         let btn_remove: Button = builder.get_object("btn_remove").unwrap();
         let btn_edit: Button = builder.get_object("btn_edit").unwrap();
+        let btn_restore: Button = builder.get_object("btn_restore").unwrap();
 
         table_selection.connect_changed(move |selection| {
             if selection.count_selected_rows() == 1 {
                 btn_remove.set_sensitive(true);
                 btn_edit.set_sensitive(true);
+                btn_restore.set_sensitive(true);
 
             } else {
                 btn_remove.set_sensitive(false);
                 btn_edit.set_sensitive(false);
+                btn_restore.set_sensitive(false);
             }
         });
     }
@@ -362,36 +943,532 @@ pub fn create_window(app: &Application,
         });
     }
 
-    // Store builder and database path in thread local storage
+    // Fill table
+    refresh_table(&table);
+    refresh_saved_searches(&table);
+
+    window
+}
+
+/// Open the database of another profile
+///
+/// All windows of the current profile are closed and replaced by the main
+/// window of the new one.
+fn switch_profile(app: &Application, state: &Rc<RefCell<State>>, name: &str) {
     {
-        let builder = builder.clone();
-        let db_path = state.borrow().db_path.clone();
+        let mut borrowed = state.borrow_mut();
 
-        REFRESH.with(move |r| {
-            *r.borrow_mut() = Some((builder, db_path));
-        });
+        let db_path = match borrowed.config.profile_path(name) {
+            Some(path) => path.to_string(),
+            None => return
+        };
+
+        borrowed.config.profile = name.to_string();
+        borrowed.db_path = db_path;
+        borrowed.key = None;
+
+        config::save(&borrowed.config);
     }
 
-    window
+    reopen(app, state);
+}
+
+/// Replace the windows of the application with a new main window for the
+/// database in the state
+///
+/// The database is opened again by the new window.
+pub fn reopen(app: &Application, state: &Rc<RefCell<State>>) {
+    let windows = app.get_windows();
+
+    state.borrow_mut().worker = None;
+
+    // Open the new window first, the application quits when the last one is closed
+    let window = ::gui::open_profile(app, state);
+
+    window.show_all();
+    app.add_window(&window);
+
+    for old in windows.iter() {
+        old.close();
+    }
+}
+
+/// Obtain a table column by its name in `COLUMNS`
+fn get_column(builder: &Builder, name: &str) -> TreeViewColumn {
+    builder.get_object(format!("column_{}", name).as_str()).unwrap()
+}
+
+/// Set the order and visibility of the table columns
+fn apply_column_layout(builder: &Builder, layout: &ColumnLayout) {
+    let view_table: TreeView = builder.get_object("view_table").unwrap();
+
+    let mut previous: Option<TreeViewColumn> = None;
+
+    for name in layout.order.iter() {
+        let column = get_column(builder, name);
+
+        view_table.move_column_after(&column, previous.as_ref());
+        column.set_visible(!layout.hidden.contains(name));
+
+        previous = Some(column);
+    }
+}
+
+/// Read the search filter from the search widgets
+fn read_filter(builder: &Builder, date_format: DateFormat) -> db::Filter {
+    let entry_search: SearchEntry = builder.get_object("entry_search").unwrap();
+    let toggle_expiring: ToggleButton = builder.get_object("toggle_expiring").unwrap();
+    let toggle_trash: ToggleButton = builder.get_object("toggle_trash").unwrap();
+    let entry_shop: Entry = builder.get_object("entry_shop").unwrap();
+    let spin_cost_from: SpinButton = builder.get_object("spin_cost_from").unwrap();
+    let spin_cost_to: SpinButton = builder.get_object("spin_cost_to").unwrap();
+    let entry_date_from: Entry = builder.get_object("entry_date_from").unwrap();
+    let entry_date_to: Entry = builder.get_object("entry_date_to").unwrap();
+    let combo_type: ComboBox = builder.get_object("combo_type").unwrap();
+    let combo_currency: ComboBox = builder.get_object("combo_currency").unwrap();
+    let check_recent: CheckButton = builder.get_object("check_recent").unwrap();
+
+    let mut filter = db::Filter::new();
+
+    filter.text = entry_search.get_text().unwrap_or(String::new());
+    filter.shop = entry_shop.get_text().unwrap_or(String::new());
+    filter.cost_from = spin_cost_from.get_value();
+    filter.cost_to = spin_cost_to.get_value();
+
+    // Incomplete dates are ignored
+    let date_from = entry_date_from.get_text().unwrap_or(String::new());
+    filter.date_from = date_format.parse(date_from.as_str());
+
+    let date_to = entry_date_to.get_text().unwrap_or(String::new());
+    filter.date_to = date_format.parse(date_to.as_str());
+
+    if let Some(iter) = combo_type.get_active_iter() {
+        let model = combo_type.get_model().unwrap();
+        filter.payment_type = model.get_value(&iter, 0).get::<String>().unwrap();
+    }
+
+    if let Some(iter) = combo_currency.get_active_iter() {
+        let model = combo_currency.get_model().unwrap();
+        filter.currency = model.get_value(&iter, 0).get::<String>().unwrap();
+    }
+
+    if toggle_expiring.get_active() {
+        filter.expiring_days = EXPIRY_DAYS;
+    }
+
+    if check_recent.get_active() {
+        filter.entered_days = RECENT_DAYS;
+    }
+
+    filter.deleted = toggle_trash.get_active();
+
+    filter
+}
+
+/// Show a search filter in the search widgets
+fn apply_filter(builder: &Builder, filter: &db::Filter, date_format: DateFormat) {
+    let entry_search: SearchEntry = builder.get_object("entry_search").unwrap();
+    let toggle_expiring: ToggleButton = builder.get_object("toggle_expiring").unwrap();
+    let toggle_trash: ToggleButton = builder.get_object("toggle_trash").unwrap();
+    let entry_shop: Entry = builder.get_object("entry_shop").unwrap();
+    let spin_cost_from: SpinButton = builder.get_object("spin_cost_from").unwrap();
+    let spin_cost_to: SpinButton = builder.get_object("spin_cost_to").unwrap();
+    let entry_date_from: Entry = builder.get_object("entry_date_from").unwrap();
+    let entry_date_to: Entry = builder.get_object("entry_date_to").unwrap();
+    let combo_type: ComboBox = builder.get_object("combo_type").unwrap();
+    let combo_currency: ComboBox = builder.get_object("combo_currency").unwrap();
+    let check_recent: CheckButton = builder.get_object("check_recent").unwrap();
+
+    entry_search.set_text(filter.text.as_str());
+    toggle_expiring.set_active(filter.expiring_days > 0);
+    toggle_trash.set_active(filter.deleted);
+    entry_shop.set_text(filter.shop.as_str());
+    spin_cost_from.set_value(filter.cost_from);
+    spin_cost_to.set_value(filter.cost_to);
+
+    entry_date_from.set_text(
+        filter.date_from.map_or(String::new(), |d| date_format.format(d)).as_str()
+    );
+
+    entry_date_to.set_text(
+        filter.date_to.map_or(String::new(), |d| date_format.format(d)).as_str()
+    );
+
+    let payment_type = filter.payment_type.clone();
+    combo_type.set_active(-1);
+    set_active_combo!(combo_type, PAYMENTS, payment_type);
+
+    let currency = filter.currency.clone();
+    combo_currency.set_active(-1);
+    set_active_combo!(combo_currency, CURRENCIES, currency);
+
+    check_recent.set_active(filter.entered_days > 0);
+}
+
+/// Reload the saved searches sidebar along with their receipt count
+///
+/// Counting runs a query per saved search, so it is only done when receipts
+/// or saved searches change, not when the search in the window changes.
+fn refresh_saved_searches(table: &Rc<RefCell<Table>>) {
+    let borrowed = table.borrow();
+    let builder = borrowed.builder.clone();
+
+    let table = table.clone();
+
+    borrowed.worker.run(
+        |db| {
+            db.get_saved_searches().map(|searches| {
+                let counts: Vec<i32> = searches.iter()
+                    .map(|search| db.count_receipts(&search.filter).unwrap_or(0))
+                    .collect();
+
+                (searches, counts)
+            })
+        },
+        move |result| {
+            let (searches, counts) = match result {
+                Ok(loaded) => loaded,
+                Err(_) => {
+                    show_error(&builder, &gettext("Error loading saved searches"));
+                    return;
+                }
+            };
+
+            let list_saved: ListBox = builder.get_object("list_saved").unwrap();
+
+            // Keep the selected search selected
+            let selected = list_saved.get_selected_row()
+                .and_then(|row| table.borrow().saved_searches.get(row.get_index() as usize).map(|s| s.id));
+
+            for child in list_saved.get_children() {
+                list_saved.remove(&child);
+            }
+
+            for (index, (search, count)) in searches.iter().zip(counts.iter()).enumerate() {
+                let row_box = Box::new(Orientation::Horizontal, 10);
+                row_box.set_border_width(5);
+
+                let lbl_name = Label::new(Some(search.name.as_str()));
+                lbl_name.set_halign(Align::Start);
+                row_box.pack_start(&lbl_name, true, true, 0);
+
+                let lbl_count = Label::new(Some(count.to_string().as_str()));
+                row_box.pack_end(&lbl_count, false, false, 0);
+
+                list_saved.insert(&row_box, index as i32);
+            }
+
+            list_saved.show_all();
+
+            if let Some(index) = selected.and_then(|id| searches.iter().position(|s| s.id == id)) {
+                if let Some(row) = list_saved.get_row_at_index(index as i32) {
+                    list_saved.select_row(&row);
+                }
+            }
+
+            table.borrow_mut().saved_searches = searches;
+        });
 }
 
 /// Refresh the receipt table
 ///
-/// This is usually done when adding or editing a receipt
-pub fn refresh_table() -> glib::Continue {
-    REFRESH.with(move |r| {
-        if let Some((ref builder, ref db_path)) = *r.borrow() {
-            let store_table: ListStore = builder.get_object("store_table").unwrap();
-
-            let receipts = db::get_all_receipts(&db_path).unwrap();
-            fill_store!(table => store_table, receipts);
+/// Only the first page of receipts is loaded, the rest are loaded when
+/// scrolling down.
+fn refresh_table(table: &Rc<RefCell<Table>>) {
+    {
+        let mut borrowed = table.borrow_mut();
+
+        let store_table: ListStore = borrowed.builder.get_object("store_table").unwrap();
+        let column_match: TreeViewColumn = borrowed.builder.get_object("column_match").unwrap();
+
+        let filter = read_filter(&borrowed.builder, borrowed.date_format);
+        column_match.set_visible(!filter.text.trim().is_empty());
+
+        store_table.clear();
+
+        // Pages requested before the refresh are discarded
+        borrowed.generation += 1;
+        borrowed.last = None;
+        borrowed.loaded.clear();
+        borrowed.complete = false;
+        borrowed.loading = false;
+    }
+
+    load_page(table);
+}
+
+/// Update a single receipt in the table after it was created, changed or deleted
+///
+/// The receipt is added, updated or removed depending on whether it still
+/// exists and matches the current search.
+fn update_row(table: &Rc<RefCell<Table>>, id: i32) {
+    let borrowed = table.borrow();
+    let filter = read_filter(&borrowed.builder, borrowed.date_format);
+    let table = table.clone();
+
+    borrowed.worker.run(
+        move |db| db.find_receipt(&filter, id),
+        move |result| {
+            let mut table = table.borrow_mut();
+            let store_table: ListStore = table.builder.get_object("store_table").unwrap();
+
+            let result = result.unwrap_or(None);
+
+            // Find row in the table
+            let mut row = None;
+
+            if let Some(iter) = store_table.get_iter_first() {
+                loop {
+                    if store_table.get_value(&iter, 0).get::<i32>() == Some(id) {
+                        row = Some(iter);
+                        break;
+                    }
+
+                    if !store_table.iter_next(&iter) {
+                        break;
+                    }
+                }
+            }
+
+            match (row, result) {
+                (Some(iter), Some(val)) => {
+                    fill_store!(row => store_table, iter, val, table.date_format);
+                },
+
+                (Some(iter), None) => {
+                    store_table.remove(&iter);
+                    table.loaded.remove(&id);
+                },
+
+                (None, Some(val)) => {
+                    // Pages loaded later will skip the receipt
+                    let iter = store_table.append();
+                    fill_store!(row => store_table, iter, val, table.date_format);
+                    table.loaded.insert(id);
+                },
+
+                (None, None) => {}
+            }
+        });
+}
+
+/// Load the next page of receipts in the table, if any
+///
+/// Nothing is done while a page is being loaded or the table is being
+/// updated.
+fn load_page(table: &Rc<RefCell<Table>>) {
+    let mut borrowed = match table.try_borrow_mut() {
+        Ok(b) => b,
+        Err(_) => return
+    };
+
+    if borrowed.complete || borrowed.loading {
+        return;
+    }
+
+    let store_table: ListStore = borrowed.builder.get_object("store_table").unwrap();
+
+    let filter = read_filter(&borrowed.builder, borrowed.date_format);
+    let sort = read_sort(&store_table);
+    let last = borrowed.last.clone();
+    let generation = borrowed.generation;
+
+    borrowed.loading = true;
+
+    let table = table.clone();
+
+    borrowed.worker.run(
+        move |db| db.find_receipts_page(&filter, &sort, last.as_ref(), PAGE_SIZE),
+        move |page| {
+            let mut table = table.borrow_mut();
+
+            // Table was refreshed in the meantime
+            if table.generation != generation {
+                return;
+            }
+
+            table.loading = false;
+
+            let page = match page {
+                Ok(page) => page,
+                Err(_) => {
+                    show_error(&table.builder, &gettext("Error loading receipts"));
+                    return;
+                }
+            };
+
+            let store_table: ListStore = table.builder.get_object("store_table").unwrap();
+
+            table.complete = page.len() < PAGE_SIZE as usize;
+
+            if let Some(val) = page.last() {
+                table.last = Some(val.receipt.clone());
+            }
+
+            for val in page.iter() {
+                if table.loaded.insert(val.receipt.id) {
+                    let iter = store_table.append();
+                    fill_store!(row => store_table, iter, val, table.date_format);
+                }
+            }
+        });
+}
+
+/// Obtain the order of the receipts from the sort column of the table
+fn read_sort(store_table: &ListStore) -> db::Sort {
+    let (column, order) = match store_table.get_sort_column_id() {
+        Some((SortColumn::Index(column), order)) => (column, order),
+        _ => (10, SortType::Descending)
+    };
+
+    let key = match column {
+        0 => db::SortKey::Id,
+        1 => db::SortKey::Shop,
+        2 => db::SortKey::Amount,
+        3 => db::SortKey::Currency,
+        4 => db::SortKey::PaymentType,
+        11 => db::SortKey::ReturnBy,
+        12 => db::SortKey::WarrantyUntil,
+        13 => db::SortKey::Description,
+        15 => db::SortKey::CreatedAt,
+        _ => db::SortKey::DatePaid
+    };
+
+    db::Sort {
+        key: key,
+        descending: order == SortType::Descending
+    }
+}
+
+/// Notify subscribers if another process changed the database
+///
+/// The first check only stores the current data version.
+fn check_data_version(state: &Rc<RefCell<State>>, last: &Rc<Cell<Option<i32>>>) {
+    let state = state.clone();
+    let last = last.clone();
+    let worker = state.borrow().worker().clone();
+
+    worker.run(|db| db.data_version(), move |version| {
+        let version = match version {
+            Ok(v) => Some(v),
+            Err(_) => return
+        };
+
+        let previous = last.get();
+        last.set(version);
+
+        if previous.is_some() && previous != version {
+            common::emit(&state, ReceiptEvent::External);
+        }
+    });
+}
+
+/// Notify the user of return and warranty periods that are about to end
+pub fn notify_expiring(app: &Application, worker: &Worker, date_format: DateFormat) {
+    let app = app.clone();
+
+    worker.run(|db| db.get_expiring_receipts(EXPIRY_DAYS), move |receipts| {
+        let receipts = match receipts {
+            Ok(r) => r,
+            Err(_) => return
+        };
+
+        let today = Local::today().naive_local();
+        let limit = today + Duration::days(EXPIRY_DAYS);
+        let expiring = |date: Option<NaiveDate>| date.and_then(|d| if d >= today && d <= limit { Some(d) } else { None });
+
+        for receipt in receipts.iter() {
+            let mut periods = Vec::new();
+
+            // Only the periods that end soon are mentioned
+            if let Some(return_by) = expiring(receipt.return_by) {
+                periods.push(tr_format("return by {}", &[date_format.format(return_by).as_str()]));
+            }
+
+            if let Some(warranty_until) = expiring(receipt.warranty_until) {
+                periods.push(tr_format("warranty until {}",
+                                       &[date_format.format(warranty_until).as_str()]));
+            }
+
+            notification::send(
+                &app,
+                format!("expiring-{}", receipt.id).as_str(),
+                tr_format("Receipt from {} expiring soon", &[receipt.shop.as_str()]).as_str(),
+                periods.join(", ").as_str()
+            );
         }
     });
+}
+
+/// Receipt table state
+struct Table {
+    builder: Builder,
+    worker: Worker,
+    // Last receipt loaded, used to fetch the next page
+    last: Option<Receipt>,
+    // IDs of the receipts in the table
+    loaded: HashSet<i32>,
+    // Whether all pages have been loaded
+    complete: bool,
+    // Whether a page is being loaded
+    loading: bool,
+    // Incremented on every refresh to discard pages of a previous refresh
+    generation: u32,
+    date_format: DateFormat,
+    // Saved searches in the order of their rows in the sidebar
+    saved_searches: Vec<db::SavedSearch>
+}
 
-    glib::Continue(false)
+/// Ask the user for a statement file to import
+fn choose_statement(parent: &ApplicationWindow) -> Option<PathBuf> {
+    let dialog = FileChooserDialog::new(
+        Some(gettext("Import statement").as_str()),
+        Some(parent),
+        FileChooserAction::Open
+    );
+
+    dialog.add_button(gettext("Cancel").as_str(), ResponseType::Cancel.into());
+    dialog.add_button(gettext("Open").as_str(), ResponseType::Accept.into());
+
+    let filter = FileFilter::new();
+    filter.set_name(gettext("Statements (OFX, QFX, CAMT.053, MT940)").as_str());
+
+    for pattern in ["*.ofx", "*.OFX", "*.qfx", "*.QFX", "*.xml", "*.XML", "*.sta", "*.STA",
+                    "*.mt940", "*.940", "*.txt"].iter() {
+        filter.add_pattern(pattern);
+    }
+
+    dialog.add_filter(&filter);
+
+    let path = if dialog.run() == ResponseType::Accept.into() {
+        dialog.get_filename()
+    } else {
+        None
+    };
+
+    dialog.destroy();
+    path
+}
+
+/// Show a message in a dialog
+pub fn show_message(parent: &ApplicationWindow, message_type: MessageType, text: &str) {
+    let dialog = MessageDialog::new(
+        Some(parent),
+        gtk::DIALOG_MODAL,
+        message_type,
+        ButtonsType::Close,
+        text
+    );
+
+    dialog.run();
+    dialog.destroy();
 }
 
-// Keep builder in thread local storage to update the table
-thread_local!(
-    static REFRESH: RefCell<Option<(Builder, String)>> = RefCell::new(None)
-);
+/// Show an error in the information bar of the window
+fn show_error(builder: &Builder, text: &str) {
+    let revealer: Revealer = builder.get_object("revealer_info").unwrap();
+    let lbl_info: Label = builder.get_object("lbl_info").unwrap();
+
+    lbl_info.set_text(text);
+    revealer.set_reveal_child(true);
+}
@@ -6,23 +6,38 @@ macro_rules! fill_store {
         }
     };
 
-    (table => $list:ident, $values:ident) => {
+    (table => $list:ident, $values:ident, $format:expr) => {
         $list.clear();
 
-        for (index, val) in $values.iter().enumerate() {
-            $list.insert_with_values(
-                None,
-                &[0, 1, 2, 3, 4, 5],
-                &[
-                    &val.id,
-                    &val.shop,
-                    &val.amount,
-                    &val.currency,
-                    &val.payment_type,
-                    &val.date_paid.to_string()
-                ]);
+        for val in $values.iter() {
+            let iter = $list.append();
+            fill_store!(row => $list, iter, val, $format);
         }
     };
+
+    (row => $list:ident, $iter:ident, $val:ident, $format:expr) => {
+        $list.set(
+            &$iter,
+            &[0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15],
+            &[
+                &$val.receipt.id,
+                &$val.receipt.shop,
+                &$val.receipt.amount,
+                &$val.receipt.currency,
+                &$val.receipt.payment_type,
+                &$format.format($val.receipt.date_paid),
+                &$val.receipt.return_by.map_or(String::new(), |d| $format.format(d)),
+                &$val.receipt.warranty_until.map_or(String::new(), |d| $format.format(d)),
+                &::gui::highlight_markup(&$val.highlight),
+                &::i18n::format_amount($val.receipt.amount),
+                &::gui::date_sort_key(Some($val.receipt.date_paid)),
+                &::gui::date_sort_key($val.receipt.return_by),
+                &::gui::date_sort_key($val.receipt.warranty_until),
+                &$val.receipt.description.lines().next().unwrap_or("").to_string(),
+                &$format.format_datetime($val.receipt.created_at),
+                &::gui::datetime_sort_key($val.receipt.created_at)
+            ]);
+    };
 }
 
 /// Set combobox active element based on the value
@@ -39,6 +54,283 @@ macro_rules! set_active_combo {
     }
 }
 
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
+
+use chrono::{Datelike, Local, NaiveDate, NaiveDateTime};
+use gettextrs::gettext;
+use glib::Continue;
+use gtk;
+use gtk::prelude::*;
+use gtk::{Application, ButtonsType, FileChooserAction, FileChooserDialog, FileFilter, MessageDialog,
+          MessageType, ResponseType, Window};
+
+use backup;
+use common::State;
+use dates::DateFormat;
+use db;
+use encryption;
+use i18n;
+use i18n::tr_format;
+use migrations;
+use worker::Worker;
+
+/// Sort key of a date in the receipt table
+///
+/// Missing dates are sorted first.
+pub fn date_sort_key(date: Option<NaiveDate>) -> i32 {
+    date.map_or(0, |d| d.num_days_from_ce())
+}
+
+/// Sort key of a date and time in the receipt table
+///
+/// Milliseconds since the epoch, so receipts entered in the same second
+/// keep their order.
+pub fn datetime_sort_key(datetime: NaiveDateTime) -> i64 {
+    datetime.timestamp() * 1000 + datetime.timestamp_subsec_millis() as i64
+}
+
+/// Convert a search highlight into Pango markup
+///
+/// Text is escaped and matched terms are shown in bold.
+pub fn highlight_markup(text: &str) -> String {
+    let mut markup = String::with_capacity(text.len());
+
+    for c in text.chars() {
+        match c {
+            '&' => markup.push_str("&amp;"),
+            '<' => markup.push_str("&lt;"),
+            '>' => markup.push_str("&gt;"),
+            '\'' => markup.push_str("&apos;"),
+            '"' => markup.push_str("&quot;"),
+            db::MATCH_START => markup.push_str("<b>"),
+            db::MATCH_END => markup.push_str("</b>"),
+            _ => markup.push(c)
+        }
+    }
+
+    markup
+}
+
+/// Field names and values of a receipt as shown to the user
+pub fn receipt_fields(receipt: &db::Receipt, date_format: DateFormat) -> Vec<(String, String)> {
+    let format_date = |date: Option<NaiveDate>| {
+        date.map(|d| date_format.format(d)).unwrap_or(String::new())
+    };
+
+    vec![
+        (gettext("Shop"), receipt.shop.clone()),
+        (gettext("Cost"), i18n::format_money(receipt.amount, receipt.currency.as_str())),
+        (gettext("Type"), receipt.payment_type.clone()),
+        (gettext("Date"), date_format.format(receipt.date_paid)),
+        (gettext("Return by"), format_date(receipt.return_by)),
+        (gettext("Warranty until"), format_date(receipt.warranty_until)),
+        (gettext("Description"), receipt.description.clone())
+    ]
+}
+
+/// Open the database of the current profile and create its main window
+///
+/// The first-run assistant is returned instead when the profile has no
+/// database yet, and the passphrase prompt when the database is encrypted and
+/// its passphrase is not known.
+pub fn open_profile(app: &Application, state: &Rc<RefCell<State>>) -> Window {
+    let db_path = state.borrow().db_path.clone();
+
+    if db_path.is_empty() {
+        // Ask for database path
+        return first_run::create_window(app, state);
+    }
+
+    let key = state.borrow().key.clone();
+
+    if key.is_none() && encryption::is_encrypted(db_path.as_str()) {
+        if !encryption::is_available() {
+            let text = encryption::unavailable_message(db_path.as_str());
+            return open_failed(app, state, text.as_str());
+        }
+
+        return lock::create_window(app, state);
+    }
+
+    // Open and migrate database, then hand it to the worker thread
+    let database = match db::Database::open_with_key(db_path.as_str(),
+                                                     key.as_ref().map(|k| k.as_str())) {
+        Ok(database) => database,
+        Err(e) => {
+            let text = tr_format("{} could not be opened: {}",
+                                 &[db_path.as_str(), e.to_string().as_str()]);
+            return open_failed(app, state, text.as_str());
+        }
+    };
+
+    if migrations::migrate(&database) < 0 {
+        let text = tr_format("{} could not be updated to the current version",
+                             &[db_path.as_str()]);
+        return open_failed(app, state, text.as_str());
+    }
+
+    let worker = Worker::start(database);
+
+    // Empty the trash of old receipts
+    let retention = state.borrow().config.trash_retention_days;
+    worker.run(move |db| db.purge_deleted(retention), |_| {});
+    state.borrow_mut().worker = Some(worker);
+    state.borrow_mut().session += 1;
+
+    schedule_backups(app, state);
+    schedule_expiry_notifications(app, state);
+
+    main_window::create_window(app, state).upcast()
+}
+
+/// Tell that the database of the current profile could not be opened
+///
+/// Returns the first-run assistant so that another database can be chosen.
+fn open_failed(app: &Application, state: &Rc<RefCell<State>>, text: &str) -> Window {
+    let dialog = MessageDialog::new(None::<&Window>, gtk::DIALOG_MODAL, MessageType::Error,
+                                    ButtonsType::Close, text);
+    dialog.run();
+    dialog.destroy();
+
+    state.borrow_mut().key = None;
+
+    first_run::create_window(app, state)
+}
+
+/// Back up the database of the current profile whenever a backup is due
+///
+/// Checked when the profile is opened and every hour after that, until a
+/// database is opened again.
+fn schedule_backups(app: &Application, state: &Rc<RefCell<State>>) {
+    let app = app.clone();
+    let state = state.clone();
+    let session = state.borrow().session;
+
+    backup_if_due(&app, &state);
+
+    gtk::timeout_add_seconds(3600, move || {
+        if state.borrow().session != session {
+            return Continue(false);
+        }
+
+        backup_if_due(&app, &state);
+
+        Continue(true)
+    });
+}
+
+/// Notify about receipts of the current profile that expire soon
+///
+/// Notifications are sent when the profile is opened and once a day after
+/// that, until a database is opened again.
+fn schedule_expiry_notifications(app: &Application, state: &Rc<RefCell<State>>) {
+    let app = app.clone();
+    let state = state.clone();
+    let session = state.borrow().session;
+    let notified_on = Cell::new(Local::today().naive_local());
+
+    main_window::notify_expiring(&app, state.borrow().worker(), state.borrow().config.date_format);
+
+    gtk::timeout_add_seconds(3600, move || {
+        if state.borrow().session != session {
+            return Continue(false);
+        }
+
+        let today = Local::today().naive_local();
+
+        if notified_on.get() != today {
+            notified_on.set(today);
+
+            let borrowed = state.borrow();
+            main_window::notify_expiring(&app, borrowed.worker(), borrowed.config.date_format);
+        }
+
+        Continue(true)
+    });
+}
+
+/// Back up the database of the current profile if automatic backups are
+/// enabled and the last one is too old
+///
+/// A desktop notification is sent if the backup fails.
+fn backup_if_due(app: &Application, state: &Rc<RefCell<State>>) {
+    let app = app.clone();
+    let borrowed = state.borrow();
+    let settings = borrowed.config.backup.clone();
+    let db_path = borrowed.db_path.clone();
+
+    if !settings.enabled || !backup::is_due(settings.directory.as_str(), db_path.as_str()) {
+        return;
+    }
+
+    borrowed.worker().run(
+        move |db| {
+            let dir = settings.directory.as_str();
+
+            backup::create(db, db_path.as_str(), dir)
+                .and_then(|_| backup::rotate(dir, db_path.as_str(), &settings))
+        },
+        move |result| {
+            if let Err(err) = result {
+                notification::send(&app, "backup-failed", gettext("Backup failed").as_str(),
+                                   err.as_str());
+            }
+        });
+}
+
+/// Ask the user for a database file
+///
+/// `action` is `Save` to choose a new file and `Open` for an existing one.
+/// Returns the path of the chosen file, if any.
+pub fn choose_database<W: IsA<Window>>(parent: &W, action: FileChooserAction) -> Option<String> {
+    let (title, accept) = match action {
+        FileChooserAction::Save => (gettext("Create database"), gettext("Create")),
+        _ => (gettext("Open database"), gettext("Open"))
+    };
+
+    let dialog = FileChooserDialog::new(Some(title.as_str()), Some(parent), action);
+    dialog.add_button(gettext("Cancel").as_str(), ResponseType::Cancel.into());
+    dialog.add_button(accept.as_str(), ResponseType::Accept.into());
+
+    let filter = FileFilter::new();
+    filter.set_name(gettext("Databases").as_str());
+    filter.add_pattern("*.db");
+    filter.add_pattern("*.sqlite");
+    dialog.add_filter(&filter);
+
+    let all = FileFilter::new();
+    all.set_name(gettext("All files").as_str());
+    all.add_pattern("*");
+    dialog.add_filter(&all);
+
+    if action == FileChooserAction::Save {
+        dialog.set_current_name("receipts.db");
+    }
+
+    let response = dialog.run();
+
+    let path = if response == ResponseType::Accept.into() {
+        dialog.get_filename().and_then(|p| p.to_str().map(|p| p.to_string()))
+    } else {
+        None
+    };
+
+    dialog.destroy();
+
+    path
+}
+
 pub mod main_window;
 pub mod edit_window;
 pub mod settings_window;
+pub mod notification;
+pub mod file_monitor;
+pub mod conflict_dialog;
+pub mod doctor_dialog;
+pub mod first_run;
+pub mod lock;
+pub mod passphrase_dialog;
+pub mod duplicates_window;
+pub mod import_dialog;
+pub mod reconcile_window;
@@ -0,0 +1,54 @@
+// MIT License
+//
+// Copyright (c) 2016 Rafael Medina García <rafamedgar@gmail.com>
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+/// Desktop notifications
+///
+/// The gio bindings do not wrap `GNotification` yet, so notifications are
+/// sent through the FFI functions directly.
+
+use gio;
+use gio_ffi;
+use glib::object::Cast;
+use glib::translate::*;
+use gobject_ffi;
+use gtk::Application;
+
+
+/// Send a desktop notification
+///
+/// Sending a notification with the same ID replaces the previous one.
+pub fn send(app: &Application, id: &str, title: &str, body: &str) {
+    let app: gio::Application = app.clone().upcast();
+
+    unsafe {
+        let notification = gio_ffi::g_notification_new(title.to_glib_none().0);
+        gio_ffi::g_notification_set_body(notification, body.to_glib_none().0);
+
+        gio_ffi::g_application_send_notification(
+            app.to_glib_none().0,
+            id.to_glib_none().0,
+            notification
+        );
+
+        gobject_ffi::g_object_unref(notification as *mut gobject_ffi::GObject);
+    }
+}
@@ -0,0 +1,115 @@
+// MIT License
+//
+// Copyright (c) 2016 Rafael Medina García <rafamedgar@gmail.com>
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+/// Dialog to change the passphrase of the database
+
+use gettextrs::gettext;
+use gtk;
+use gtk::prelude::*;
+use gtk::{Align, ApplicationWindow, Dialog, Entry, Grid, Label, ResponseType};
+
+
+/// Ask the user for a new passphrase
+///
+/// `current` is the passphrase of the database, which has to be entered
+/// again, or `None` if it is not encrypted. Returns the new passphrase,
+/// empty to stop encrypting the database, or `None` if cancelled.
+pub fn run(parent: &ApplicationWindow, current: Option<&str>) -> Option<String> {
+    let dialog = Dialog::new_with_buttons(
+        Some(gettext("Change passphrase").as_str()),
+        Some(parent),
+        gtk::DIALOG_MODAL,
+        &[
+            (gettext("Cancel").as_str(), ResponseType::Cancel.into()),
+            (gettext("Change").as_str(), ResponseType::Accept.into())
+        ]
+    );
+
+    let grid = Grid::new();
+    grid.set_border_width(10);
+    grid.set_row_spacing(5);
+    grid.set_column_spacing(15);
+
+    let lbl_message = Label::new(Some(
+        gettext("Leave the new passphrase empty to store the database unencrypted.").as_str()));
+    lbl_message.set_halign(Align::Start);
+    grid.attach(&lbl_message, 0, 0, 2, 1);
+
+    let entry_current = password_entry();
+    let entry_new = password_entry();
+    let entry_confirm = password_entry();
+
+    let mut rows = vec![(gettext("New passphrase"), &entry_new),
+                        (gettext("Confirm passphrase"), &entry_confirm)];
+
+    if current.is_some() {
+        rows.insert(0, (gettext("Current passphrase"), &entry_current));
+    }
+
+    for (row, &(ref name, entry)) in rows.iter().enumerate() {
+        let lbl_name = Label::new(Some(name.as_str()));
+        lbl_name.set_halign(Align::Start);
+        grid.attach(&lbl_name, 0, row as i32 + 1, 1, 1);
+        grid.attach(entry, 1, row as i32 + 1, 1, 1);
+    }
+
+    let lbl_error = Label::new(None);
+    lbl_error.set_halign(Align::Start);
+    grid.attach(&lbl_error, 0, rows.len() as i32 + 1, 2, 1);
+
+    dialog.get_content_area().add(&grid);
+    dialog.set_default_response(ResponseType::Accept.into());
+    dialog.show_all();
+
+    let mut passphrase = None;
+
+    while dialog.run() == ResponseType::Accept.into() {
+        let text = |entry: &Entry| entry.get_text().unwrap_or(String::new());
+
+        if current.map_or(false, |c| c != text(&entry_current)) {
+            lbl_error.set_text(gettext("Wrong passphrase").as_str());
+            continue;
+        }
+
+        if text(&entry_new) != text(&entry_confirm) {
+            lbl_error.set_text(gettext("The passphrases do not match").as_str());
+            continue;
+        }
+
+        passphrase = Some(text(&entry_new));
+        break;
+    }
+
+    dialog.destroy();
+
+    passphrase
+}
+
+/// Entry that hides what is typed
+fn password_entry() -> Entry {
+    let entry = Entry::new();
+    entry.set_visibility(false);
+    entry.set_activates_default(true);
+    entry.set_hexpand(true);
+
+    entry
+}
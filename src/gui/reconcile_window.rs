@@ -0,0 +1,347 @@
+// MIT License
+//
+// Copyright (c) 2016 Rafael Medina García <rafamedgar@gmail.com>
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+/// Window to reconcile receipts with bank and card payments
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use chrono::NaiveDate;
+use gettextrs::gettext;
+use gtk::prelude::*;
+use gtk::{
+    Align,
+    Application,
+    ApplicationWindow,
+    Box,
+    Button,
+    Frame,
+    Label,
+    ListBox,
+    MessageType,
+    Orientation,
+    ScrolledWindow,
+    WindowPosition
+};
+use rusqlite;
+
+use common;
+use common::{ReceiptEvent, State};
+use dates::DateFormat;
+use db::{BankTransaction, Receipt};
+use gui::lock;
+use gui::main_window;
+use i18n;
+use reconcile;
+use statements;
+
+
+/// Unmatched payments and receipts shown in the window
+struct Unmatched {
+    transactions: Vec<BankTransaction>,
+    receipts: Vec<Receipt>
+}
+
+/// Create the reconciliation window
+///
+/// Payments without a receipt are listed next to card and transfer receipts
+/// without a payment. A payment and a receipt can be matched by selecting both, and
+/// payments that need no receipt can be ignored.
+pub fn create_window(app: &Application, state: &Rc<RefCell<State>>) -> ApplicationWindow {
+    let window = ApplicationWindow::new(app);
+    window.set_title(gettext("Reconciliation").as_str());
+    window.set_position(WindowPosition::CenterOnParent);
+    window.set_default_size(800, 500);
+
+    let main_box = Box::new(Orientation::Vertical, 10);
+    main_box.set_border_width(10);
+
+    let lists_box = Box::new(Orientation::Horizontal, 10);
+    lists_box.set_homogeneous(true);
+
+    let list_transactions = create_list(&lists_box, gettext("Payments without receipt"),
+                                        gettext("All payments have a receipt"));
+    let list_receipts = create_list(&lists_box, gettext("Receipts without payment"),
+                                    gettext("All card and transfer receipts have a payment"));
+
+    main_box.pack_start(&lists_box, true, true, 0);
+
+    let buttons_box = Box::new(Orientation::Horizontal, 5);
+    buttons_box.set_halign(Align::End);
+
+    let btn_auto = Button::new_with_label(gettext("Match automatically").as_str());
+    let btn_ignore = Button::new_with_label(gettext("Ignore payment").as_str());
+    let btn_match = Button::new_with_label(gettext("Match selected").as_str());
+
+    btn_ignore.set_tooltip_text(Some(gettext("The payment needs no receipt").as_str()));
+    btn_ignore.set_sensitive(false);
+    btn_match.set_sensitive(false);
+
+    buttons_box.pack_start(&btn_auto, false, false, 0);
+    buttons_box.pack_start(&btn_ignore, false, false, 0);
+    buttons_box.pack_start(&btn_match, false, false, 0);
+    main_box.pack_start(&buttons_box, false, false, 0);
+
+    window.add(&main_box);
+
+    let unmatched = Rc::new(RefCell::new(Unmatched { transactions: Vec::new(), receipts: Vec::new() }));
+
+    // Reload the lists when receipts change elsewhere
+    let subscription = {
+        let list_transactions = list_transactions.clone();
+        let list_receipts = list_receipts.clone();
+        let unmatched = unmatched.clone();
+        let state_events = state.clone();
+
+        state.borrow_mut().events.subscribe(move |_| {
+            load(&state_events, &list_transactions, &list_receipts, &unmatched);
+        })
+    };
+
+    // Toggle match and ignore buttons
+    {
+        let list_receipts = list_receipts.clone();
+        let btn_ignore = btn_ignore.clone();
+        let btn_match = btn_match.clone();
+
+        list_transactions.connect_row_selected(move |_, row| {
+            btn_ignore.set_sensitive(row.is_some());
+            btn_match.set_sensitive(row.is_some() && list_receipts.get_selected_row().is_some());
+        });
+    }
+
+    {
+        let list_transactions = list_transactions.clone();
+        let btn_match = btn_match.clone();
+
+        list_receipts.connect_row_selected(move |_, row| {
+            btn_match.set_sensitive(row.is_some() && list_transactions.get_selected_row().is_some());
+        });
+    }
+
+    // Match payments with receipts automatically
+    {
+        let state = state.clone();
+        let window = window.clone();
+
+        btn_auto.connect_clicked(move |_| {
+            let state = state.clone();
+            let window = window.clone();
+            let worker = state.borrow().worker().clone();
+
+            worker.run(
+                move |db| reconcile::auto_match(db, &statements::PAYMENT_TYPES),
+                move |result| {
+                    if result.is_err() {
+                        main_window::show_message(&window, MessageType::Error, &gettext("Error matching the payments"));
+                    }
+
+                    common::emit(&state, ReceiptEvent::External);
+                });
+        });
+    }
+
+    // Ignore the selected payment
+    {
+        let state = state.clone();
+        let window = window.clone();
+        let list_transactions = list_transactions.clone();
+        let unmatched = unmatched.clone();
+
+        btn_ignore.connect_clicked(move |_| {
+            let index = match list_transactions.get_selected_row() {
+                Some(row) => row.get_index() as usize,
+                None => return
+            };
+
+            let transaction_id = unmatched.borrow().transactions[index].id;
+
+            let state = state.clone();
+            let window = window.clone();
+            let worker = state.borrow().worker().clone();
+
+            worker.run(
+                move |db| db.ignore_transaction(transaction_id),
+                move |result| {
+                    if result.is_err() {
+                        main_window::show_message(&window, MessageType::Error, &gettext("Error ignoring the payment"));
+                    }
+
+                    common::emit(&state, ReceiptEvent::External);
+                });
+        });
+    }
+
+    // Match the selected payment and receipt
+    {
+        let state = state.clone();
+        let window = window.clone();
+        let list_transactions = list_transactions.clone();
+        let list_receipts = list_receipts.clone();
+        let unmatched = unmatched.clone();
+
+        btn_match.connect_clicked(move |_| {
+            let (transaction, receipt) = match (list_transactions.get_selected_row(),
+                                                list_receipts.get_selected_row()) {
+                (Some(transaction), Some(receipt)) => (transaction.get_index() as usize,
+                                                       receipt.get_index() as usize),
+                _ => return
+            };
+
+            let transaction_id = unmatched.borrow().transactions[transaction].id;
+            let receipt_id = unmatched.borrow().receipts[receipt].id;
+
+            let state = state.clone();
+            let window = window.clone();
+            let worker = state.borrow().worker().clone();
+
+            worker.run(
+                move |db| db.match_transaction(transaction_id, Some(receipt_id)),
+                move |result| {
+                    if result.is_err() {
+                        main_window::show_message(&window, MessageType::Error, &gettext("Error matching the payment"));
+                    }
+
+                    common::emit(&state, ReceiptEvent::External);
+                });
+        });
+    }
+
+    // Window closed, stop following changes
+    {
+        let state = state.clone();
+
+        window.connect_delete_event(move |_, _| {
+            state.borrow_mut().events.unsubscribe(subscription);
+            Inhibit(false)
+        });
+    }
+
+    lock::track_activity(state, &window);
+    load(state, &list_transactions, &list_receipts, &unmatched);
+
+    window.show_all();
+    window
+}
+
+/// Add a titled list to `parent`
+///
+/// `empty` is shown when the list has no rows.
+fn create_list(parent: &Box, title: String, empty: String) -> ListBox {
+    let list = ListBox::new();
+
+    let lbl_empty = Label::new(Some(empty.as_str()));
+    lbl_empty.show();
+    list.set_placeholder(Some(&lbl_empty));
+
+    let scrolled = ScrolledWindow::new(None, None);
+    scrolled.add(&list);
+
+    let frame = Frame::new(Some(title.as_str()));
+    frame.add(&scrolled);
+    parent.pack_start(&frame, true, true, 0);
+
+    list
+}
+
+/// Load unmatched payments and receipts into the lists
+fn load(state: &Rc<RefCell<State>>, list_transactions: &ListBox, list_receipts: &ListBox,
+        unmatched: &Rc<RefCell<Unmatched>>) {
+
+    let list_transactions = list_transactions.clone();
+    let list_receipts = list_receipts.clone();
+    let unmatched = unmatched.clone();
+    let date_format = state.borrow().config.date_format;
+    let worker = state.borrow().worker().clone();
+
+    worker.run(
+        move |db| {
+            let transactions = try!(db.get_unmatched_transactions());
+            let receipts = try!(db.get_unmatched_receipts(&statements::PAYMENT_TYPES));
+
+            Ok(Unmatched { transactions: transactions, receipts: receipts })
+        },
+        move |result: Result<Unmatched, rusqlite::Error>| {
+            for list in [&list_transactions, &list_receipts].iter() {
+                for child in list.get_children() {
+                    list.remove(&child);
+                }
+            }
+
+            let loaded = match result {
+                Ok(loaded) => loaded,
+                Err(_) => return
+            };
+
+            for transaction in loaded.transactions.iter() {
+                let row = create_row(date_format, transaction.date_posted, reconcile::payee(transaction),
+                                     &i18n::format_money(transaction.amount.abs(), transaction.currency.as_str()),
+                                     &transaction.source);
+                list_transactions.insert(&row, -1);
+            }
+
+            for receipt in loaded.receipts.iter() {
+                let row = create_row(date_format, receipt.date_paid, &receipt.shop,
+                                     &i18n::format_money(receipt.amount, receipt.currency.as_str()),
+                                     &receipt.description);
+                list_receipts.insert(&row, -1);
+            }
+
+            list_transactions.show_all();
+            list_receipts.show_all();
+
+            *unmatched.borrow_mut() = loaded;
+        });
+}
+
+/// Row showing a payment or a receipt
+fn create_row(date_format: DateFormat, date: NaiveDate, name: &str, amount: &str,
+              details: &str) -> Box {
+
+    let row_box = Box::new(Orientation::Vertical, 2);
+    row_box.set_border_width(5);
+
+    let header_box = Box::new(Orientation::Horizontal, 10);
+
+    let lbl_date = Label::new(Some(date_format.format(date).as_str()));
+    header_box.pack_start(&lbl_date, false, false, 0);
+
+    let lbl_name = Label::new(None);
+    lbl_name.set_markup(format!("<b>{}</b>", ::gui::highlight_markup(name)).as_str());
+    lbl_name.set_halign(Align::Start);
+    header_box.pack_start(&lbl_name, true, true, 0);
+
+    let lbl_amount = Label::new(Some(amount));
+    header_box.pack_start(&lbl_amount, false, false, 0);
+
+    row_box.pack_start(&header_box, false, false, 0);
+
+    if !details.is_empty() {
+        let lbl_details = Label::new(None);
+        lbl_details.set_markup(format!("<small>{}</small>", ::gui::highlight_markup(details)).as_str());
+        lbl_details.set_halign(Align::Start);
+        lbl_details.set_line_wrap(true);
+        row_box.pack_start(&lbl_details, false, false, 0);
+    }
+
+    row_box
+}
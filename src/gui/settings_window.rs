@@ -22,11 +22,13 @@
 
 /// Settings definition
 
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
 use std::os::raw::{c_int, c_double};
+use std::path::PathBuf;
 use std::rc::Rc;
 
 use chrono::NaiveDate;
+use gettextrs::gettext;
 use glib;
 use gtk;
 use gtk::prelude::*;
@@ -39,6 +41,7 @@ use gtk::{
     Dialog,
     DialogFlags,
     MessageType,
+    ResponseType,
     Revealer,
     Window,
     WindowPosition
@@ -47,8 +50,13 @@ use gtk::{
     Button,
     CellRendererText,
     Calendar,
+    CheckButton,
     ComboBox,
+    ComboBoxText,
     Entry,
+    FileChooserAction,
+    FileChooserDialog,
+    FileFilter,
     HeaderBar,
     InfoBar,
     Label,
@@ -64,13 +72,20 @@ use gtk::{
     TreeView,
     TreeViewColumn
 };
-use regex::Regex;
 
-use common::{State, RE_DATE, CURRENCIES, PAYMENTS};
+use backup;
+use common;
+use common::{Profile, ReceiptEvent, State, CURRENCIES, PAYMENTS};
 use config;
+use config::BackupSettings;
+use dates::{DateFormat, DATE_FORMATS};
 use db;
 use db::Receipt;
-use gui::main_window;
+use doctor;
+use encryption;
+use gui::{doctor_dialog, main_window, passphrase_dialog};
+use i18n;
+use i18n::tr_format;
 
 
 /// Creates the settings dialog
@@ -78,13 +93,14 @@ pub fn create_window(app: &Application, state: &Rc<RefCell<State>>)
                      -> ApplicationWindow {
 
     let window = ApplicationWindow::new(&app);
-    window.set_title("Settings");
+    window.set_title(gettext("Settings").as_str());
     // window.set_border_width(10);
     window.set_default_size(250, 150);
     window.set_modal(true);
     window.set_position(WindowPosition::Center);
 
     let builder = Builder::new();
+    builder.set_translation_domain(Some(i18n::DOMAIN));
     builder.add_from_string(include_str!("settings_window.ui"));
 
     // Header bar
@@ -95,17 +111,465 @@ pub fn create_window(app: &Application, state: &Rc<RefCell<State>>)
     let main_box: Box = builder.get_object("main_box").unwrap();
     window.add(&main_box);
 
+    // Profiles being edited, with the selected one and the one currently open
+    let profiles: Rc<RefCell<Vec<Profile>>> = Rc::new(RefCell::new(state.borrow().config.profiles.clone()));
+    let selected = Rc::new(Cell::new(0));
+    let open = Rc::new(Cell::new(0));
+
     // Load settings
     {
         let state = state.clone();
 
-        let entry_db: Entry = builder.get_object("entry_db").unwrap();
-        entry_db.set_text(state.borrow().db_path.as_str());
+        {
+            let borrowed = state.borrow();
+            let index = borrowed.config.profiles.iter()
+                .position(|p| p.name == borrowed.config.profile)
+                .unwrap_or(0);
+
+            selected.set(index);
+            open.set(index);
+        }
+
+        refresh_profiles(&builder, &profiles.borrow(), selected.get());
+
+        let spin_retention: SpinButton = builder.get_object("spin_retention").unwrap();
+        spin_retention.set_value(state.borrow().config.trash_retention_days as f64);
+
+        let combo_date_format: ComboBoxText = builder.get_object("combo_date_format").unwrap();
+
+        for &(format, name) in DATE_FORMATS.iter() {
+            combo_date_format.append(Some(name), format.label().as_str());
+        }
+
+        combo_date_format.set_active_id(state.borrow().config.date_format.name());
+
+        show_backup(&builder, &state.borrow().config.backup);
+
+        let spin_lock: SpinButton = builder.get_object("spin_lock").unwrap();
+        spin_lock.set_value(state.borrow().config.lock_minutes as f64);
+    }
+
+    // The passphrase can only be changed if SQLite supports encryption
+    {
+        let btn_passphrase: Button = builder.get_object("btn_passphrase").unwrap();
+        btn_passphrase.set_sensitive(false);
+
+        if !encryption::is_available() {
+            btn_passphrase.set_tooltip_text(
+                Some(gettext("This build of Receipt Keeper does not support encryption").as_str()));
+        } else {
+            state.borrow().worker().run(
+                |db| encryption::is_supported(db),
+                move |supported| {
+                    btn_passphrase.set_sensitive(supported);
+
+                    if !supported {
+                        btn_passphrase.set_tooltip_text(
+                            Some(gettext("This version of SQLite does not support encryption").as_str()));
+                    }
+                });
+        }
     }
 
 
     // Events
 
+    // Choose the database of the selected profile
+    {
+        let builder = builder.clone();
+        let btn_db: Button = builder.get_object("btn_db").unwrap();
+
+        let window = window.clone();
+        let entry_db: Entry = builder.get_object("entry_db").unwrap();
+
+        btn_db.connect_clicked(move |_| {
+            // New and existing files can be chosen
+            if let Some(path) = ::gui::choose_database(&window, FileChooserAction::Save) {
+                entry_db.set_text(path.as_str());
+            }
+        });
+    }
+
+    // Choose the backup folder
+    {
+        let builder = builder.clone();
+        let btn_backup_dir: Button = builder.get_object("btn_backup_dir").unwrap();
+
+        let window = window.clone();
+        let entry_backup_dir: Entry = builder.get_object("entry_backup_dir").unwrap();
+
+        btn_backup_dir.connect_clicked(move |_| {
+            let dialog = FileChooserDialog::new(
+                Some(gettext("Backup folder").as_str()),
+                Some(&window),
+                FileChooserAction::SelectFolder
+            );
+
+            dialog.add_button(gettext("Cancel").as_str(), ResponseType::Cancel.into());
+            dialog.add_button(gettext("Select").as_str(), ResponseType::Accept.into());
+            dialog.set_current_folder(entry_backup_dir.get_text().unwrap_or(String::new()));
+
+            if dialog.run() == ResponseType::Accept.into() {
+                if let Some(dir) = dialog.get_filename() {
+                    entry_backup_dir.set_text(dir.to_string_lossy().as_ref());
+                }
+            }
+
+            dialog.destroy();
+        });
+    }
+
+    // Back up the open database now
+    {
+        let builder = builder.clone();
+        let state = state.clone();
+        let btn_backup_now: Button = builder.get_object("btn_backup_now").unwrap();
+
+        let revealer: Revealer = builder.get_object("revealer").unwrap();
+        let lbl_info: Label = builder.get_object("lbl_info").unwrap();
+
+        btn_backup_now.connect_clicked(move |btn| {
+            let settings = read_backup(&builder);
+            let db_path = state.borrow().db_path.clone();
+
+            let btn = btn.clone();
+            let revealer = revealer.clone();
+            let lbl_info = lbl_info.clone();
+
+            btn.set_sensitive(false);
+
+            state.borrow().worker().run(
+                move |db| {
+                    let dir = settings.directory.as_str();
+
+                    backup::create(db, db_path.as_str(), dir)
+                        .and_then(|path| {
+                            backup::rotate(dir, db_path.as_str(), &settings).map(|_| path)
+                        })
+                },
+                move |result| {
+                    btn.set_sensitive(true);
+
+                    lbl_info.set_text(match result {
+                        Ok(path) => tr_format("Backup saved to {}", &[path.to_string_lossy().as_ref()]),
+                        Err(err) => tr_format("Backup failed: {}", &[err.as_str()])
+                    }.as_str());
+
+                    revealer.set_reveal_child(true);
+                });
+        });
+    }
+
+    // Replace the open database with a backup
+    {
+        let builder = builder.clone();
+        let state = state.clone();
+        let btn_restore_backup: Button = builder.get_object("btn_restore_backup").unwrap();
+
+        let window = window.clone();
+        let revealer: Revealer = builder.get_object("revealer").unwrap();
+        let lbl_info: Label = builder.get_object("lbl_info").unwrap();
+
+        btn_restore_backup.connect_clicked(move |_| {
+            let settings = read_backup(&builder);
+
+            let path = match choose_backup(&window, settings.directory.as_str()) {
+                Some(p) => p,
+                None => return
+            };
+
+            let dialog = MessageDialog::new(
+                Some(&window),
+                gtk::DIALOG_MODAL,
+                MessageType::Question,
+                ButtonsType::YesNo,
+                gettext("All receipts will be replaced with the ones in the backup. \
+                         The current receipts are backed up first. Continue?").as_str()
+            );
+
+            let response = dialog.run();
+            dialog.destroy();
+
+            if response != ResponseType::Yes.into() {
+                return;
+            }
+
+            let db_path = state.borrow().db_path.clone();
+            let state = state.clone();
+            let revealer = revealer.clone();
+            let lbl_info = lbl_info.clone();
+            let worker = state.borrow().worker().clone();
+
+            worker.run(
+                move |db| {
+                    backup::create(db, db_path.as_str(), settings.directory.as_str())
+                        .and_then(|_| backup::restore(db, db_path.as_str(), &path))
+                },
+                move |result| {
+                    match result {
+                        Ok(_) => {
+                            lbl_info.set_text(gettext("Backup restored").as_str());
+                            common::emit(&state, ReceiptEvent::External);
+                        },
+
+                        Err(err) => {
+                            lbl_info.set_text(tr_format("Restore failed: {}", &[err.as_str()]).as_str());
+                        }
+                    }
+
+                    revealer.set_reveal_child(true);
+                });
+        });
+    }
+
+    // Check the open database and fix its problems
+    {
+        let builder = builder.clone();
+        let state = state.clone();
+        let btn_check: Button = builder.get_object("btn_check").unwrap();
+
+        let window = window.clone();
+        let revealer: Revealer = builder.get_object("revealer").unwrap();
+        let lbl_info: Label = builder.get_object("lbl_info").unwrap();
+
+        btn_check.connect_clicked(move |btn| {
+            let btn = btn.clone();
+            let state = state.clone();
+            let window = window.clone();
+            let revealer = revealer.clone();
+            let lbl_info = lbl_info.clone();
+            let worker = state.borrow().worker().clone();
+
+            btn.set_sensitive(false);
+
+            worker.run(
+                |db| doctor::check(db),
+                move |result| {
+                    btn.set_sensitive(true);
+
+                    let problems = match result {
+                        Ok(ref problems) if problems.is_empty() => {
+                            lbl_info.set_text(gettext("No problems found").as_str());
+                            revealer.set_reveal_child(true);
+                            return;
+                        },
+
+                        Ok(problems) => problems,
+
+                        Err(err) => {
+                            lbl_info.set_text(tr_format("Check failed: {}", &[err.as_str()]).as_str());
+                            revealer.set_reveal_child(true);
+                            return;
+                        }
+                    };
+
+                    if !doctor_dialog::run(&window, &problems) {
+                        return;
+                    }
+
+                    let (db_path, dir, currency, payment_type) = {
+                        let borrowed = state.borrow();
+
+                        (borrowed.db_path.clone(),
+                         borrowed.config.backup.directory.clone(),
+                         borrowed.config.base_currency.clone(),
+                         borrowed.config.default_payment.clone())
+                    };
+
+                    let state = state.clone();
+                    let revealer = revealer.clone();
+                    let lbl_info = lbl_info.clone();
+                    let worker = state.borrow().worker().clone();
+
+                    worker.run(
+                        move |db| {
+                            backup::create(db, db_path.as_str(), dir.as_str())
+                                .and_then(|_| {
+                                    doctor::repair(db, &problems, currency.as_str(),
+                                                   payment_type.as_str())
+                                })
+                        },
+                        move |result| {
+                            match result {
+                                Ok(fixed) => {
+                                    lbl_info.set_text(tr_format("Problems fixed: {}",
+                                                                &[fixed.to_string().as_str()]).as_str());
+                                    common::emit(&state, ReceiptEvent::External);
+                                },
+
+                                Err(err) => {
+                                    lbl_info.set_text(tr_format("Repair failed: {}", &[err.as_str()]).as_str());
+                                }
+                            }
+
+                            revealer.set_reveal_child(true);
+                        });
+                });
+        });
+    }
+
+    // Encrypt the open database, change its passphrase or decrypt it
+    {
+        let builder = builder.clone();
+        let state = state.clone();
+        let btn_passphrase: Button = builder.get_object("btn_passphrase").unwrap();
+
+        let app = app.clone();
+        let window = window.clone();
+        let revealer: Revealer = builder.get_object("revealer").unwrap();
+        let lbl_info: Label = builder.get_object("lbl_info").unwrap();
+
+        btn_passphrase.connect_clicked(move |btn| {
+            let current = state.borrow().key.clone();
+
+            let passphrase = match passphrase_dialog::run(&window, current.as_ref().map(|k| k.as_str())) {
+                Some(p) => p,
+                None => return
+            };
+
+            if current.is_none() && passphrase.is_empty() {
+                return;
+            }
+
+            let db_path = state.borrow().db_path.clone();
+            let key = if passphrase.is_empty() { None } else { Some(passphrase.clone()) };
+
+            let btn = btn.clone();
+            let app = app.clone();
+            let state = state.clone();
+            let revealer = revealer.clone();
+            let lbl_info = lbl_info.clone();
+            let worker = state.borrow().worker().clone();
+
+            btn.set_sensitive(false);
+
+            worker.run(
+                move |db| encryption::change_passphrase(db, db_path.as_str(), passphrase.as_str()),
+                move |result| {
+                    btn.set_sensitive(true);
+
+                    match result {
+                        Ok(reopen) => {
+                            state.borrow_mut().key = key.clone();
+
+                            // The database was replaced by an encrypted or
+                            // decrypted copy
+                            if reopen {
+                                main_window::reopen(&app, &state);
+                                return;
+                            }
+
+                            lbl_info.set_text(gettext("Passphrase changed").as_str());
+                        },
+
+                        Err(err) => {
+                            lbl_info.set_text(
+                                tr_format("Changing the passphrase failed: {}", &[err.as_str()]).as_str());
+                        }
+                    }
+
+                    revealer.set_reveal_child(true);
+                });
+        });
+    }
+
+    // Show the selected profile, keeping the changes to the previous one
+    {
+        let builder = builder.clone();
+        let combo_profile: ComboBoxText = builder.get_object("combo_profile").unwrap();
+
+        let profiles = profiles.clone();
+        let selected = selected.clone();
+
+        combo_profile.connect_changed(move |combo| {
+            let index = combo.get_active();
+
+            if index < 0 || index as usize == selected.get() {
+                return;
+            }
+
+            read_profile(&builder, &mut profiles.borrow_mut()[selected.get()]);
+            selected.set(index as usize);
+
+            let borrowed = profiles.borrow();
+            show_profile(&builder, &borrowed[index as usize]);
+        });
+    }
+
+    // Add a profile
+    {
+        let builder = builder.clone();
+        let btn_add_profile: Button = builder.get_object("btn_add_profile").unwrap();
+
+        let profiles = profiles.clone();
+        let selected = selected.clone();
+
+        btn_add_profile.connect_clicked(move |_| {
+            read_profile(&builder, &mut profiles.borrow_mut()[selected.get()]);
+
+            let mut borrowed = profiles.borrow_mut();
+            let mut number = borrowed.len() + 1;
+
+            let profile_name = |number: usize| {
+                tr_format("Profile {}", &[number.to_string().as_str()])
+            };
+
+            while borrowed.iter().any(|p| p.name == profile_name(number)) {
+                number += 1;
+            }
+
+            borrowed.push(Profile {
+                name: profile_name(number),
+                db_path: String::new()
+            });
+
+            selected.set(borrowed.len() - 1);
+            refresh_profiles(&builder, &borrowed, selected.get());
+        });
+    }
+
+    // Remove the selected profile, unless it is open
+    {
+        let builder = builder.clone();
+        let btn_remove_profile: Button = builder.get_object("btn_remove_profile").unwrap();
+
+        let profiles = profiles.clone();
+        let selected = selected.clone();
+        let open = open.clone();
+
+        btn_remove_profile.connect_clicked(move |_| {
+            let index = selected.get();
+
+            if index == open.get() {
+                return;
+            }
+
+            let mut borrowed = profiles.borrow_mut();
+            borrowed.remove(index);
+
+            if index < open.get() {
+                open.set(open.get() - 1);
+            }
+
+            selected.set(open.get());
+            refresh_profiles(&builder, &borrowed, selected.get());
+        });
+    }
+
+    // Only profiles that are not open can be removed
+    {
+        let builder = builder.clone();
+        let combo_profile: ComboBoxText = builder.get_object("combo_profile").unwrap();
+        let btn_remove_profile: Button = builder.get_object("btn_remove_profile").unwrap();
+
+        let open = open.clone();
+        btn_remove_profile.set_sensitive(false);
+
+        combo_profile.connect_changed(move |combo| {
+            btn_remove_profile.set_sensitive(combo.get_active() != open.get() as i32);
+        });
+    }
+
     // Hide the information bar
     {
         let builder = builder.clone();
@@ -125,19 +589,144 @@ pub fn create_window(app: &Application, state: &Rc<RefCell<State>>)
         let btn_save: Button = builder.get_object("btn_save").unwrap();
         let state = state.clone();
 
-        let entry_db: Entry = builder.get_object("entry_db").unwrap();
+        let spin_retention: SpinButton = builder.get_object("spin_retention").unwrap();
+        let combo_date_format: ComboBoxText = builder.get_object("combo_date_format").unwrap();
+        let spin_lock: SpinButton = builder.get_object("spin_lock").unwrap();
         let revealer: Revealer = builder.get_object("revealer").unwrap();
+        let lbl_info: Label = builder.get_object("lbl_info").unwrap();
 
         btn_save.connect_clicked(move |_| {
+            read_profile(&builder, &mut profiles.borrow_mut()[selected.get()]);
+
+            // Names are stored as a comma separated list
+            {
+                let edited = profiles.borrow();
+
+                let invalid = edited.iter().enumerate().any(|(index, profile)| {
+                    profile.name.is_empty()
+                        || profile.name.contains(',')
+                        || edited[..index].iter().any(|p| p.name == profile.name)
+                });
+
+                if invalid {
+                    lbl_info.set_text(gettext("Profile names must be unique and cannot contain commas").as_str());
+                    revealer.set_reveal_child(true);
+                    return;
+                }
+            }
+
             let mut borrowed = state.borrow_mut();
 
-            borrowed.db_path = entry_db.get_text().unwrap();
+            borrowed.config.profiles = profiles.borrow().clone();
+            borrowed.config.profile = borrowed.config.profiles[open.get()].name.clone();
+
+            borrowed.config.trash_retention_days = spin_retention.get_value_as_int() as i64;
+            borrowed.config.backup = read_backup(&builder);
+            borrowed.config.lock_minutes = spin_lock.get_value_as_int() as u32;
 
-            config::write_config_file(&borrowed);
+            if let Some(format) = combo_date_format.get_active_id()
+                .and_then(|id| DateFormat::from_name(id.as_str())) {
+                borrowed.config.date_format = format;
+            }
 
+            config::save(&borrowed.config);
+
+            lbl_info.set_text(gettext("Restart for changes to take effect").as_str());
             revealer.set_reveal_child(true);
         });
     }
 
     window
 }
+
+/// Fill the profile list and show the selected profile
+fn refresh_profiles(builder: &Builder, profiles: &Vec<Profile>, selected: usize) {
+    let combo_profile: ComboBoxText = builder.get_object("combo_profile").unwrap();
+
+    combo_profile.remove_all();
+
+    for profile in profiles.iter() {
+        combo_profile.append_text(profile.name.as_str());
+    }
+
+    combo_profile.set_active(selected as i32);
+    show_profile(builder, &profiles[selected]);
+}
+
+/// Show the details of a profile
+fn show_profile(builder: &Builder, profile: &Profile) {
+    let entry_profile: Entry = builder.get_object("entry_profile").unwrap();
+    let entry_db: Entry = builder.get_object("entry_db").unwrap();
+
+    entry_profile.set_text(profile.name.as_str());
+    entry_db.set_text(profile.db_path.as_str());
+}
+
+/// Show the backup settings
+fn show_backup(builder: &Builder, settings: &BackupSettings) {
+    let check_backup: CheckButton = builder.get_object("check_backup").unwrap();
+    let entry_backup_dir: Entry = builder.get_object("entry_backup_dir").unwrap();
+    let spin_keep_daily: SpinButton = builder.get_object("spin_keep_daily").unwrap();
+    let spin_keep_weekly: SpinButton = builder.get_object("spin_keep_weekly").unwrap();
+    let spin_keep_monthly: SpinButton = builder.get_object("spin_keep_monthly").unwrap();
+
+    check_backup.set_active(settings.enabled);
+    entry_backup_dir.set_text(settings.directory.as_str());
+    spin_keep_daily.set_value(settings.keep_daily as f64);
+    spin_keep_weekly.set_value(settings.keep_weekly as f64);
+    spin_keep_monthly.set_value(settings.keep_monthly as f64);
+}
+
+/// Read the backup settings being edited
+fn read_backup(builder: &Builder) -> BackupSettings {
+    let check_backup: CheckButton = builder.get_object("check_backup").unwrap();
+    let entry_backup_dir: Entry = builder.get_object("entry_backup_dir").unwrap();
+    let spin_keep_daily: SpinButton = builder.get_object("spin_keep_daily").unwrap();
+    let spin_keep_weekly: SpinButton = builder.get_object("spin_keep_weekly").unwrap();
+    let spin_keep_monthly: SpinButton = builder.get_object("spin_keep_monthly").unwrap();
+
+    BackupSettings {
+        enabled: check_backup.get_active(),
+        directory: entry_backup_dir.get_text().unwrap_or(String::new()).trim().to_string(),
+        keep_daily: spin_keep_daily.get_value_as_int() as u32,
+        keep_weekly: spin_keep_weekly.get_value_as_int() as u32,
+        keep_monthly: spin_keep_monthly.get_value_as_int() as u32
+    }
+}
+
+/// Ask the user for a backup to restore, starting in the backup folder
+fn choose_backup(parent: &ApplicationWindow, dir: &str) -> Option<PathBuf> {
+    let dialog = FileChooserDialog::new(
+        Some(gettext("Restore from backup").as_str()),
+        Some(parent),
+        FileChooserAction::Open
+    );
+
+    dialog.add_button(gettext("Cancel").as_str(), ResponseType::Cancel.into());
+    dialog.add_button(gettext("Restore").as_str(), ResponseType::Accept.into());
+    dialog.set_current_folder(dir);
+
+    let filter = FileFilter::new();
+    filter.set_name(gettext("Backups").as_str());
+    filter.add_pattern("*.db");
+    dialog.add_filter(&filter);
+
+    let path = if dialog.run() == ResponseType::Accept.into() {
+        dialog.get_filename()
+    } else {
+        None
+    };
+
+    dialog.destroy();
+
+    path
+}
+
+/// Store the details being edited in a profile
+fn read_profile(builder: &Builder, profile: &mut Profile) {
+    let entry_profile: Entry = builder.get_object("entry_profile").unwrap();
+    let entry_db: Entry = builder.get_object("entry_db").unwrap();
+
+    profile.name = entry_profile.get_text().unwrap_or(String::new()).trim().to_string();
+    profile.db_path = entry_db.get_text().unwrap_or(String::new());
+}
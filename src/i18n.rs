@@ -0,0 +1,118 @@
+// MIT License
+//
+// Copyright (c) 2016 Rafael Medina García <rafamedgar@gmail.com>
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+/// Translation of the user interface and locale-aware number formatting
+///
+/// Messages are looked up with gettext in the `receipt-keeper` domain.
+/// Compiled catalogs are searched in `$RECEIPT_KEEPER_LOCALEDIR`, or in the
+/// `LOCALEDIR` given at build time, or in `/usr/local/share/locale`.
+
+use std::env;
+use std::ffi::CStr;
+use std::os::raw::c_char;
+
+use gettextrs::{bind_textdomain_codeset, bindtextdomain, gettext, setlocale, textdomain,
+                LocaleCategory};
+
+
+/// Gettext domain of the application
+pub const DOMAIN: &'static str = "receipt-keeper";
+
+/// First fields of `struct lconv`, which is all we read
+#[repr(C)]
+struct LConv {
+    decimal_point: *const c_char,
+    thousands_sep: *const c_char
+}
+
+extern "C" {
+    fn localeconv() -> *const LConv;
+}
+
+/// Set the locale from the environment and bind the message catalog
+///
+/// Must be called before any window is created.
+pub fn init() {
+    setlocale(LocaleCategory::LcAll, "");
+
+    let dir = match env::var("RECEIPT_KEEPER_LOCALEDIR") {
+        Ok(dir) => dir,
+        Err(_) => option_env!("LOCALEDIR").unwrap_or("/usr/local/share/locale").to_string()
+    };
+
+    // Messages are shown untranslated if the catalog cannot be used
+    let _ = bindtextdomain(DOMAIN, dir);
+    let _ = bind_textdomain_codeset(DOMAIN, "UTF-8");
+    let _ = textdomain(DOMAIN);
+}
+
+/// Translate a message and replace its `{}` placeholders in order
+///
+/// Translations may not reorder the placeholders.
+pub fn tr_format(msgid: &str, args: &[&str]) -> String {
+    let mut message = gettext(msgid);
+
+    for arg in args {
+        message = message.replacen("{}", arg, 1);
+    }
+
+    message
+}
+
+/// Format an amount with two decimals and the separators of the locale
+pub fn format_amount(amount: f64) -> String {
+    let (decimal_point, thousands_sep) = unsafe {
+        let conv = localeconv();
+
+        if conv.is_null() {
+            (".".to_string(), String::new())
+        } else {
+            (CStr::from_ptr((*conv).decimal_point).to_string_lossy().into_owned(),
+             CStr::from_ptr((*conv).thousands_sep).to_string_lossy().into_owned())
+        }
+    };
+
+    let text = format!("{:.2}", amount.abs());
+    let (integer, fraction) = text.split_at(text.len() - 3);
+
+    // Group integer digits by thousands
+    let mut grouped = String::new();
+
+    for (index, digit) in integer.chars().enumerate() {
+        if index > 0 && (integer.len() - index) % 3 == 0 {
+            grouped.push_str(thousands_sep.as_str());
+        }
+
+        grouped.push(digit);
+    }
+
+    format!("{}{}{}{}",
+            if amount < 0.0 && text != "0.00" { "-" } else { "" },
+            grouped,
+            decimal_point,
+            &fraction[1..])
+}
+
+/// Format an amount followed by its currency
+pub fn format_money(amount: f64, currency: &str) -> String {
+    format!("{} {}", format_amount(amount), currency)
+}
@@ -21,71 +21,355 @@
 // SOFTWARE.
 
 extern crate chrono;
+extern crate gettextrs;
 extern crate gio;
+extern crate gio_sys as gio_ffi;
 extern crate glib;
+extern crate glib_sys as glib_ffi;
+extern crate gobject_sys as gobject_ffi;
 extern crate gtk;
 extern crate ini;
+#[macro_use]
+extern crate lazy_static;
+extern crate libc;
 extern crate regex;
 extern crate rusqlite;
 
+mod backup;
 mod config;
 mod common;
+mod dates;
 mod db;
+mod doctor;
+mod encryption;
+mod i18n;
 mod migrations;
+mod reconcile;
+mod statements;
 mod gui;
+mod worker;
 
 use std::cell::RefCell;
 use std::collections::HashMap;
+use std::env;
+use std::io;
+use std::io::Write;
+use std::mem;
+use std::path::Path;
+use std::process;
 use std::rc::Rc;
+use std::time::Instant;
 
+use gettextrs::gettext;
 use gtk::prelude::*;
 use gtk::Application;
 
-use common::State;
-use gui::{main_window, settings_window};
+use common::{EventBus, State};
+use i18n::tr_format;
 
 
 /// Activation signal handler
-fn do_activate(app: &Application) {
+///
+/// `profile` overrides the current profile in the config file.
+fn do_activate(app: &Application, profile: &Option<String>) {
     // Application state
-    let db_path = config::read_config_file();
+    let mut config = config::load();
+
+    if let Some(ref name) = *profile {
+        config.profile = name.clone();
+    }
+
+    let db_path = config.profile_path(config.profile.as_str()).unwrap_or("").to_string();
 
     let state: Rc<RefCell<State>> = Rc::new(RefCell::new(State {
-        db_path: db_path.clone(),
+        config: config,
+        db_path: db_path,
+        worker: None,
+        key: None,
+        session: 0,
+        last_activity: Instant::now(),
+        events: EventBus::new(),
         window_map: HashMap::new()
     }));
 
-    let window;
+    let window = gui::open_profile(&app, &state);
+
+    window.show_all();
+    app.add_window(&window);
+}
+
+/// Open the database of a profile from the command line
+///
+/// The passphrase of encrypted databases is read from the standard input.
+/// Returns the path of the database along with it.
+fn open_database(config: &config::Config, profile: &str) -> Option<(String, db::Database)> {
+    let db_path = config.profile_path(profile).unwrap_or("").to_string();
+
+    if !Path::new(db_path.as_str()).is_file() {
+        eprintln!("{}", tr_format("Profile {} has no database", &[profile]));
+        return None;
+    }
 
-    if db_path.is_empty() {
-        // Ask for database path
-        window = settings_window::create_window(&app, &state);
+    let key = if encryption::is_encrypted(db_path.as_str()) {
+        if !encryption::is_available() {
+            eprintln!("{}", encryption::unavailable_message(db_path.as_str()));
+            return None;
+        }
 
+        match read_passphrase() {
+            Some(key) => Some(key),
+            None => return None
+        }
     } else {
-        // Migrate database and show main window
-        let db_version = migrations::migrate(db_path.as_str());
-        //TODO show error if -1
+        None
+    };
 
-        window = main_window::create_window(&app, &state);
+    match db::Database::open_with_key(db_path.as_str(), key.as_ref().map(|k| k.as_str())) {
+        Ok(database) => Some((db_path, database)),
+
+        Err(_) if key.is_some() => {
+            eprintln!("{}", gettext("Wrong passphrase"));
+            None
+        },
+
+        Err(err) => {
+            eprintln!("{}", tr_format("Could not open the database: {}", &[err.to_string().as_str()]));
+            None
+        }
     }
+}
 
-    window.show_all();
-    app.add_window(&window);
+/// Back up the database of a profile and rotate its backups
+///
+/// Returns whether the backup was made.
+fn run_backup(config: &config::Config, profile: &str) -> bool {
+    let (db_path, database) = match open_database(config, profile) {
+        Some(opened) => opened,
+        None => return false
+    };
+
+    let db_path = db_path.as_str();
+    let settings = &config.backup;
+    let dir = settings.directory.as_str();
+
+    let result = backup::create(&database, db_path, dir)
+        .and_then(|path| backup::rotate(dir, db_path, settings).map(|_| path));
+
+    match result {
+        Ok(path) => {
+            println!("{}", tr_format("Backup saved to {}", &[path.to_string_lossy().as_ref()]));
+            true
+        },
+
+        Err(err) => {
+            eprintln!("{}", tr_format("Backup failed: {}", &[err.as_str()]));
+            false
+        }
+    }
+}
+
+/// Check the database of a profile for problems
+///
+/// With `fix`, the database is backed up and the problems that can be fixed
+/// are fixed. Returns whether the database has no problems left.
+fn run_doctor(config: &config::Config, profile: &str, fix: bool) -> bool {
+    let (db_path, database) = match open_database(config, profile) {
+        Some(opened) => opened,
+        None => return false
+    };
+
+    let mut problems = match doctor::check(&database) {
+        Ok(problems) => problems,
+        Err(err) => {
+            eprintln!("{}", tr_format("Check failed: {}", &[err.as_str()]));
+            return false;
+        }
+    };
+
+    if fix && problems.iter().any(|p| p.is_fixable()) {
+        let result = backup::create(&database, db_path.as_str(), config.backup.directory.as_str())
+            .and_then(|_| {
+                doctor::repair(&database, &problems,
+                               config.base_currency.as_str(), config.default_payment.as_str())
+            })
+            .and_then(|fixed| doctor::check(&database).map(|remaining| (fixed, remaining)));
+
+        match result {
+            Ok((fixed, remaining)) => {
+                println!("{}", tr_format("Problems fixed: {}", &[fixed.to_string().as_str()]));
+                problems = remaining;
+            },
+
+            Err(err) => {
+                eprintln!("{}", tr_format("Repair failed: {}", &[err.as_str()]));
+                return false;
+            }
+        }
+    }
+
+    if problems.is_empty() {
+        println!("{}", gettext("No problems found"));
+        return true;
+    }
+
+    for problem in problems.iter() {
+        println!("{}", problem.describe());
+    }
+
+    if !fix && problems.iter().any(|p| p.is_fixable()) {
+        println!("{}", gettext("Run receipt-keeper doctor --fix to fix them"));
+    }
+
+    false
+}
+
+/// Read the passphrase of an encrypted database from the standard input
+///
+/// The passphrase is not echoed when the standard input is a terminal. The
+/// prompt goes to the standard error, leaving the standard output to the
+/// command.
+fn read_passphrase() -> Option<String> {
+    eprint!("{}", gettext("Passphrase: "));
+    let _ = io::stderr().flush();
+
+    let echo = disable_echo();
+
+    let mut line = String::new();
+    let result = io::stdin().read_line(&mut line);
+
+    if let Some(termios) = echo {
+        unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &termios); }
+
+        // The newline typed by the user was not echoed either
+        eprintln!("");
+    }
+
+    match result {
+        Ok(read) if read > 0 => Some(line.trim_right_matches(&['\r', '\n'][..]).to_string()),
+        _ => None
+    }
+}
+
+/// Stop the terminal in the standard input from echoing what is typed
+///
+/// Returns the previous settings of the terminal, or `None` if the standard
+/// input is not a terminal.
+fn disable_echo() -> Option<libc::termios> {
+    unsafe {
+        if libc::isatty(libc::STDIN_FILENO) != 1 {
+            return None;
+        }
+
+        let mut termios: libc::termios = mem::zeroed();
+
+        if libc::tcgetattr(libc::STDIN_FILENO, &mut termios) != 0 {
+            return None;
+        }
+
+        let mut silent = termios;
+        silent.c_lflag &= !libc::ECHO;
+
+        if libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &silent) != 0 {
+            return None;
+        }
+
+        Some(termios)
+    }
+}
+
+/// Command given on the command line
+#[derive(PartialEq)]
+enum Command {
+    // Open the main window
+    Run,
+    // Back up the database and exit
+    Backup,
+    // Check the database and exit, fixing its problems if set
+    Doctor(bool)
+}
+
+/// Parse command line options
+///
+/// Returns the command and the profile given with `--profile NAME`, if any.
+fn parse_args() -> Result<(Command, Option<String>), String> {
+    let mut args = env::args().skip(1);
+    let mut command = Command::Run;
+    let mut profile = None;
+
+    while let Some(arg) = args.next() {
+        if arg == "--profile" {
+            match args.next() {
+                Some(name) => profile = Some(name),
+                None => return Err(gettext("Missing profile name"))
+            }
+
+        } else if arg.starts_with("--profile=") {
+            profile = Some(arg["--profile=".len()..].to_string());
+
+        } else if arg == "backup" && command == Command::Run {
+            command = Command::Backup;
+
+        } else if arg == "doctor" && command == Command::Run {
+            command = Command::Doctor(false);
+
+        } else if arg == "--fix" && command == Command::Doctor(false) {
+            command = Command::Doctor(true);
+
+        } else {
+            return Err(tr_format("Unknown option: {}", &[arg.as_str()]));
+        }
+    }
+
+    Ok((command, profile))
 }
 
 fn main() {
-    if gtk::init().is_err() {
-		println!("Failed to initialize GTK.");
+    i18n::init();
+
+    let (command, profile) = match parse_args() {
+        Ok(args) => args,
+        Err(err) => {
+            eprintln!("{}", err);
+            eprintln!("{}", gettext("Usage: receipt-keeper [--profile NAME] [backup | doctor [--fix]]"));
+            process::exit(2);
+        }
+    };
+
+    if let Some(ref name) = profile {
+        if config::load().profile_path(name.as_str()).is_none() {
+            eprintln!("{}", tr_format("Unknown profile: {}", &[name.as_str()]));
+            process::exit(2);
+        }
+    }
+
+    if command != Command::Run {
+        let config = config::load();
+        let name = profile.unwrap_or(config.profile.clone());
+
+        let success = match command {
+            Command::Doctor(fix) => run_doctor(&config, name.as_str(), fix),
+            _ => run_backup(&config, name.as_str())
+        };
+
+        if !success {
+            process::exit(1);
+        }
+
         return;
     }
 
+    if gtk::init().is_err() {
+        eprintln!("{}", gettext("Failed to initialize GTK."));
+        process::exit(1);
+    }
+
     let app = Application::new(
         Some("com.rmedgar.ReceiptKeeper"),
         gio::APPLICATION_FLAGS_NONE
     ).unwrap();
 
-    app.connect_activate(|ref app| {
-        do_activate(&app,)
+    app.connect_activate(move |ref app| {
+        do_activate(&app, &profile)
     });
 
     // Number of params and params array
@@ -22,42 +22,126 @@
 
 use rusqlite;
 use rusqlite::Connection;
-use db::open_connection;
+use db::Database;
+
+/// Version databases are brought to by `migrate`
+pub const LATEST_VERSION: i32 = 11;
 
 /// Execute SQL queries to create/update tables of the database on-the-go.
 ///
 /// Only migrations with a higher version number than the one in the database
-/// (default is -1) will be executed.
+/// (default is -1) will be executed. Each migration runs in a transaction
+/// along with the update of the version, so one that fails leaves the
+/// database as it was and is tried again the next time.
 ///
-/// Returns current migration in the database
-pub fn migrate(db_path: &str) -> i32 {
-    let conn = open_connection(&db_path).unwrap();
+/// Returns current migration in the database, or -1 if a migration failed
+pub fn migrate(db: &Database) -> i32 {
+    let conn = db.connection();
 
     // Find out version (default is -1)
-    let mut version: i32 = match get_version(&conn) {
+    let mut version: i32 = match get_version(conn) {
         Ok(v) => v,
         Err(_) => -1
     };
 
     if version < 1 {
-        run_migration_ver1(&conn);
+        if db.transaction(|db| run_migration_ver1(db.connection())).is_err() {
+            return -1;
+        }
         version = 1;
     }
 
+    if version < 2 {
+        if db.transaction(|db| run_migration_ver2(db.connection())).is_err() {
+            return -1;
+        }
+        version = 2;
+    }
+
+    if version < 3 {
+        if db.transaction(|db| run_migration_ver3(db.connection())).is_err() {
+            return -1;
+        }
+        version = 3;
+    }
+
+    if version < 4 {
+        if db.transaction(|db| run_migration_ver4(db.connection())).is_err() {
+            return -1;
+        }
+        version = 4;
+    }
+
+    if version < 5 {
+        if db.transaction(|db| run_migration_ver5(db.connection())).is_err() {
+            return -1;
+        }
+        version = 5;
+    }
+
+    if version < 6 {
+        if db.transaction(|db| run_migration_ver6(db.connection())).is_err() {
+            return -1;
+        }
+        version = 6;
+    }
+
+    if version < 7 {
+        if db.transaction(|db| run_migration_ver7(db.connection())).is_err() {
+            return -1;
+        }
+        version = 7;
+    }
+
+    if version < 8 {
+        if db.transaction(|db| run_migration_ver8(db.connection())).is_err() {
+            return -1;
+        }
+        version = 8;
+    }
+
+    if version < 9 {
+        if db.transaction(|db| run_migration_ver9(db.connection())).is_err() {
+            return -1;
+        }
+        version = 9;
+    }
+
+    if version < 10 {
+        if db.transaction(|db| run_migration_ver10(db.connection())).is_err() {
+            return -1;
+        }
+        version = 10;
+    }
+
+    if version < 11 {
+        if db.transaction(|db| run_migration_ver11(db.connection())).is_err() {
+            return -1;
+        }
+        version = 11;
+    }
+
     version
 }
 
+/// Version of the tables in a database
+///
+/// Returns -1 if the database has not been migrated yet.
+pub fn current_version(db: &Database) -> i32 {
+    get_version(db.connection()).unwrap_or(-1)
+}
+
 /// Get current version in database
-fn get_version(conn: &Connection) -> rusqlite::Result<i32> {
+pub fn get_version(conn: &Connection) -> rusqlite::Result<i32> {
     conn.query_row("SELECT version FROM __revision", &[], |row| {
         row.get(0)
     })
 }
 
 /// Base migration that creates the database tables.
-fn run_migration_ver1(conn: &Connection) {
+fn run_migration_ver1(conn: &Connection) -> rusqlite::Result<()> {
     // Create receipts table
-    conn.execute("
+    try!(conn.execute("
         CREATE TABLE receipts (
             id INTEGER PRIMARY KEY,
             description TEXT,
@@ -66,17 +150,267 @@ fn run_migration_ver1(conn: &Connection) {
             currency TEXT NOT NULL,
             payment_type TEXT NOT NULL,
             date_paid TEXT NOT NULL
-        )", &[]).unwrap();
+        )", &[]));
 
     // Create revision table
-    conn.execute("
+    try!(conn.execute("
         CREATE TABLE __revision (
             version INTEGER
-        )", &[]).unwrap();
+        )", &[]));
 
     // Insert revision number
-    conn.execute("
+    try!(conn.execute("
         INSERT INTO __revision (version)
         VALUES (1)",
-        &[]).unwrap();
+        &[]));
+
+    Ok(())
+}
+
+/// Add return and warranty dates to receipts.
+fn run_migration_ver2(conn: &Connection) -> rusqlite::Result<()> {
+    conn.execute_batch("
+        ALTER TABLE receipts ADD COLUMN return_by TEXT;
+        ALTER TABLE receipts ADD COLUMN warranty_until TEXT;
+
+        UPDATE __revision SET version = 2;
+        ")
+}
+
+/// Full-text index over shop and description.
+///
+/// The index is an external content table kept in sync by triggers.
+fn run_migration_ver3(conn: &Connection) -> rusqlite::Result<()> {
+    conn.execute_batch("
+        CREATE VIRTUAL TABLE receipts_fts USING fts5(
+            shop,
+            description,
+            content='receipts',
+            content_rowid='id'
+        );
+
+        CREATE TRIGGER receipts_fts_insert AFTER INSERT ON receipts BEGIN
+            INSERT INTO receipts_fts (rowid, shop, description)
+            VALUES (new.id, new.shop, new.description);
+        END;
+
+        CREATE TRIGGER receipts_fts_delete AFTER DELETE ON receipts BEGIN
+            INSERT INTO receipts_fts (receipts_fts, rowid, shop, description)
+            VALUES ('delete', old.id, old.shop, old.description);
+        END;
+
+        CREATE TRIGGER receipts_fts_update AFTER UPDATE ON receipts BEGIN
+            INSERT INTO receipts_fts (receipts_fts, rowid, shop, description)
+            VALUES ('delete', old.id, old.shop, old.description);
+            INSERT INTO receipts_fts (rowid, shop, description)
+            VALUES (new.id, new.shop, new.description);
+        END;
+
+        INSERT INTO receipts_fts (receipts_fts) VALUES ('rebuild');
+
+        UPDATE __revision SET version = 3;
+        ")
+}
+
+/// Saved search filters.
+fn run_migration_ver4(conn: &Connection) -> rusqlite::Result<()> {
+    conn.execute_batch("
+        CREATE TABLE saved_searches (
+            id INTEGER PRIMARY KEY,
+            name TEXT NOT NULL UNIQUE,
+            text TEXT NOT NULL DEFAULT '',
+            shop TEXT NOT NULL DEFAULT '',
+            cost_from REAL NOT NULL DEFAULT 0.0,
+            cost_to REAL NOT NULL DEFAULT 0.0,
+            date_from TEXT,
+            date_to TEXT,
+            payment_type TEXT NOT NULL DEFAULT '',
+            currency TEXT NOT NULL DEFAULT '',
+            expiring_days INTEGER NOT NULL DEFAULT 0
+        );
+
+        UPDATE __revision SET version = 4;
+        ")
+}
+
+/// Add a version number to receipts, incremented on every update.
+fn run_migration_ver5(conn: &Connection) -> rusqlite::Result<()> {
+    conn.execute_batch("
+        ALTER TABLE receipts ADD COLUMN version INTEGER NOT NULL DEFAULT 1;
+
+        UPDATE __revision SET version = 5;
+        ")
+}
+
+/// History of changes to receipts, with the values before and after each change.
+fn run_migration_ver6(conn: &Connection) -> rusqlite::Result<()> {
+    conn.execute_batch("
+        CREATE TABLE receipt_history (
+            id INTEGER PRIMARY KEY,
+            receipt_id INTEGER NOT NULL,
+            action TEXT NOT NULL,
+            changed_at TEXT NOT NULL,
+            user TEXT NOT NULL DEFAULT '',
+            old_description TEXT,
+            old_shop TEXT,
+            old_amount REAL,
+            old_currency TEXT,
+            old_payment_type TEXT,
+            old_date_paid TEXT,
+            old_return_by TEXT,
+            old_warranty_until TEXT,
+            old_version INTEGER,
+            new_description TEXT,
+            new_shop TEXT,
+            new_amount REAL,
+            new_currency TEXT,
+            new_payment_type TEXT,
+            new_date_paid TEXT,
+            new_return_by TEXT,
+            new_warranty_until TEXT,
+            new_version INTEGER
+        );
+
+        CREATE INDEX receipt_history_receipt ON receipt_history (receipt_id);
+
+        UPDATE __revision SET version = 6;
+        ")
+}
+
+/// Soft deletion: deleted receipts are kept in the trash until purged.
+fn run_migration_ver7(conn: &Connection) -> rusqlite::Result<()> {
+    conn.execute_batch("
+        ALTER TABLE receipts ADD COLUMN deleted_at TEXT;
+
+        CREATE INDEX receipts_deleted_at ON receipts (deleted_at);
+
+        UPDATE __revision SET version = 7;
+        ")
+}
+
+/// Creation and modification times of receipts.
+///
+/// Existing receipts take them from their history when available, or the
+/// time of the migration otherwise.
+fn run_migration_ver8(conn: &Connection) -> rusqlite::Result<()> {
+    conn.execute_batch("
+        ALTER TABLE receipts ADD COLUMN created_at TEXT;
+        ALTER TABLE receipts ADD COLUMN updated_at TEXT;
+
+        UPDATE receipts SET
+            created_at = IFNULL(
+                (SELECT MIN(changed_at) FROM receipt_history
+                 WHERE receipt_id = receipts.id),
+                strftime('%Y-%m-%dT%H:%M:%f', 'now', 'localtime')),
+            updated_at = IFNULL(
+                (SELECT MAX(changed_at) FROM receipt_history
+                 WHERE receipt_id = receipts.id AND action IN ('insert', 'update')),
+                strftime('%Y-%m-%dT%H:%M:%f', 'now', 'localtime'));
+
+        CREATE INDEX receipts_created_at ON receipts (created_at);
+
+        ALTER TABLE saved_searches ADD COLUMN entered_days INTEGER NOT NULL DEFAULT 0;
+
+        UPDATE __revision SET version = 8;
+        ")
+}
+
+/// Pairs of receipts the user said are not duplicates.
+///
+/// The receipt with the lowest ID comes first.
+fn run_migration_ver9(conn: &Connection) -> rusqlite::Result<()> {
+    conn.execute_batch("
+        CREATE TABLE dismissed_duplicates (
+            receipt_id INTEGER NOT NULL,
+            other_id INTEGER NOT NULL,
+            PRIMARY KEY (receipt_id, other_id)
+        );
+
+        UPDATE __revision SET version = 9;
+        ")
+}
+
+/// Source of receipts imported from bank and card statements.
+///
+/// Identifiers given by banks are only unique within an account.
+fn run_migration_ver10(conn: &Connection) -> rusqlite::Result<()> {
+    conn.execute_batch("
+        ALTER TABLE receipts ADD COLUMN import_source TEXT;
+        ALTER TABLE receipts ADD COLUMN import_id TEXT;
+        ALTER TABLE receipts ADD COLUMN import_account TEXT;
+
+        CREATE INDEX receipts_import_id ON receipts (import_id);
+
+        UPDATE __revision SET version = 10;
+        ")
+}
+
+/// Lines of imported statements, matched with receipts when reconciling.
+///
+/// Lines without an identifier are told apart by their details and by the
+/// number of identical lines before them in their statement.
+fn run_migration_ver11(conn: &Connection) -> rusqlite::Result<()> {
+    conn.execute_batch("
+        CREATE TABLE transactions (
+            id INTEGER PRIMARY KEY,
+            format TEXT NOT NULL,
+            source TEXT NOT NULL,
+            account TEXT NOT NULL DEFAULT '',
+            bank_id TEXT,
+            date_posted TEXT NOT NULL,
+            amount REAL NOT NULL,
+            currency TEXT NOT NULL,
+            payee TEXT NOT NULL DEFAULT '',
+            memo TEXT NOT NULL DEFAULT '',
+            occurrence INTEGER NOT NULL DEFAULT 0,
+            receipt_id INTEGER,
+            ignored INTEGER NOT NULL DEFAULT 0,
+            imported_at TEXT NOT NULL
+        );
+
+        CREATE INDEX transactions_bank_id ON transactions (bank_id);
+        CREATE INDEX transactions_receipt_id ON transactions (receipt_id);
+
+        UPDATE __revision SET version = 11;
+        ")
+}
+
+
+#[cfg(test)]
+mod tests {
+    use db::Database;
+    use super::*;
+
+    #[test]
+    fn migrates_new_databases() {
+        let db = Database::open(":memory:").unwrap();
+
+        assert_eq!(current_version(&db), -1);
+        assert_eq!(migrate(&db), LATEST_VERSION);
+        assert_eq!(migrate(&db), LATEST_VERSION);
+    }
+
+    #[test]
+    fn leaves_failed_migrations_undone() {
+        let db = Database::open(":memory:").unwrap();
+        let conn = db.connection();
+
+        run_migration_ver1(conn).unwrap();
+        run_migration_ver2(conn).unwrap();
+        run_migration_ver3(conn).unwrap();
+        run_migration_ver4(conn).unwrap();
+        run_migration_ver5(conn).unwrap();
+        run_migration_ver6(conn).unwrap();
+        run_migration_ver7(conn).unwrap();
+
+        // Makes version 8 fail after adding its columns
+        conn.execute_batch("CREATE INDEX receipts_created_at ON receipts (shop);").unwrap();
+
+        assert_eq!(migrate(&db), -1);
+        assert_eq!(current_version(&db), 7);
+
+        conn.execute_batch("DROP INDEX receipts_created_at;").unwrap();
+
+        assert_eq!(migrate(&db), LATEST_VERSION);
+    }
 }
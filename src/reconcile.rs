@@ -0,0 +1,293 @@
+// MIT License
+//
+// Copyright (c) 2016 Rafael Medina García <rafamedgar@gmail.com>
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+/// Reconciliation of receipts with bank and card transactions
+///
+/// Payments are matched with receipts of the same amount and currency paid
+/// within `MATCH_DAYS` days of them. When there are several candidates, the
+/// one whose shop is most similar to the payee wins; a candidate with a
+/// dissimilar shop is only taken when it is the only one on both sides.
+///
+/// Statements only list card payments and bank transfers, so only receipts
+/// paid with those payment types (`statements::PAYMENT_TYPES`) are offered;
+/// receipts paid in cash or with points are never matched.
+
+use std::cmp::Ordering;
+
+use rusqlite;
+
+use common::MATCH_DAYS;
+use db::{BankTransaction, Database, Receipt};
+
+
+/// Similarity above which a shop is taken to be the payee
+const MIN_SIMILARITY: f64 = 0.5;
+
+/// Match unmatched payments with unmatched receipts paid with one of
+/// `payment_types`
+///
+/// Callers pass `statements::PAYMENT_TYPES`, the payment types statements
+/// are about. Returns the number of payments matched.
+pub fn auto_match(db: &Database, payment_types: &[&str]) -> rusqlite::Result<usize> {
+    db.transaction(|db| {
+        let transactions = try!(db.get_unmatched_transactions());
+        let receipts = try!(db.get_unmatched_receipts(payment_types));
+
+        let matches = find_matches(&transactions, &receipts);
+
+        for &(transaction_id, receipt_id) in matches.iter() {
+            try!(db.match_transaction(transaction_id, Some(receipt_id)));
+        }
+
+        Ok(matches.len())
+    })
+}
+
+/// Pairs of transaction and receipt IDs that are taken to be the same payment
+pub fn find_matches(transactions: &[BankTransaction], receipts: &[Receipt]) -> Vec<(i32, i32)> {
+    // Candidates as (transaction, receipt, similarity, days apart)
+    let mut candidates = Vec::new();
+
+    for (t, transaction) in transactions.iter().enumerate() {
+        for (r, receipt) in receipts.iter().enumerate() {
+            let days = (transaction.date_posted - receipt.date_paid).num_days().abs();
+
+            if (transaction.amount.abs() - receipt.amount).abs() < 0.005
+                && transaction.currency == receipt.currency
+                && days <= MATCH_DAYS {
+
+                candidates.push((t, r, similarity(payee(transaction), &receipt.shop), days));
+            }
+        }
+    }
+
+    let count = |index: usize, of_transaction: bool| {
+        candidates.iter()
+            .filter(|c| if of_transaction { c.0 == index } else { c.1 == index })
+            .count()
+    };
+
+    let unique: Vec<bool> = candidates.iter().map(|c| count(c.0, true) == 1 && count(c.1, false) == 1).collect();
+
+    // Most similar first, then closest in time
+    let mut order: Vec<usize> = (0..candidates.len()).collect();
+
+    order.sort_by(|&a, &b| {
+        let (a, b) = (&candidates[a], &candidates[b]);
+        b.2.partial_cmp(&a.2).unwrap_or(Ordering::Equal).then(a.3.cmp(&b.3))
+    });
+
+    let mut used_transactions = vec![false; transactions.len()];
+    let mut used_receipts = vec![false; receipts.len()];
+    let mut matches = Vec::new();
+
+    for index in order {
+        let (t, r, similar, _) = candidates[index];
+
+        if used_transactions[t] || used_receipts[r] || (similar < MIN_SIMILARITY && !unique[index]) {
+            continue;
+        }
+
+        used_transactions[t] = true;
+        used_receipts[r] = true;
+        matches.push((transactions[t].id, receipts[r].id));
+    }
+
+    matches
+}
+
+/// Name of the other party of a transaction
+///
+/// Some banks leave the payee empty and put the name in the memo.
+pub fn payee(transaction: &BankTransaction) -> &str {
+    if transaction.payee.trim().is_empty() { &transaction.memo } else { &transaction.payee }
+}
+
+/// How similar two merchant names are, from 0 to 1
+///
+/// Bank statements often add locations, numbers or legal forms to names, so
+/// names are the same when all the words of the shorter one start words of
+/// the longer one. Otherwise the Dice coefficient of their letter pairs is
+/// used, which tolerates abbreviations and typos.
+pub fn similarity(a: &str, b: &str) -> f64 {
+    let words_a = words(a);
+    let words_b = words(b);
+
+    if words_a.is_empty() || words_b.is_empty() {
+        return 0.0;
+    }
+
+    let (shorter, longer) = if words_a.len() <= words_b.len() { (&words_a, &words_b) }
+                            else { (&words_b, &words_a) };
+
+    // Single letters, e.g. from "Sainsbury's", would start too many words
+    let significant: Vec<&String> = shorter.iter().filter(|w| w.chars().count() > 1).collect();
+
+    let contained = !significant.is_empty() && significant.iter()
+        .all(|w| longer.iter().any(|l| l.starts_with(w.as_str())));
+
+    if contained {
+        return 1.0;
+    }
+
+    let pairs_a = letter_pairs(&words_a);
+    let mut pairs_b = letter_pairs(&words_b);
+    let total = pairs_a.len() + pairs_b.len();
+    let mut common = 0;
+
+    for pair in pairs_a.iter() {
+        if let Some(position) = pairs_b.iter().position(|p| p == pair) {
+            pairs_b.remove(position);
+            common += 1;
+        }
+    }
+
+    if total == 0 { 0.0 } else { 2.0 * common as f64 / total as f64 }
+}
+
+/// Lowercase words of a name, without punctuation
+fn words(name: &str) -> Vec<String> {
+    name.split(|c: char| !c.is_alphanumeric())
+        .filter(|w| !w.is_empty())
+        .map(|w| w.to_lowercase())
+        .collect()
+}
+
+/// Pairs of adjacent letters within each word
+fn letter_pairs(words: &[String]) -> Vec<(char, char)> {
+    let mut pairs = Vec::new();
+
+    for word in words.iter() {
+        let chars: Vec<char> = word.chars().collect();
+
+        for pair in chars.windows(2) {
+            pairs.push((pair[0], pair[1]));
+        }
+    }
+
+    pairs
+}
+
+
+#[cfg(test)]
+mod tests {
+    use chrono::NaiveDate;
+
+    use db::{BankTransaction, Receipt};
+    use super::*;
+
+    fn transaction(id: i32, payee: &str, amount: f64, day: u32) -> BankTransaction {
+        BankTransaction {
+            id: id,
+            format: "OFX".to_string(),
+            source: "OFX: march.ofx".to_string(),
+            account: "".to_string(),
+            bank_id: None,
+            date_posted: NaiveDate::from_ymd(2017, 3, day),
+            amount: -amount,
+            currency: "EUR".to_string(),
+            payee: payee.to_string(),
+            memo: "".to_string(),
+            occurrence: 0,
+            receipt_id: None,
+            ignored: false,
+            imported_at: NaiveDate::from_ymd(2017, 4, 1).and_hms(0, 0, 0)
+        }
+    }
+
+    fn receipt(id: i32, shop: &str, amount: f64, day: u32) -> Receipt {
+        let mut receipt = Receipt::new();
+
+        receipt.id = id;
+        receipt.shop = shop.to_string();
+        receipt.amount = amount;
+        receipt.currency = "EUR".to_string();
+        receipt.payment_type = "card".to_string();
+        receipt.date_paid = NaiveDate::from_ymd(2017, 3, day);
+        receipt
+    }
+
+    #[test]
+    fn scores_shop_names() {
+        assert_eq!(similarity("ACME", "ACME STORE 123 BERLIN"), 1.0);
+        assert_eq!(similarity("Electro Shop GmbH", "electro-shop"), 1.0);
+        assert!(similarity("Hardware Store", "Hardwre Store") > MIN_SIMILARITY);
+        assert!(similarity("Coffee & Co", "Hardware Store") < MIN_SIMILARITY);
+        assert_eq!(similarity("", "ACME"), 0.0);
+        assert_eq!(similarity("!!", "ACME"), 0.0);
+    }
+
+    #[test]
+    fn takes_the_memo_without_payee() {
+        let mut payment = transaction(1, " ", 10.0, 1);
+        payment.memo = "ACME".to_string();
+
+        assert_eq!(payee(&payment), "ACME");
+    }
+
+    #[test]
+    fn prefers_similar_shops_then_closer_dates() {
+        // The similar shop wins although the other receipt is closer in time
+        let transactions = vec![transaction(1, "ACME STORE", 10.0, 10)];
+        let receipts = vec![receipt(10, "Bakery", 10.0, 10), receipt(11, "Acme", 10.0, 13)];
+
+        assert_eq!(find_matches(&transactions, &receipts), vec![(1, 11)]);
+
+        // With the same shop, the closest date wins
+        let receipts = vec![receipt(10, "Acme", 10.0, 6), receipt(11, "Acme", 10.0, 9)];
+
+        assert_eq!(find_matches(&transactions, &receipts), vec![(1, 11)]);
+
+        // Each payment and receipt is matched once
+        let transactions = vec![transaction(1, "ACME", 10.0, 10), transaction(2, "ACME", 10.0, 12)];
+        let receipts = vec![receipt(10, "Acme", 10.0, 10), receipt(11, "Acme", 10.0, 12)];
+
+        assert_eq!(find_matches(&transactions, &receipts), vec![(1, 10), (2, 11)]);
+    }
+
+    #[test]
+    fn takes_dissimilar_shops_only_without_alternatives() {
+        let transactions = vec![transaction(1, "PAYPAL *12345", 10.0, 10)];
+
+        assert_eq!(find_matches(&transactions, &[receipt(10, "Bakery", 10.0, 10)]), vec![(1, 10)]);
+
+        let receipts = vec![receipt(10, "Bakery", 10.0, 10), receipt(11, "Butcher", 10.0, 11)];
+
+        assert!(find_matches(&transactions, &receipts).is_empty());
+    }
+
+    #[test]
+    fn requires_same_amount_currency_and_close_dates() {
+        let transactions = vec![transaction(1, "ACME", 10.0, 10)];
+
+        let mut dollars = receipt(12, "Acme", 10.0, 10);
+        dollars.currency = "USD".to_string();
+
+        let receipts = vec![receipt(10, "Acme", 10.01, 10),
+                            receipt(11, "Acme", 10.0, 10 + MATCH_DAYS as u32 + 1),
+                            dollars];
+
+        assert!(find_matches(&transactions, &receipts).is_empty());
+        assert_eq!(find_matches(&transactions, &[receipt(10, "Acme", 10.004, 10 - MATCH_DAYS as u32)]),
+                   vec![(1, 10)]);
+    }
+}
@@ -0,0 +1,1067 @@
+// MIT License
+//
+// Copyright (c) 2016 Rafael Medina García <rafamedgar@gmail.com>
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+/// Bank and card statements
+///
+/// Statements are read from OFX (and QFX, which is the same format), ISO 20022
+/// CAMT.053 and SWIFT MT940 files. Only the parts needed to create receipts
+/// are parsed, so tags are matched with regular expressions instead of a full
+/// SGML or XML parser.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+use chrono::{Local, NaiveDate};
+use gettextrs::gettext;
+use regex::Regex;
+use rusqlite;
+
+use common::{CURRENCIES, DUPLICATE_DAYS};
+use db::{BankTransaction, Database, Receipt};
+use i18n::tr_format;
+use reconcile;
+
+
+/// Payment types of the payments listed in statements
+///
+/// Reconciliation only matches payments with receipts of these types, cash
+/// withdrawals are only matched with the receipts created from them.
+pub const PAYMENT_TYPES: [&'static str; 2] = ["card", "transfer"];
+
+/// CAMT.053 elements read by the parser
+const CAMT053_TAGS: [&'static str; 24] = [
+    "Acct", "AcctSvcrRef", "AddtlNtryInf", "BkTxCd", "BookgDt", "Cd", "CdtDbtInd", "Cdtr", "Dbtr",
+    "Dt", "DtTm", "Fmly", "IBAN", "Id", "Nm", "Ntry", "NtryRef", "Othr", "RltdPties", "RmtInf",
+    "Stmt", "SubFmlyCd", "Ustrd", "ValDt"
+];
+
+lazy_static! {
+    // Statements of bank accounts and of credit cards
+    static ref OFX_STATEMENT: Regex = Regex::new(r"(?s)<(?:CC)?STMTRS>(.*?)</(?:CC)?STMTRS>").unwrap();
+
+    static ref OFX_TRANSACTION: Regex = Regex::new(r"(?s)<STMTTRN>(.*?)</STMTTRN>").unwrap();
+
+    // Elements of both SGML and XML variants, which have no children
+    static ref OFX_ELEMENT: Regex = Regex::new(r"<([A-Z0-9.]+)>([^<\r\n]*)").unwrap();
+
+    static ref CAMT053_AMOUNT: Regex = Regex::new(
+        r#"<(?:\w+:)?Amt\s+Ccy="([A-Za-z]{3})"\s*>([^<]*)<"#).unwrap();
+
+    // Elements named after each tag, with any namespace prefix
+    static ref XML_ELEMENTS: HashMap<&'static str, Regex> = CAMT053_TAGS.iter()
+        .map(|&tag| {
+            let pattern = format!(r"(?s)<(?:\w+:)?{0}(?:\s[^>]*)?>(.*?)</(?:\w+:)?{0}>", tag);
+            (tag, Regex::new(pattern.as_str()).unwrap())
+        })
+        .collect();
+
+    static ref MT940_TAG: Regex = Regex::new(r"^:(\d\d[A-Z]?):(.*)$").unwrap();
+
+    static ref MT940_BALANCE: Regex = Regex::new(r"^[CD]\d{6}([A-Z]{3})").unwrap();
+
+    static ref MT940_LINE: Regex = Regex::new(
+        r"^(\d{6})(?:\d{4})?(R?[CD])[A-Z]?(\d+(?:,\d*)?)[A-Z]([A-Z0-9]{3})([^/]*)(?://(.*))?$").unwrap();
+
+    static ref MT940_SUBFIELD: Regex = Regex::new(r"\?(\d\d)([^?]*)").unwrap();
+}
+
+/// Statement file formats
+#[derive(Clone, Copy, PartialEq)]
+pub enum Format {
+    Ofx,
+    Camt053,
+    Mt940
+}
+
+impl Format {
+    /// Name of the format shown to the user and stored in import sources
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Format::Ofx => "OFX",
+            Format::Camt053 => "CAMT.053",
+            Format::Mt940 => "MT940"
+        }
+    }
+
+    /// Guess the format of the contents of a statement file
+    pub fn detect(text: &str) -> Option<Format> {
+        if text.contains("OFXHEADER") || text.contains("<OFX>") {
+            Some(Format::Ofx)
+
+        } else if text.contains("camt.053") || text.contains("BkToCstmrStmt>") {
+            Some(Format::Camt053)
+
+        } else if text.contains(":20:") && text.contains(":61:") {
+            Some(Format::Mt940)
+
+        } else {
+            None
+        }
+    }
+}
+
+/// Line of a statement
+#[derive(Clone)]
+pub struct Transaction {
+    // Identifier given by the bank, if any
+    pub id: Option<String>,
+    // Account number or IBAN, empty if the statement does not give it
+    pub account: String,
+    pub date: NaiveDate,
+    // Negative for payments, positive for deposits and refunds
+    pub amount: f64,
+    pub currency: String,
+    // One of `PAYMENTS`, from the kind of transaction
+    pub payment_type: &'static str,
+    pub payee: String,
+    pub memo: String,
+    // Number of identical lines without identifier before this one
+    pub occurrence: i32
+}
+
+impl Transaction {
+    /// Create a receipt to be completed by the user from a payment of
+    /// `statement`
+    ///
+    /// Payments without a payee or memo give receipts without a shop, which
+    /// do not pass `Receipt::validate`.
+    pub fn to_receipt(&self, statement: &Statement) -> Receipt {
+        let mut receipt = Receipt::new();
+
+        receipt.shop = if self.payee.is_empty() { self.memo.clone() } else { self.payee.clone() };
+        receipt.description = if self.payee.is_empty() { String::new() } else { self.memo.clone() };
+        receipt.amount = self.amount.abs();
+        receipt.currency = self.currency.clone();
+        receipt.payment_type = self.payment_type.to_string();
+        receipt.date_paid = self.date;
+        receipt.import_source = Some(statement.source.clone());
+        receipt.import_id = self.id.clone();
+        receipt.import_account = non_empty(self.account.clone());
+
+        receipt
+    }
+
+    /// Line of `statement` to store for reconciliation
+    pub fn to_bank_transaction(&self, statement: &Statement) -> BankTransaction {
+        BankTransaction {
+            id: -1,
+            format: statement.format.name().to_string(),
+            source: statement.source.clone(),
+            account: self.account.clone(),
+            bank_id: self.id.clone(),
+            date_posted: self.date,
+            amount: self.amount,
+            currency: self.currency.clone(),
+            payee: self.payee.clone(),
+            memo: self.memo.clone(),
+            occurrence: self.occurrence,
+            receipt_id: None,
+            ignored: false,
+            imported_at: Local::now().naive_local()
+        }
+    }
+}
+
+/// Contents of a statement file
+pub struct Statement {
+    pub format: Format,
+    // Describes the file, e.g. "OFX: march.ofx"
+    pub source: String,
+    pub transactions: Vec<Transaction>
+}
+
+/// Whether a transaction of a statement can be imported
+#[derive(Clone, Copy, PartialEq)]
+pub enum Status {
+    New,
+    // A receipt was already created from the same transaction
+    Imported,
+    // A receipt looks like the same purchase
+    Duplicate,
+    // Deposits and refunds do not create receipts
+    Deposit,
+    UnknownCurrency,
+    // The payment has no payee or memo to take the shop from
+    Invalid
+}
+
+impl Status {
+    /// Description of the status shown to the user
+    pub fn label(&self) -> String {
+        match *self {
+            Status::New => gettext("New"),
+            Status::Imported => gettext("Already imported"),
+            Status::Duplicate => gettext("Possible duplicate"),
+            Status::Deposit => gettext("Deposit"),
+            Status::UnknownCurrency => gettext("Unknown currency"),
+            Status::Invalid => gettext("No payee")
+        }
+    }
+
+    /// Whether the transaction is imported unless the user says otherwise
+    pub fn is_selected(&self) -> bool {
+        *self == Status::New
+    }
+
+    /// Whether the user can choose to import the transaction
+    pub fn can_import(&self) -> bool {
+        *self == Status::New || *self == Status::Duplicate
+    }
+}
+
+/// Read a statement file
+pub fn read(path: &Path) -> Result<Statement, String> {
+    let mut bytes = Vec::new();
+
+    try!(File::open(path)
+         .and_then(|mut file| file.read_to_end(&mut bytes))
+         .map_err(|e| e.to_string()));
+
+    // Older statements are usually in Latin-1
+    let text = match String::from_utf8(bytes) {
+        Ok(text) => text,
+        Err(err) => err.into_bytes().iter().map(|&b| b as char).collect()
+    };
+
+    let name = path.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or(String::new());
+
+    parse(&text, &name)
+}
+
+/// Parse the contents of a statement file named `name`
+pub fn parse(text: &str, name: &str) -> Result<Statement, String> {
+    let format = match Format::detect(text) {
+        Some(format) => format,
+        None => return Err(tr_format("{} is not an OFX, CAMT.053 or MT940 statement", &[name]))
+    };
+
+    let transactions = match format {
+        Format::Ofx => parse_ofx(text),
+        Format::Camt053 => parse_camt053(text),
+        Format::Mt940 => parse_mt940(text)
+    };
+
+    match transactions {
+        Some(mut transactions) => {
+            count_occurrences(&mut transactions);
+
+            Ok(Statement {
+                format: format,
+                source: format!("{}: {}", format.name(), name),
+                transactions: transactions
+            })
+        },
+
+        None => Err(tr_format("{} is not a valid {} statement", &[name, format.name()]))
+    }
+}
+
+/// Decide which transactions of a statement can be imported
+pub fn check(db: &Database, statement: &Statement) -> rusqlite::Result<Vec<Status>> {
+    let mut statuses = Vec::new();
+
+    for transaction in statement.transactions.iter() {
+        let status = if transaction.amount >= 0.0 {
+            Status::Deposit
+
+        } else if !CURRENCIES.contains(&transaction.currency.as_str()) {
+            Status::UnknownCurrency
+
+        } else if try!(is_imported(db, statement, transaction)) {
+            Status::Imported
+
+        } else if transaction.to_receipt(statement).validate().is_err() {
+            Status::Invalid
+
+        } else {
+            let receipt = transaction.to_receipt(statement);
+
+            if try!(db.find_duplicates_of(&receipt, DUPLICATE_DAYS)).is_empty() {
+                Status::New
+            } else {
+                Status::Duplicate
+            }
+        };
+
+        statuses.push(status);
+    }
+
+    Ok(statuses)
+}
+
+/// Outcome of importing a statement
+pub struct Imported {
+    // IDs of the new receipts
+    pub receipts: Vec<i32>,
+    // Number of payments matched with existing receipts
+    pub matched: usize,
+    // Indices of the chosen transactions that gave invalid receipts
+    pub rejected: Vec<usize>
+}
+
+/// Store the transactions of a statement and create receipts from some
+///
+/// Receipts are created from the transactions at the indices in `chosen` and
+/// matched with them, unless they do not pass `Receipt::validate`.
+/// Transactions stored before are not stored again, and the rest of the
+/// payments are then matched with existing receipts.
+pub fn import(db: &Database, statement: &Statement, chosen: &[usize])
+              -> rusqlite::Result<Imported> {
+
+    db.transaction(|db| {
+        let mut ids = Vec::new();
+        let mut rejected = Vec::new();
+
+        for (index, transaction) in statement.transactions.iter().enumerate() {
+            let line = transaction.to_bank_transaction(statement);
+
+            let transaction_id = match try!(db.find_transaction(&line)) {
+                Some(id) => id,
+                None => try!(db.insert_transaction(&line))
+            };
+
+            if !chosen.contains(&index) {
+                continue;
+            }
+
+            let receipt = transaction.to_receipt(statement);
+
+            if receipt.validate().is_err() {
+                rejected.push(index);
+                continue;
+            }
+
+            let receipt_id = try!(db.insert_receipt(&receipt));
+            try!(db.match_transaction(transaction_id, Some(receipt_id)));
+
+            ids.push(receipt_id);
+        }
+
+        let matched = try!(reconcile::auto_match(db, &PAYMENT_TYPES));
+
+        Ok(Imported {
+            receipts: ids,
+            matched: matched,
+            rejected: rejected
+        })
+    })
+}
+
+/// Whether a receipt was created from a transaction
+///
+/// Transactions without an identifier are never considered imported, the
+/// duplicate check catches them instead.
+fn is_imported(db: &Database, statement: &Statement, transaction: &Transaction)
+               -> rusqlite::Result<bool> {
+
+    match transaction.id {
+        Some(ref id) => {
+            db.find_imported(statement.format.name(), &transaction.account, id).map(|r| r.is_some())
+        },
+        None => Ok(false)
+    }
+}
+
+/// Parse the transactions of an OFX statement
+///
+/// Both the SGML (1.x) and XML (2.x) variants are accepted, as SGML elements
+/// without closing tags end at the next tag. A file can hold the statements
+/// of several accounts, each with its own currency.
+fn parse_ofx(text: &str) -> Option<Vec<Transaction>> {
+    let mut statements: Vec<&str> = OFX_STATEMENT.captures_iter(text).map(|c| c.at(1).unwrap()).collect();
+
+    if statements.is_empty() {
+        statements.push(text);
+    }
+
+    let mut transactions = Vec::new();
+
+    for statement in statements {
+        let account = ofx_value(statement, "ACCTID").unwrap_or(String::new());
+        let currency = ofx_value(statement, "CURDEF").unwrap_or(String::new());
+
+        for captures in OFX_TRANSACTION.captures_iter(statement) {
+            let fields = captures.at(1).unwrap();
+
+            let date = match ofx_value(fields, "DTPOSTED").and_then(|d| parse_compact_date(&d)) {
+                Some(date) => date,
+                None => return None
+            };
+
+            let amount = match ofx_value(fields, "TRNAMT").and_then(|a| parse_amount(&a)) {
+                Some(amount) => amount,
+                None => return None
+            };
+
+            let kind = ofx_value(fields, "TRNTYPE").unwrap_or(String::new());
+
+            transactions.push(Transaction {
+                id: ofx_value(fields, "FITID"),
+                account: account.clone(),
+                date: date,
+                amount: amount,
+                currency: ofx_value(fields, "CURSYM").unwrap_or(currency.clone()),
+                payment_type: ofx_payment_type(&kind),
+                payee: ofx_value(fields, "NAME").unwrap_or(String::new()),
+                memo: ofx_value(fields, "MEMO").unwrap_or(String::new()),
+                occurrence: 0
+            });
+        }
+    }
+
+    Some(transactions)
+}
+
+/// Payment type of an OFX transaction type
+///
+/// Generic debits and point of sale payments are taken to be card payments.
+fn ofx_payment_type(kind: &str) -> &'static str {
+    match kind {
+        "ATM" | "CASH" => "cash",
+        "XFER" | "PAYMENT" | "DIRECTDEBIT" | "REPEATPMT" | "CHECK" => "transfer",
+        _ => "card"
+    }
+}
+
+/// Value of the first OFX element named `tag`
+fn ofx_value(text: &str, tag: &str) -> Option<String> {
+    OFX_ELEMENT.captures_iter(text)
+        .find(|c| c.at(1) == Some(tag))
+        .and_then(|c| c.at(2))
+        .and_then(|v| non_empty(unescape(v.trim())))
+}
+
+/// Parse the entries of a CAMT.053 statement
+///
+/// Amounts are taken from the entries, so batch entries create a single
+/// transaction. A file can hold the statements of several accounts.
+fn parse_camt053(text: &str) -> Option<Vec<Transaction>> {
+    let mut statements = xml_elements(text, "Stmt");
+
+    if statements.is_empty() {
+        statements.push(text.to_string());
+    }
+
+    let mut transactions = Vec::new();
+
+    for statement in statements.iter() {
+        let account = camt053_account(statement).unwrap_or(String::new());
+
+        match parse_camt053_entries(statement, &account) {
+            Some(entries) => transactions.extend(entries),
+            None => return None
+        }
+    }
+
+    Some(transactions)
+}
+
+/// Parse the entries of the statement of `account` in a CAMT.053 file
+fn parse_camt053_entries(text: &str, account: &str) -> Option<Vec<Transaction>> {
+    let mut transactions = Vec::new();
+
+    for entry in xml_elements(text, "Ntry") {
+        let (currency, amount) = match CAMT053_AMOUNT.captures(entry.as_str()) {
+            Some(captures) => (captures.at(1).unwrap().to_uppercase(),
+                               parse_amount(captures.at(2).unwrap())),
+            None => return None
+        };
+
+        let amount = match amount {
+            Some(amount) => amount,
+            None => return None
+        };
+
+        let debit = match xml_text(&entry, "CdtDbtInd") {
+            Some(ref indicator) if indicator == "DBIT" => true,
+            Some(ref indicator) if indicator == "CRDT" => false,
+            _ => return None
+        };
+
+        // Dates are either a date or a date and time
+        let date = xml_element(&entry, "BookgDt")
+            .or(xml_element(&entry, "ValDt"))
+            .and_then(|d| xml_text(&d, "Dt").or(xml_text(&d, "DtTm")))
+            .and_then(|d| NaiveDate::parse_from_str(&d[..10.min(d.len())], "%Y-%m-%d").ok());
+
+        let date = match date {
+            Some(date) => date,
+            None => return None
+        };
+
+        // The other party is the creditor of payments and the debtor of deposits
+        let party = if debit { "Cdtr" } else { "Dbtr" };
+
+        let payee = xml_element(&entry, "RltdPties")
+            .and_then(|p| xml_element(&p, party))
+            .and_then(|p| xml_text(&p, "Nm"));
+
+        let memo = xml_element(&entry, "RmtInf")
+            .and_then(|r| xml_text(&r, "Ustrd"))
+            .or(xml_text(&entry, "AddtlNtryInf"));
+
+        transactions.push(Transaction {
+            id: xml_text(&entry, "AcctSvcrRef").or(xml_text(&entry, "NtryRef")),
+            account: account.to_string(),
+            date: date,
+            amount: if debit { -amount } else { amount },
+            currency: currency,
+            payment_type: camt053_payment_type(&entry),
+            payee: payee.unwrap_or(String::new()),
+            memo: memo.unwrap_or(String::new()),
+            occurrence: 0
+        });
+    }
+
+    Some(transactions)
+}
+
+/// Payment type of a CAMT.053 entry from the family of its bank transaction
+/// code
+///
+/// Entries without a code or with a proprietary one are taken to be card
+/// payments.
+fn camt053_payment_type(entry: &str) -> &'static str {
+    let family = xml_element(entry, "BkTxCd").and_then(|code| xml_element(&code, "Fmly"));
+
+    let code = family.as_ref().and_then(|f| xml_text(f, "Cd")).unwrap_or(String::new());
+    let subfamily = family.as_ref().and_then(|f| xml_text(f, "SubFmlyCd")).unwrap_or(String::new());
+
+    match (code.as_str(), subfamily.as_str()) {
+        ("CNTR", _) | (_, "CWDL") => "cash",
+        ("ICDT", _) | ("IDDT", _) | ("ICHQ", _) | ("RCDT", _) | ("RDDT", _) | ("RCHQ", _) => "transfer",
+        _ => "card"
+    }
+}
+
+/// Account of a CAMT.053 statement, its IBAN or other identifier
+fn camt053_account(text: &str) -> Option<String> {
+    xml_element(text, "Acct").and_then(|account| {
+        xml_text(&account, "IBAN")
+            .or(xml_element(&account, "Othr").and_then(|other| xml_text(&other, "Id")))
+    })
+}
+
+/// Contents of all XML elements named `tag`, with any namespace prefix
+///
+/// `tag` must be one of `CAMT053_TAGS`.
+fn xml_elements(text: &str, tag: &str) -> Vec<String> {
+    XML_ELEMENTS[tag].captures_iter(text)
+        .map(|c| c.at(1).unwrap().to_string())
+        .collect()
+}
+
+/// Contents of the first XML element named `tag`
+fn xml_element(text: &str, tag: &str) -> Option<String> {
+    xml_elements(text, tag).into_iter().next()
+}
+
+/// Text of the first XML element named `tag`, if it has no children
+fn xml_text(text: &str, tag: &str) -> Option<String> {
+    xml_element(text, tag)
+        .and_then(|v| if v.contains('<') { None } else { non_empty(unescape(v.trim())) })
+}
+
+/// Parse the transactions of an MT940 statement
+///
+/// Statement lines (`:61:`) take the account of the last account
+/// identification (`:25:`), the currency of the opening balance (`:60F:` or
+/// `:60M:`) and the payee and memo from the information to the account owner
+/// (`:86:`) that follows them.
+fn parse_mt940(text: &str) -> Option<Vec<Transaction>> {
+    let mut account = String::new();
+    let mut currency = String::new();
+    let mut transactions: Vec<Transaction> = Vec::new();
+
+    for &(ref name, ref value) in mt940_fields(text).iter() {
+        match name.as_str() {
+            "25" => account = value.trim().to_string(),
+
+            "60F" | "60M" => {
+                match MT940_BALANCE.captures(value) {
+                    Some(captures) => currency = captures.at(1).unwrap().to_string(),
+                    None => return None
+                }
+            },
+
+            "61" => {
+                let first_line = value.lines().next().unwrap_or("");
+
+                let captures = match MT940_LINE.captures(first_line) {
+                    Some(captures) => captures,
+                    None => return None
+                };
+
+                let date = match parse_compact_date(&format!("20{}", captures.at(1).unwrap())) {
+                    Some(date) => date,
+                    None => return None
+                };
+
+                let amount = match parse_amount(captures.at(3).unwrap()) {
+                    Some(amount) => amount,
+                    None => return None
+                };
+
+                // Reversals of credits are debits and the other way round
+                let debit = match captures.at(2).unwrap() {
+                    "D" | "RC" => true,
+                    _ => false
+                };
+
+                let reference = captures.at(6)
+                    .or(captures.at(5))
+                    .and_then(|r| if r.trim() == "NONREF" { None } else { non_empty(r.trim().to_string()) });
+
+                // Transfers, direct debits, standing orders and cheques
+                let payment_type = match captures.at(4).unwrap() {
+                    "TRF" | "DDT" | "STO" | "CHK" => "transfer",
+                    _ => "card"
+                };
+
+                transactions.push(Transaction {
+                    id: reference,
+                    account: account.clone(),
+                    date: date,
+                    amount: if debit { -amount } else { amount },
+                    currency: currency.clone(),
+                    payment_type: payment_type,
+                    payee: String::new(),
+                    memo: String::new(),
+                    occurrence: 0
+                });
+            },
+
+            "86" => {
+                if let Some(transaction) = transactions.last_mut() {
+                    let (payee, memo) = mt940_information(value);
+                    transaction.payee = payee;
+                    transaction.memo = memo;
+                }
+            },
+
+            _ => {}
+        }
+    }
+
+    Some(transactions)
+}
+
+/// Tags of an MT940 statement and their values, which can span several lines
+fn mt940_fields(text: &str) -> Vec<(String, String)> {
+    let mut fields: Vec<(String, String)> = Vec::new();
+
+    for text_line in text.lines().map(|l| l.trim_right()) {
+        if let Some(captures) = MT940_TAG.captures(text_line) {
+            fields.push((captures.at(1).unwrap().to_string(), captures.at(2).unwrap().to_string()));
+
+        } else if text_line.starts_with("-") || text_line.starts_with("{") {
+            // End of message or block headers
+
+        } else if let Some(&mut (_, ref mut value)) = fields.last_mut() {
+            value.push('\n');
+            value.push_str(text_line);
+        }
+    }
+
+    fields
+}
+
+/// Payee and memo from the information to the account owner
+///
+/// Structured information uses `?NN` subfields, with the name in 32 and 33
+/// and the memo in 20 to 29. Otherwise the first line is taken as the payee.
+fn mt940_information(value: &str) -> (String, String) {
+    let joined: String = value.lines().collect();
+
+    if !joined.contains('?') {
+        let mut lines = value.lines().map(|l| l.trim());
+        let payee = lines.next().unwrap_or("").to_string();
+        let memo: Vec<&str> = lines.collect();
+
+        return (payee, memo.join(" "));
+    }
+
+    let mut payee = String::new();
+    let mut memo = String::new();
+
+    for captures in MT940_SUBFIELD.captures_iter(&joined) {
+        let number: u32 = captures.at(1).unwrap().parse().unwrap();
+        let text = captures.at(2).unwrap();
+
+        match number {
+            20...29 => memo.push_str(text),
+            32 | 33 => payee.push_str(text),
+            _ => {}
+        }
+    }
+
+    (payee.trim().to_string(), memo.trim().to_string())
+}
+
+/// Number the lines without identifier that are the same as earlier ones
+///
+/// Banks that give no identifiers can list two purchases of the same amount
+/// at the same shop on the same day, which are then told apart by their
+/// order in the statement.
+fn count_occurrences(transactions: &mut [Transaction]) {
+    for index in 0..transactions.len() {
+        let (before, rest) = transactions.split_at_mut(index);
+        let transaction = &mut rest[0];
+
+        if transaction.id.is_some() {
+            continue;
+        }
+
+        transaction.occurrence = before.iter()
+            .filter(|other| {
+                other.id.is_none()
+                    && other.account == transaction.account
+                    && other.date == transaction.date
+                    && other.amount == transaction.amount
+                    && other.currency == transaction.currency
+                    && other.payee == transaction.payee
+                    && other.memo == transaction.memo
+            })
+            .count() as i32;
+    }
+}
+
+/// Parse dates like `20170301`, ignoring anything after the day
+fn parse_compact_date(text: &str) -> Option<NaiveDate> {
+    if text.len() < 8 || !text.is_char_boundary(8) {
+        return None;
+    }
+
+    NaiveDate::parse_from_str(&text[..8], "%Y%m%d").ok()
+}
+
+/// Parse amounts with either a dot or a comma as decimal separator
+fn parse_amount(text: &str) -> Option<f64> {
+    match text.trim().replace(',', ".").parse::<f64>() {
+        Ok(amount) if amount.is_finite() => Some(amount),
+        _ => None
+    }
+}
+
+/// `None` for empty strings
+fn non_empty(text: String) -> Option<String> {
+    if text.is_empty() { None } else { Some(text) }
+}
+
+/// Replace the predefined XML entities
+fn unescape(text: &str) -> String {
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::NaiveDate;
+
+    use db::Database;
+    use migrations;
+    use super::*;
+
+    const OFX: &'static str = "OFXHEADER:100
+DATA:OFXSGML
+
+<OFX>
+<BANKMSGSRSV1><STMTTRNRS><STMTRS>
+<CURDEF>EUR
+<BANKACCTFROM><BANKID>1234<ACCTID>0001234567<ACCTTYPE>CHECKING</BANKACCTFROM>
+<BANKTRANLIST>
+<STMTTRN>
+<TRNTYPE>DEBIT
+<DTPOSTED>20170301120000[-5:EST]
+<TRNAMT>-12.30
+<FITID>A1
+<NAME>Coffee &amp; Co
+<MEMO>Card 1234
+</STMTTRN>
+<STMTTRN>
+<TRNTYPE>CREDIT
+<DTPOSTED>20170302
+<TRNAMT>100.00
+<FITID>A2
+<NAME>Salary
+</STMTTRN>
+<STMTTRN>
+<TRNTYPE>DEBIT
+<DTPOSTED>20170303
+<TRNAMT>-5,00
+<FITID>A3
+<CURRENCY><CURRATE>1.0<CURSYM>JPY</CURRENCY>
+</STMTTRN>
+</BANKTRANLIST>
+</STMTRS></STMTTRNRS></BANKMSGSRSV1>
+</OFX>";
+
+    const CAMT053: &'static str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<Document xmlns="urn:iso:std:iso:20022:tech:xsd:camt.053.001.02">
+<BkToCstmrStmt><Stmt>
+<Acct><Id><IBAN>DE89370400440532013000</IBAN></Id></Acct>
+<Ntry>
+  <NtryRef>R1</NtryRef>
+  <Amt Ccy="EUR">45.10</Amt>
+  <CdtDbtInd>DBIT</CdtDbtInd>
+  <BookgDt><Dt>2017-03-04</Dt></BookgDt>
+  <ValDt><Dt>2017-03-05</Dt></ValDt>
+  <AcctSvcrRef>BANK-1</AcctSvcrRef>
+  <NtryDtls><TxDtls>
+    <AmtDtls><InstdAmt><Amt Ccy="USD">50.00</Amt></InstdAmt></AmtDtls>
+    <RltdPties><Dbtr><Nm>Me</Nm></Dbtr><Cdtr><Nm>Hardware Store</Nm></Cdtr></RltdPties>
+    <RmtInf><Ustrd>Invoice 42</Ustrd></RmtInf>
+  </TxDtls></NtryDtls>
+</Ntry>
+<Ntry>
+  <Amt Ccy="EUR">10</Amt>
+  <CdtDbtInd>CRDT</CdtDbtInd>
+  <BookgDt><DtTm>2017-03-06T10:00:00</DtTm></BookgDt>
+  <AddtlNtryInf>Refund</AddtlNtryInf>
+</Ntry>
+</Stmt></BkToCstmrStmt></Document>"#;
+
+    const MT940: &'static str = "{1:F01BANKDEFFXXXX0000000000}{4:
+:20:STARTUMSE
+:25:10020030/1234567
+:28C:00001/001
+:60F:C170301EUR1234,56
+:61:1703070307DR50,00NTRFNONREF//BREF1
+:86:166?00GUTSCHRIFT?20Invoice 77?21 March?32Electro?33 Shop GmbH
+:61:170308C20,NMSCREF2
+:86:Friend
+Money back
+:62F:C170308EUR1204,56
+-}";
+
+    fn date(year: i32, month: u32, day: u32) -> NaiveDate {
+        NaiveDate::from_ymd(year, month, day)
+    }
+
+    #[test]
+    fn parses_ofx() {
+        let statement = parse(OFX, "march.ofx").ok().unwrap();
+
+        assert!(statement.format == Format::Ofx);
+        assert_eq!(statement.source, "OFX: march.ofx");
+        assert_eq!(statement.transactions.len(), 3);
+
+        let first = &statement.transactions[0];
+        assert_eq!(first.id, Some("A1".to_string()));
+        assert_eq!(first.account, "0001234567");
+        assert_eq!(first.date, date(2017, 3, 1));
+        assert_eq!(first.amount, -12.30);
+        assert_eq!(first.currency, "EUR");
+        assert_eq!(first.payment_type, "card");
+        assert_eq!(first.payee, "Coffee & Co");
+        assert_eq!(first.memo, "Card 1234");
+
+        assert_eq!(statement.transactions[1].amount, 100.0);
+
+        // Decimal commas and the currency of the transaction
+        let last = &statement.transactions[2];
+        assert_eq!(last.amount, -5.0);
+        assert_eq!(last.currency, "JPY");
+        assert_eq!(last.payee, "");
+    }
+
+    #[test]
+    fn parses_ofx_statements_of_several_accounts() {
+        let cards = "<CCSTMTRS>
+<CURDEF>USD
+<CCACCTFROM><ACCTID>4111000011112222</CCACCTFROM>
+<BANKTRANLIST>
+<STMTTRN>
+<TRNTYPE>ATM
+<DTPOSTED>20170304
+<TRNAMT>-40.00
+<FITID>A1
+<NAME>Cash machine
+</STMTTRN>
+<STMTTRN>
+<TRNTYPE>DIRECTDEBIT
+<DTPOSTED>20170305
+<TRNAMT>-9.99
+<FITID>A2
+<NAME>Phone company
+</STMTTRN>
+</BANKTRANLIST>
+</CCSTMTRS>";
+
+        let text = OFX.replace("</BANKMSGSRSV1>", &format!("</BANKMSGSRSV1>\n{}", cards));
+        let statement = parse(&text, "march.ofx").ok().unwrap();
+
+        assert_eq!(statement.transactions.len(), 5);
+        assert_eq!(statement.transactions[0].account, "0001234567");
+        assert_eq!(statement.transactions[0].currency, "EUR");
+
+        let withdrawal = &statement.transactions[3];
+        assert_eq!(withdrawal.account, "4111000011112222");
+        assert_eq!(withdrawal.currency, "USD");
+        assert_eq!(withdrawal.payment_type, "cash");
+
+        assert_eq!(statement.transactions[4].payment_type, "transfer");
+    }
+
+    #[test]
+    fn parses_camt053() {
+        let text = CAMT053.replace("<ValDt>", "<BkTxCd><Domn><Cd>PMNT</Cd><Fmly><Cd>IDDT</Cd>\
+                                    <SubFmlyCd>ESDD</SubFmlyCd></Fmly></Domn></BkTxCd><ValDt>");
+        let statement = parse(&text, "march.xml").ok().unwrap();
+
+        assert!(statement.format == Format::Camt053);
+        assert_eq!(statement.transactions.len(), 2);
+
+        // Amounts of the entry, not of its details
+        let payment = &statement.transactions[0];
+        assert_eq!(payment.id, Some("BANK-1".to_string()));
+        assert_eq!(payment.account, "DE89370400440532013000");
+        assert_eq!(payment.date, date(2017, 3, 4));
+        assert_eq!(payment.amount, -45.10);
+        assert_eq!(payment.currency, "EUR");
+        assert_eq!(payment.payment_type, "transfer");
+        assert_eq!(payment.payee, "Hardware Store");
+        assert_eq!(payment.memo, "Invoice 42");
+
+        let refund = &statement.transactions[1];
+        assert_eq!(refund.id, None);
+        assert_eq!(refund.date, date(2017, 3, 6));
+        assert_eq!(refund.amount, 10.0);
+        assert_eq!(refund.payment_type, "card");
+        assert_eq!(refund.memo, "Refund");
+
+        // Each statement has its own account
+        let other = CAMT053.replace("</Stmt>", "</Stmt><Stmt>\
+            <Acct><Id><Othr><Id>12345678</Id></Othr></Id></Acct>\
+            <Ntry><Amt Ccy=\"EUR\">1</Amt><CdtDbtInd>DBIT</CdtDbtInd>\
+            <BookgDt><Dt>2017-03-07</Dt></BookgDt></Ntry></Stmt>");
+        let statement = parse(&other, "march.xml").ok().unwrap();
+
+        assert_eq!(statement.transactions.len(), 3);
+        assert_eq!(statement.transactions[1].account, "DE89370400440532013000");
+        assert_eq!(statement.transactions[2].account, "12345678");
+    }
+
+    #[test]
+    fn parses_mt940() {
+        let statement = parse(MT940, "march.sta").ok().unwrap();
+
+        assert!(statement.format == Format::Mt940);
+        assert_eq!(statement.transactions.len(), 2);
+
+        // Structured information to the account owner
+        let payment = &statement.transactions[0];
+        assert_eq!(payment.id, Some("BREF1".to_string()));
+        assert_eq!(payment.account, "10020030/1234567");
+        assert_eq!(payment.date, date(2017, 3, 7));
+        assert_eq!(payment.amount, -50.0);
+        assert_eq!(payment.currency, "EUR");
+        assert_eq!(payment.payment_type, "transfer");
+        assert_eq!(payment.payee, "Electro Shop GmbH");
+        assert_eq!(payment.memo, "Invoice 77 March");
+
+        // Unstructured information, the first line is the payee
+        let deposit = &statement.transactions[1];
+        assert_eq!(deposit.id, Some("REF2".to_string()));
+        assert_eq!(deposit.amount, 20.0);
+        assert_eq!(deposit.payment_type, "card");
+        assert_eq!(deposit.payee, "Friend");
+        assert_eq!(deposit.memo, "Money back");
+    }
+
+    #[test]
+    fn rejects_unknown_and_broken_statements() {
+        assert!(parse("Date,Amount\n2017-03-01,-5.00", "march.csv").is_err());
+        assert!(parse(&OFX.replace("<TRNAMT>-12.30", "<TRNAMT>twelve"), "march.ofx").is_err());
+        assert!(parse(&CAMT053.replace("DBIT", "DEBIT"), "march.xml").is_err());
+        assert!(parse(&MT940.replace(":60F:C170301EUR", ":60F:X"), "march.sta").is_err());
+    }
+
+    #[test]
+    fn imports_valid_payments_once_per_account() {
+        let db = Database::open(":memory:").unwrap();
+        migrations::migrate(&db);
+
+        let statement = parse(OFX, "march.ofx").ok().unwrap();
+        assert!(check(&db, &statement).unwrap()[2] == Status::UnknownCurrency);
+
+        let statement = parse(&OFX.replace("JPY", "EUR"), "march.ofx").ok().unwrap();
+        let statuses = check(&db, &statement).unwrap();
+
+        assert!(statuses[0] == Status::New);
+        assert!(statuses[1] == Status::Deposit);
+        assert!(statuses[2] == Status::Invalid);
+
+        // Payments without a payee are reported instead of imported
+        let imported = import(&db, &statement, &[0, 2]).unwrap();
+        assert_eq!(imported.receipts.len(), 1);
+        assert_eq!(imported.rejected, vec![2]);
+
+        assert!(check(&db, &statement).unwrap()[0] == Status::Imported);
+
+        // The same identifiers in another account are other transactions
+        let other = parse(&OFX.replace("JPY", "EUR").replace("0001234567", "0007654321"),
+                          "march.ofx").ok().unwrap();
+        assert!(check(&db, &other).unwrap()[0] != Status::Imported);
+
+        import(&db, &other, &[]).unwrap();
+
+        let stored = db.get_unmatched_transactions().unwrap();
+        assert_eq!(stored.iter().filter(|t| t.account == "0007654321").count(), 2);
+    }
+
+    #[test]
+    fn imports_identical_payments_without_identifier() {
+        let db = Database::open(":memory:").unwrap();
+        migrations::migrate(&db);
+
+        let line = "<STMTTRN>
+<TRNTYPE>POS
+<DTPOSTED>20170310
+<TRNAMT>-2.50
+<NAME>Bakery
+</STMTTRN>
+";
+        let text = OFX.replace("<BANKTRANLIST>\n", &format!("<BANKTRANLIST>\n{0}{0}", line));
+        let statement = parse(&text, "march.ofx").ok().unwrap();
+
+        assert_eq!(statement.transactions[0].occurrence, 0);
+        assert_eq!(statement.transactions[1].occurrence, 1);
+
+        let imported = import(&db, &statement, &[0, 1]).unwrap();
+        assert_eq!(imported.receipts.len(), 2);
+
+        // Importing the statement again adds no lines
+        import(&db, &statement, &[]).unwrap();
+
+        let bakery = db.get_unmatched_transactions().unwrap().into_iter()
+            .filter(|t| t.payee == "Bakery")
+            .count();
+        assert_eq!(bakery, 0);
+        assert_eq!(db.get_unmatched_receipts(&PAYMENT_TYPES).unwrap().len(), 0);
+    }
+}
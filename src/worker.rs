@@ -0,0 +1,222 @@
+// MIT License
+//
+// Copyright (c) 2016 Rafael Medina García <rafamedgar@gmail.com>
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+/// Background thread for database operations
+///
+/// The worker thread owns the database connection and runs jobs in the
+/// order they are received. Results are handed back to the GTK main thread
+/// in an idle callback, where the callback given along with the job is
+/// called.
+///
+/// GLib only accepts `Send` closures in idle callbacks, so callbacks, which
+/// usually hold widgets, are kept in thread local storage of the main thread
+/// and only the results travel between threads. Each worker has its own
+/// callbacks, so jobs of a worker still running after another one is started,
+/// e.g. when switching profiles, are delivered too.
+///
+/// A job that panics is rolled back and its callback dropped; the thread
+/// goes on with the next job.
+
+use std::any::Any;
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::panic;
+use std::panic::AssertUnwindSafe;
+use std::sync::mpsc::{channel, Receiver, Sender, TryRecvError};
+use std::thread;
+
+use glib;
+
+use db::Database;
+
+/// Job run in the worker thread
+trait Job: Send {
+    fn run(self: Box<Self>, db: &Database) -> Box<Any + Send>;
+}
+
+impl<R, F> Job for F
+    where R: Send + 'static, F: FnOnce(&Database) -> R + Send {
+
+    fn run(self: Box<Self>, db: &Database) -> Box<Any + Send> {
+        Box::new((*self)(db))
+    }
+}
+
+/// Callback receiving the result of a job
+type Callback = Box<FnMut(Box<Any + Send>)>;
+
+/// Handle to the worker thread
+///
+/// Handles can be cloned freely; the thread stops once all of them have
+/// been dropped and the jobs already sent have finished.
+#[derive(Clone)]
+pub struct Worker {
+    id: u64,
+    sender: Sender<(u64, Box<Job>)>
+}
+
+impl Worker {
+    /// Start the worker thread with the given database
+    ///
+    /// Must be called from the GTK main thread.
+    pub fn start(db: Database) -> Worker {
+        let (sender, jobs) = channel::<(u64, Box<Job>)>();
+        let (results_sender, results) = channel();
+
+        let worker_id = NEXT_WORKER.with(|n| {
+            let id = n.get();
+            n.set(id + 1);
+            id
+        });
+
+        PENDING.with(move |p| {
+            p.borrow_mut().insert(worker_id, Pending {
+                next_id: 0,
+                callbacks: HashMap::new(),
+                results: results
+            });
+        });
+
+        thread::spawn(move || {
+            for (id, job) in jobs.iter() {
+                let result = match panic::catch_unwind(AssertUnwindSafe(|| job.run(&db))) {
+                    Ok(result) => Some(result),
+                    Err(_) => {
+                        eprintln!("Database job {} failed, its result is dropped", id);
+                        db.rollback_all();
+                        None
+                    }
+                };
+
+                if results_sender.send((id, result)).is_err() {
+                    break;
+                }
+
+                glib::idle_add(move || deliver_results(worker_id));
+            }
+
+            // Let the main thread forget about this worker
+            drop(results_sender);
+            glib::idle_add(move || deliver_results(worker_id));
+        });
+
+        Worker { id: worker_id, sender: sender }
+    }
+
+    /// Run a job in the worker thread
+    ///
+    /// `callback` is called in the main thread with the value returned by
+    /// `job`. It is not called if the job panics or the worker thread is no
+    /// longer running. Must be called from the GTK main thread.
+    pub fn run<R, F, C>(&self, job: F, callback: C)
+        where R: Send + 'static,
+              F: FnOnce(&Database) -> R + Send + 'static,
+              C: FnOnce(R) + 'static {
+
+        let mut callback = Some(callback);
+
+        let callback: Callback = Box::new(move |result: Box<Any + Send>| {
+            if let (Some(callback), Ok(result)) = (callback.take(), result.downcast::<R>()) {
+                callback(*result);
+            }
+        });
+
+        let worker_id = self.id;
+
+        let id = PENDING.with(move |p| {
+            let mut borrowed = p.borrow_mut();
+            let pending = borrowed.get_mut(&worker_id).expect("worker is not running");
+
+            let id = pending.next_id;
+            pending.next_id += 1;
+            pending.callbacks.insert(id, callback);
+
+            id
+        });
+
+        if self.sender.send((id, Box::new(job))).is_err() {
+            eprintln!("Database worker is not running, job {} dropped", id);
+
+            PENDING.with(|p| {
+                if let Some(pending) = p.borrow_mut().get_mut(&worker_id) {
+                    pending.callbacks.remove(&id);
+                }
+            });
+        }
+    }
+}
+
+/// Call the callbacks of finished jobs of a worker
+fn deliver_results(worker_id: u64) -> glib::Continue {
+    loop {
+        // Release the borrow before calling back, callbacks may run new jobs
+        let next = PENDING.with(|p| {
+            let mut borrowed = p.borrow_mut();
+
+            let received = match borrowed.get_mut(&worker_id) {
+                Some(pending) => match pending.results.try_recv() {
+                    Ok((id, result)) => Ok(pending.callbacks.remove(&id)
+                                           .and_then(|callback| result.map(|r| (callback, r)))),
+                    Err(err) => Err(err)
+                },
+
+                None => Err(TryRecvError::Empty)
+            };
+
+            match received {
+                Ok(next) => Some(next),
+
+                Err(TryRecvError::Disconnected) => {
+                    borrowed.remove(&worker_id);
+                    None
+                },
+
+                Err(TryRecvError::Empty) => None
+            }
+        });
+
+        match next {
+            Some(Some((mut callback, result))) => callback(result),
+            Some(None) => continue,
+            None => break
+        }
+    }
+
+    glib::Continue(false)
+}
+
+/// Jobs of a worker waiting for their result to be delivered
+///
+/// The result is `None` if the job panicked.
+struct Pending {
+    next_id: u64,
+    callbacks: HashMap<u64, Callback>,
+    results: Receiver<(u64, Option<Box<Any + Send>>)>
+}
+
+// Callbacks can only be called in the main thread
+thread_local!(
+    static PENDING: RefCell<HashMap<u64, Pending>> = RefCell::new(HashMap::new())
+);
+
+thread_local!(
+    static NEXT_WORKER: Cell<u64> = Cell::new(0)
+);